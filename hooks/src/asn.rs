@@ -0,0 +1,120 @@
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::net::IpAddr;
+
+use crate::cidr::{self, Cidr};
+
+/// Networks with the autonomous system numbers announcing them, to look up the ASN of an address
+/// by longest prefix match.
+#[derive(Default)]
+pub struct AsnDb {
+    /// The ASN of each network by its address width and prefix length, longest first, then by the
+    /// network's prefix bits.
+    networks: BTreeMap<(u8, Reverse<u8>), BTreeMap<u128, u32>>,
+    len: usize,
+}
+
+impl AsnDb {
+    /// Add `network`, announced by `asn`. Later entries for the same network win.
+    pub fn insert(&mut self, network: Cidr, asn: u32) {
+        // Not canonicalized, since the prefix length is for the network's own family.
+        let (width, bits) = match network.addr() {
+            IpAddr::V4(addr) => (32, addr.to_bits().into()),
+            IpAddr::V6(addr) => (128, addr.to_bits()),
+        };
+        let prefix = cidr::masked(bits, width, network.prefix_len());
+        let networks = self
+            .networks
+            .entry((width, Reverse(network.prefix_len())))
+            .or_default();
+        if networks.insert(prefix, asn).is_none() {
+            self.len += 1;
+        }
+    }
+
+    /// The ASN of the most specific network containing `ip`, if any. IPv4-mapped IPv6 addresses
+    /// are looked up as IPv4.
+    pub fn lookup(&self, ip: IpAddr) -> Option<u32> {
+        let (width, bits) = match ip.to_canonical() {
+            IpAddr::V4(ip) => (32, ip.to_bits().into()),
+            IpAddr::V6(ip) => (128, ip.to_bits()),
+        };
+        self.networks
+            .range((width, Reverse(u8::MAX))..=(width, Reverse(0)))
+            .find_map(|(&(_, Reverse(prefix_len)), networks)| {
+                networks
+                    .get(&cidr::masked(bits, width, prefix_len))
+                    .copied()
+            })
+    }
+}
+
+// The table may be huge, so only its size is printed.
+impl fmt::Debug for AsnDb {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "AsnDb({} networks)", self.len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn db(entries: &[(&str, u32)]) -> AsnDb {
+        let mut db = AsnDb::default();
+        for (network, asn) in entries {
+            db.insert(network.parse().unwrap(), *asn);
+        }
+        db
+    }
+
+    fn lookup(db: &AsnDb, ip: &str) -> Option<u32> {
+        db.lookup(ip.parse().unwrap())
+    }
+
+    #[test]
+    fn most_specific_network_wins() {
+        let db = db(&[("10.0.0.0/8", 1), ("10.1.0.0/16", 2), ("10.1.2.0/24", 3)]);
+        assert_eq!(lookup(&db, "10.1.2.3"), Some(3));
+        assert_eq!(lookup(&db, "10.1.3.1"), Some(2));
+        assert_eq!(lookup(&db, "10.2.0.1"), Some(1));
+        assert_eq!(lookup(&db, "11.0.0.1"), None);
+    }
+
+    #[test]
+    fn insertion_order_doesnt_matter() {
+        let db = db(&[("10.1.2.0/24", 3), ("10.0.0.0/8", 1)]);
+        assert_eq!(lookup(&db, "10.1.2.3"), Some(3));
+        assert_eq!(lookup(&db, "10.9.9.9"), Some(1));
+    }
+
+    #[test]
+    fn later_entries_for_the_same_network_win() {
+        let db = db(&[("10.0.0.0/8", 1), ("10.0.0.0/8", 2)]);
+        assert_eq!(lookup(&db, "10.0.0.1"), Some(2));
+        assert_eq!(format!("{db:?}"), "AsnDb(1 networks)");
+    }
+
+    #[test]
+    fn zero_prefix_is_the_fallback() {
+        let db = db(&[("0.0.0.0/0", 1), ("192.168.0.0/16", 2)]);
+        assert_eq!(lookup(&db, "8.8.8.8"), Some(1));
+        assert_eq!(lookup(&db, "192.168.1.1"), Some(2));
+        assert_eq!(lookup(&db, "2001:db8::1"), None);
+    }
+
+    #[test]
+    fn families_are_looked_up_separately() {
+        let db = db(&[("::/8", 6), ("0.0.0.0/8", 4), ("2001:db8::/32", 5)]);
+        assert_eq!(lookup(&db, "2001:db8::1"), Some(5));
+        assert_eq!(lookup(&db, "0.1.2.3"), Some(4));
+        assert_eq!(lookup(&db, "::2"), Some(6));
+    }
+
+    #[test]
+    fn ipv4_mapped_addresses_are_looked_up_as_ipv4() {
+        let db = db(&[("10.0.0.0/8", 1)]);
+        assert_eq!(lookup(&db, "::ffff:10.1.2.3"), Some(1));
+    }
+}
@@ -1,68 +1,2445 @@
-use std::collections::BTreeSet;
-use std::net::ToSocketAddrs;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::net::{IpAddr, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use libc::c_uint;
+use libc::{c_int, c_uint};
+
+use crate::asn::AsnDb;
+use crate::cidr::Cidr;
+use crate::dynamic;
+use crate::host_rule::{Destination, HostRule};
+use crate::latency::{Cdf, HourRange, SizeBucket, TodProfile};
+use crate::util;
+use crate::vars::Vars;
 
 /// Configuration options for the hooks in [`crate::hooks`].
+#[derive(Debug)]
 pub struct HookConfig {
-    /// List of hosts to intercept. If empty, intercept all hosts.
+    /// List of hosts to intercept. If empty, intercept all hosts unless `all_hosts` is unset.
     ///
-    /// Read from a colon-separated list in the `PRELOAD_LATENCY_HOSTS` environment variable.
+    /// Read from a colon-separated list in the `PRELOAD_LATENCY_HOSTS` environment variable. The
+    /// value `-` means no hosts at all, so only e.g. `cidrs` are intercepted. IP addresses and
+    /// networks like `10.0.0.0/8` also match sockets connected to them without a lookup, like
+    /// `cidrs`. Entries like `vsock:3:5000` match vsock sockets connected to that CID and port,
+    /// and `vsock:3` matches any port. Entries like `unix:/run/app.sock` match unix sockets
+    /// connected to that path, with abstract names written as `unix:@name`.
     ///
     /// If the `PRELOAD_LATENCY_RESOLVE` environment variable is set, these hosts are
     /// optimistically resolved using `getaddrinfo`. This is useful when a main binary somehow
     /// bypasses `getaddrinfo` when creating sockets for a host that should be intercepted.
-    pub(crate) hosts: BTreeSet<String>,
+    pub(crate) hosts: Vec<HostRule>,
+
+    /// File listing more hosts to intercept, one per line. It's re-read when it changes, so
+    /// another process can manage the list. If configured, an empty `hosts` no longer means all
+    /// hosts are intercepted.
+    ///
+    /// Read from the `PRELOAD_LATENCY_HOSTS_FILE` environment variable.
+    pub(crate) hosts_file: Option<PathBuf>,
+
+    /// Addresses to intercept with the host names they belong to, loaded at startup from a file
+    /// in `/etc/hosts` format, so addresses are tracked without any DNS lookups. The names count as
+    /// listed hosts too. If configured, an empty `hosts` no longer means all hosts are
+    /// intercepted.
+    ///
+    /// Read from the `PRELOAD_LATENCY_HOSTMAP` environment variable.
+    pub(crate) hostmap: Vec<(IpAddr, Vec<String>)>,
+
+    /// Shared object exporting `bool should_delay(const char* host, const char* ip)`. If it loads,
+    /// it decides which addresses are intercepted instead of `hosts`: for each address
+    /// `getaddrinfo` returns, with the host it was resolved for, and for any other address a
+    /// socket connects to, with a null host. Networks in `cidrs` are still intercepted.
+    ///
+    /// Read from the `PRELOAD_LATENCY_MATCH_SO` environment variable.
+    pub(crate) match_so: Option<PathBuf>,
+
+    /// Whether every host is intercepted, because neither `hosts` nor `hosts_file` list any and
+    /// `PRELOAD_LATENCY_HOSTS` isn't `-`.
+    pub(crate) all_hosts: bool,
+
+    /// Whether to resolve `hosts` using `getaddrinfo` during startup.
+    ///
+    /// Enabled by setting the `PRELOAD_LATENCY_RESOLVE` environment variable.
+    pub(crate) resolve: bool,
+
+    /// Maximum duration in milliseconds to wait for `hosts` to be resolved during startup.
+    ///
+    /// Read from the `PRELOAD_LATENCY_RESOLVE_TIMEOUT_MILLIS` environment variable.
+    pub(crate) resolve_timeout_millis: c_uint,
+
+    /// If configured, `hosts` are resolved again every `reresolve_secs` seconds on a background
+    /// thread. Addresses a host no longer resolves to stop being tracked, so interception follows
+    /// DNS changes in long-running processes.
+    ///
+    /// Read from the `PRELOAD_LATENCY_RERESOLVE_SECS` environment variable.
+    pub(crate) reresolve_secs: Option<c_uint>,
 
     /// Duration in milliseconds to sleep before reading from or writing to intercepted sockets.
     ///
     /// Read from the `PRELOAD_LATENCY_MILLIS` environment variable.
     pub(crate) sleep_duration_millis: c_uint,
 
+    /// If configured, the duration in milliseconds to sleep instead of `sleep_duration_millis` for
+    /// sockets connected to IPv4 addresses, including IPv4-mapped IPv6 addresses.
+    ///
+    /// Read from the `PRELOAD_LATENCY_V4_MILLIS` environment variable.
+    pub(crate) v4_millis: Option<c_uint>,
+
+    /// If configured, the duration in milliseconds to sleep instead of `sleep_duration_millis` for
+    /// sockets connected to IPv6 addresses, e.g. to test Happy Eyeballs fallback to IPv4.
+    ///
+    /// Read from the `PRELOAD_LATENCY_V6_MILLIS` environment variable.
+    pub(crate) v6_millis: Option<c_uint>,
+
+    /// If configured, the duration to sleep is sampled from this distribution on every call,
+    /// instead of always being `sleep_duration_millis`.
+    ///
+    /// Read from a comma-separated list of `probability=millis` points of the cumulative
+    /// distribution in the `PRELOAD_LATENCY_CDF` environment variable.
+    pub(crate) cdf: Option<Cdf>,
+
+    /// If configured, sends aren't delayed. Instead, the first receive on a socket after a send
+    /// sleeps for this many milliseconds, like a server taking a while to process each request.
+    /// Receives that don't follow a send aren't delayed either.
+    ///
+    /// Read from the `PRELOAD_LATENCY_RESPONSE_MILLIS` environment variable.
+    pub(crate) response_millis: Option<c_uint>,
+
+    /// Ranges of call sizes in bytes, each with a duration in milliseconds that calls of that size
+    /// sleep for instead of the usual duration. Sends are sized by their payload and receives by
+    /// the length of their buffer. The first matching bucket wins.
+    ///
+    /// Read from a comma-separated list of `min-max=millis` entries in the
+    /// `PRELOAD_LATENCY_SIZE_BUCKETS` environment variable. `max` may be left out.
+    pub(crate) size_buckets: Vec<SizeBucket>,
+
+    /// Whether sends with `MSG_MORE` skip the per-call sleep, since they're coalesced with the
+    /// next send. That send is then delayed once for all of them, with `size_buckets` picked by
+    /// their total size.
+    ///
+    /// Enabled by setting the `PRELOAD_LATENCY_COALESCE_MSG_MORE` environment variable.
+    pub(crate) coalesce_msg_more: bool,
+
+    /// Whether operations that start on a socket while another one on it is sleeping only sleep
+    /// until that sleep ends, so threads using one fd concurrently share a single delay instead of
+    /// each being delayed in full.
+    ///
+    /// Enabled by setting the `PRELOAD_LATENCY_COALESCE_CONCURRENT` environment variable.
+    pub(crate) coalesce_concurrent: bool,
+
+    /// If configured, sleep durations are multiplied by the factor for the current hour of the
+    /// local day, to model diurnal load.
+    ///
+    /// Read from a comma-separated list of `first-last=multiplier` entries in the
+    /// `PRELOAD_LATENCY_TOD_PROFILE` environment variable. Hours that aren't listed aren't scaled.
+    pub(crate) tod_profile: Option<TodProfile>,
+
+    /// If configured, sleep durations are multiplied by the system's 1-minute load average divided
+    /// by this while it's above this, so latency rises with real pressure on the machine.
+    ///
+    /// Read from the `PRELOAD_LATENCY_LOAD_THRESHOLD` environment variable.
+    pub(crate) load_threshold: Option<f64>,
+
+    /// If configured, each socket's sleep duration starts at `decay_factor` times the usual
+    /// duration when it starts being tracked and decreases linearly to the usual duration over
+    /// this many seconds, like a connection warming up.
+    ///
+    /// Read from the `PRELOAD_LATENCY_DECAY_SECS` environment variable.
+    pub(crate) decay_secs: Option<c_uint>,
+
+    /// Factor applied to the sleep duration of a socket that just started being tracked, with
+    /// `decay_secs`.
+    ///
+    /// Read from the `PRELOAD_LATENCY_DECAY_FACTOR` environment variable.
+    pub(crate) decay_factor: f64,
+
+    /// If configured, sleep durations grow with the number of tracked sockets that are open, like
+    /// a server slowing down under concurrency. Each open socket besides the one a call is on adds
+    /// this fraction of the usual duration.
+    ///
+    /// Read from the `PRELOAD_LATENCY_CONN_FACTOR` environment variable.
+    pub(crate) conn_factor: Option<f64>,
+
+    /// Probability between 0 and 1 that a new connection is delayed at all, decided once when it's
+    /// connected or accepted. The others are never delayed, like the healthy part of a partially
+    /// degraded connection pool.
+    ///
+    /// Read from the `PRELOAD_LATENCY_CONN_SAMPLE_RATE` environment variable.
+    pub(crate) conn_sample_rate: Option<f64>,
+
+    /// Period in seconds of a sine wave added to sleep durations, so latency rises and falls
+    /// smoothly instead of flipping like with `toggle_period`. The wave starts at zero when the
+    /// hooks are initialized and rises first.
+    ///
+    /// Read from the `PRELOAD_LATENCY_WAVE_PERIOD_SECS` environment variable.
+    pub(crate) wave_period_secs: Option<c_uint>,
+
+    /// Amplitude in milliseconds of the wave with `wave_period_secs`. Sleep durations never go
+    /// below zero at its troughs.
+    ///
+    /// Read from the `PRELOAD_LATENCY_WAVE_AMPLITUDE_MILLIS` environment variable.
+    pub(crate) wave_amplitude_millis: c_uint,
+
+    /// Name of a POSIX shared-memory segment, like `/preload_kill`, whose first byte is a kill
+    /// switch. While another process sets it to anything but zero, nothing is intercepted. The
+    /// segment must exist at startup.
+    ///
+    /// Read from the `PRELOAD_LATENCY_SHM` environment variable.
+    pub(crate) shm: Option<String>,
+
+    /// Path to a file that must exist for anything to be intercepted, e.g. a feature flag
+    /// materialized as a file. Whether it exists is checked at most every 100 milliseconds.
+    ///
+    /// Read from the `PRELOAD_LATENCY_FLAG_FILE` environment variable.
+    pub(crate) flag_file: Option<PathBuf>,
+
+    /// Path to a file holding the latency in milliseconds to sleep for instead of
+    /// `sleep_duration_millis`, so another process can change it while the program runs. It's
+    /// re-read at most every 100 milliseconds, and the last valid latency is kept while it's
+    /// missing or malformed.
+    ///
+    /// Read from the `PRELOAD_LATENCY_DYNAMIC_FILE` environment variable.
+    pub(crate) dynamic_file: Option<PathBuf>,
+
+    /// Duration in seconds after startup during which nothing is intercepted, so the process can
+    /// initialize at full speed.
+    ///
+    /// Read from the `PRELOAD_LATENCY_GRACE_SECS` environment variable.
+    pub(crate) grace_period: Option<c_uint>,
+
+    /// Wall-clock time before which nothing is intercepted, for scheduled experiments.
+    ///
+    /// Read from the `PRELOAD_LATENCY_START_AT` environment variable, either an RFC 3339
+    /// timestamp or seconds since the Unix epoch.
+    pub(crate) start_at: Option<Timestamp>,
+
+    /// Wall-clock time after which nothing is intercepted anymore. Must be after `start_at`.
+    ///
+    /// Read from the `PRELOAD_LATENCY_STOP_AT` environment variable, in the same format as
+    /// `PRELOAD_LATENCY_START_AT`.
+    pub(crate) stop_at: Option<Timestamp>,
+
+    /// Maximum random deviation in milliseconds from the sleep duration, applied uniformly in
+    /// both directions on every intercepted call.
+    ///
+    /// Read from the `PRELOAD_LATENCY_JITTER_MILLIS` environment variable.
+    pub(crate) jitter_millis: c_uint,
+
+    /// Maximum deviation in milliseconds from the sleep duration for each host. Unlike
+    /// `jitter_millis`, a host's deviation is derived from its name and `seed`, so it's the same
+    /// on every call and across runs.
+    ///
+    /// Read from the `PRELOAD_LATENCY_HOST_OFFSET_MILLIS` environment variable.
+    pub(crate) host_offset_millis: Option<c_uint>,
+
+    /// Seed for per-host offsets and jitter, to make runs reproducible.
+    ///
+    /// Read from the `PRELOAD_LATENCY_SEED` environment variable.
+    pub(crate) seed: Option<u64>,
+
+    /// Whether each intercepted call flips a coin for which direction is delayed, and is only
+    /// delayed if it goes that way. Models one-sided delays that move around unpredictably.
+    ///
+    /// Enabled by setting the `PRELOAD_LATENCY_RANDOM_DIRECTION` environment variable.
+    pub(crate) random_direction: bool,
+
     /// Duration of the "toggle period". If configured, interception oscillates between "disabled"
     /// and "enabled" every `toggle_period` seconds.
     ///
     /// Read from the PRELOAD_LATENCY_TOGGLE_PERIOD` environment variable.
     pub(crate) toggle_period: Option<c_uint>,
+
+    /// Fraction between 0 and 1 of each enabled `toggle_period` window during which anything is
+    /// intercepted, starting when the window does. For finer-grained on/off patterns, e.g. `0.25`
+    /// with a `toggle_period` of 4 seconds intercepts for 1 second every 8. Requires
+    /// `toggle_period`.
+    ///
+    /// Read from the `PRELOAD_LATENCY_DUTY_CYCLE` environment variable.
+    pub(crate) duty_cycle: f64,
+
+    /// Factor applied to the sleep duration for non-blocking sockets. `0` skips the delay for
+    /// non-blocking sockets entirely, `1` treats them the same as blocking sockets.
+    ///
+    /// Read from the `PRELOAD_LATENCY_NONBLOCK_FACTOR` environment variable.
+    pub(crate) nonblock_factor: f64,
+
+    /// Duration of a latency "burst" in seconds. If configured, interception is only enabled
+    /// during one burst at a random offset within every `burst_interval` seconds.
+    ///
+    /// Read from the `PRELOAD_LATENCY_BURST_SECS` environment variable.
+    pub(crate) burst: Option<c_uint>,
+
+    /// Length in seconds of the interval containing each burst.
+    ///
+    /// Read from the `PRELOAD_LATENCY_BURST_INTERVAL_SECS` environment variable.
+    pub(crate) burst_interval: c_uint,
+
+    /// Networks to intercept, each with an optional sleep duration in milliseconds that overrides
+    /// `sleep_duration_millis` for sockets connected to an address inside it. If an address is in
+    /// several networks, the most specific one wins. Sockets returned by `accept` are intercepted
+    /// by their peer, like connected ones.
+    ///
+    /// Read from a comma-separated list of `cidr[=millis]` entries in the `PRELOAD_LATENCY_CIDRS`
+    /// environment variable.
+    pub(crate) cidrs: Vec<HostRule>,
+
+    /// Local networks sockets must be bound to to be intercepted, e.g. to only intercept traffic
+    /// leaving one interface of a multi-homed host. Applies on top of `hosts` and `cidrs`.
+    ///
+    /// Read from a comma-separated list in the `PRELOAD_LATENCY_LOCAL_CIDRS` environment variable.
+    pub(crate) local_cidrs: Vec<Cidr>,
+
+    /// Fds to intercept from startup no matter what they're connected to, e.g. sockets inherited
+    /// from a parent process. Once one is closed, a new fd with the same number isn't intercepted.
+    ///
+    /// Read from a comma-separated list in the `PRELOAD_LATENCY_FORCE_FDS` environment variable.
+    pub(crate) force_fds: Vec<c_int>,
+
+    /// Whether to intercept every IP socket, no matter what it's connected to. `hosts` and `cidrs`
+    /// are then only used to pick the sleep duration. Unix, netlink and other non-IP sockets are
+    /// never swept in, since they're used to talk to the local system rather than the network.
+    ///
+    /// Enabled by setting the `PRELOAD_LATENCY_BLANKET` environment variable.
+    pub(crate) blanket: bool,
+
+    /// Whether to never intercept loopback addresses (`127.0.0.0/8`, `::1`), even if a host in
+    /// `hosts` resolves to one or `hosts` is empty. Addresses listed in `hosts` or `cidrs` are
+    /// still intercepted.
+    ///
+    /// Enabled by setting the `PRELOAD_LATENCY_EXCLUDE_LOOPBACK` environment variable.
+    pub(crate) exclude_loopback: bool,
+
+    /// Which addresses may be intercepted by whether they're on the public internet. Applies to
+    /// `hosts` and `blanket`, but addresses and networks listed in `hosts` or `cidrs` are still
+    /// intercepted.
+    ///
+    /// Read from the `PRELOAD_LATENCY_SCOPE` environment variable, one of `private`, `public` or
+    /// `both`.
+    pub(crate) scope: Scope,
+
+    /// Table of networks with the autonomous systems announcing them, loaded at startup from a CSV
+    /// file of `network,asn` lines like `8.8.8.0/24,15169`. Only used together with `asns`.
+    ///
+    /// Read from the `PRELOAD_LATENCY_ASN_DB` environment variable.
+    pub(crate) asn_db: Option<AsnDb>,
+
+    /// If configured, only addresses whose most specific network in `asn_db` is announced by one
+    /// of these autonomous systems are intercepted, e.g. to only delay traffic to one cloud
+    /// provider. Applies like `scope`.
+    ///
+    /// Read from a comma-separated list of ASNs in the `PRELOAD_LATENCY_ASNS` environment
+    /// variable.
+    pub(crate) asns: BTreeSet<u32>,
+
+    /// Whether to intercept both ends of every `socketpair`, which are usually used for local IPC.
+    ///
+    /// Enabled by setting the `PRELOAD_LATENCY_INTERCEPT_SOCKETPAIR` environment variable.
+    pub(crate) intercept_socketpair: bool,
+
+    /// Simulated bandwidth in bytes per second. If configured, intercepted calls additionally
+    /// sleep for as long as it would take to transfer their bytes at this rate.
+    ///
+    /// Read from the `PRELOAD_LATENCY_BYTES_PER_SEC` environment variable.
+    pub(crate) bytes_per_sec: Option<u64>,
+
+    /// Simulated bandwidth in bytes per second of a single link shared by both directions of all
+    /// intercepted sockets, so concurrent transfers slow each other down.
+    ///
+    /// Read from the `PRELOAD_LATENCY_SHARED_BPS` environment variable.
+    pub(crate) shared_bps: Option<u64>,
+
+    /// Number of bytes that must be transferred across all intercepted sockets before any latency
+    /// is injected, modelling a shared pipe that degrades under load.
+    ///
+    /// Read from the `PRELOAD_LATENCY_GLOBAL_BYTES` environment variable.
+    pub(crate) global_bytes: Option<u64>,
+
+    /// If configured, only addresses of this record type returned by `getaddrinfo` are tracked,
+    /// e.g. to only slow down IPv6. With `AF_UNSPEC` hints, the other results are ignored.
+    ///
+    /// Read from the `PRELOAD_LATENCY_DNS_RRTYPE` environment variable, either `A` or `AAAA`.
+    pub(crate) dns_rrtype: Option<RrType>,
+
+    /// Duration in milliseconds to sleep before the first `getaddrinfo` call the process makes,
+    /// like a resolver with a cold cache. Later lookups aren't delayed.
+    ///
+    /// Read from the `PRELOAD_LATENCY_FIRST_DNS_MILLIS` environment variable.
+    pub(crate) first_dns_millis: Option<c_uint>,
+
+    /// Probability between 0 and 1 that `getaddrinfo` fails with `dns_fail_code` for an
+    /// intercepted host instead of resolving it, to exercise the caller's handling of DNS errors.
+    ///
+    /// Read from the `PRELOAD_LATENCY_DNS_FAIL_RATE` environment variable.
+    pub(crate) dns_fail_rate: Option<f64>,
+
+    /// The error `getaddrinfo` fails with at `dns_fail_rate`.
+    ///
+    /// Read from the `PRELOAD_LATENCY_DNS_FAIL_CODE` environment variable, the name of an `EAI_*`
+    /// error like `EAI_NONAME`.
+    pub(crate) dns_fail_code: DnsFailCode,
+
+    /// Whether to log every address returned by `getaddrinfo`, even for hosts that aren't
+    /// intercepted.
+    ///
+    /// Enabled by setting the `PRELOAD_LATENCY_LOG_RESOLUTIONS` environment variable.
+    pub(crate) log_resolutions: bool,
+
+    /// Whether to log each host that `getaddrinfo` resolves without it matching `hosts` or the
+    /// hosts file, to surface typos in the host list. Every host is only logged once, and only
+    /// the first 256 are.
+    ///
+    /// Enabled by setting the `PRELOAD_LATENCY_LOG_UNMATCHED` environment variable.
+    pub(crate) log_unmatched: bool,
+
+    /// Whether to log every call on a tracked socket with the `CLOCK_MONOTONIC` time in
+    /// nanoseconds it started at and how long it slept, to line the delays up with packet
+    /// captures.
+    ///
+    /// Enabled by setting the `PRELOAD_LATENCY_LOG_OPS` environment variable.
+    pub(crate) log_ops: bool,
+
+    /// If configured, only sends whose payload starts with these bytes are delayed, and receives
+    /// aren't delayed at all. Useful to only slow down certain commands of a binary protocol.
+    ///
+    /// Read from a hex string like `2a33` in the `PRELOAD_LATENCY_OPCODE_PREFIX` environment
+    /// variable.
+    pub(crate) opcode_prefix: Option<HexBytes>,
+
+    /// If configured, only sends whose first 4096 bytes contain these bytes anywhere are delayed,
+    /// and receives aren't delayed at all. Useful to only slow down requests of a certain shape.
+    ///
+    /// Read from a hex string like `2f6170692f` in the `PRELOAD_LATENCY_PAYLOAD_CONTAINS`
+    /// environment variable.
+    pub(crate) payload_contains: Option<HexBytes>,
+
+    /// If configured, only connections whose TLS ClientHello advertises one of these ALPN
+    /// protocols are intercepted, e.g. `h2` to only delay HTTP/2. Decided on the first send, so
+    /// nothing on a connection is delayed before it.
+    ///
+    /// Read from a colon-separated list in the `PRELOAD_LATENCY_ALPN` environment variable.
+    pub(crate) alpn: Vec<String>,
+
+    /// Duration in milliseconds to sleep for instead of `sleep_duration_millis` on TLS
+    /// connections whose ClientHello names each of these servers, keyed by lowercase server name,
+    /// so hosts sharing an address can get different latencies. Decided on the first send, so
+    /// anything before it gets the usual latency.
+    ///
+    /// Read from a comma-separated list of `name=millis` entries in the
+    /// `PRELOAD_LATENCY_SNI_MILLIS` environment variable.
+    pub(crate) sni_millis: BTreeMap<String, c_uint>,
+
+    /// If configured, intercepted calls sleep for `multiplier - 1` times as long as the real call
+    /// took after it returns, instead of for `sleep_duration_millis`. Amplifies existing slowness.
+    ///
+    /// Read from the `PRELOAD_LATENCY_MULTIPLIER` environment variable.
+    pub(crate) multiplier: Option<f64>,
+
+    /// Whether `connect` calls to tracked IPs and `getaddrinfo` calls for intercepted hosts sleep
+    /// for the usual duration, like a slow handshake.
+    ///
+    /// Enabled by setting the `PRELOAD_LATENCY_MODE` environment variable to `handshake-only`.
+    pub(crate) delay_handshake: bool,
+
+    /// Whether sends and receives on intercepted sockets are delayed at all. Overrides every other
+    /// setting that delays them.
+    ///
+    /// Disabled by setting the `PRELOAD_LATENCY_MODE` environment variable to `handshake-only`.
+    pub(crate) delay_data: bool,
+
+    /// Whether intercepted calls sleep before or after calling the real function.
+    ///
+    /// Read from the `PRELOAD_LATENCY_TIMING` environment variable, either `pre` or `post`.
+    pub(crate) timing: Timing,
+
+    /// Whether intercepted calls that fail with `EINTR` are transparently retried.
+    ///
+    /// Enabled by setting the `PRELOAD_LATENCY_RETRY_EINTR` environment variable.
+    pub(crate) retry_eintr: bool,
+
+    /// Whether receives with `MSG_PEEK` skip the injected latency, not just the byte accounting.
+    /// Peeked bytes are received again by a later call, so they're never charged for bandwidth.
+    ///
+    /// Enabled by setting the `PRELOAD_LATENCY_SKIP_PEEK_DELAY` environment variable.
+    pub(crate) skip_peek_delay: bool,
+
+    /// Probability between 0 and 1 that an intercepted read or write on a stream socket only
+    /// transfers a random part of its buffer, to exercise the caller's handling of short counts.
+    /// `readv`, `writev`, `recvmsg` and `sendmsg` aren't shortened.
+    ///
+    /// Read from the `PRELOAD_LATENCY_SHORT_IO_RATE` environment variable.
+    pub(crate) short_io_rate: Option<f64>,
+
+    /// Probability between 0 and 1 that an intercepted send or receive on a non-blocking socket
+    /// fails with `EAGAIN` without being made, as if the socket's buffer were full or empty, to
+    /// exercise the caller's readiness and retry handling. Blocking calls are never failed.
+    ///
+    /// Read from the `PRELOAD_LATENCY_BACKPRESSURE_RATE` environment variable.
+    pub(crate) backpressure_rate: Option<f64>,
+
+    /// Duration in milliseconds to sleep before closing an intercepted socket, like a connection
+    /// that takes a while to drain and tear down.
+    ///
+    /// Read from the `PRELOAD_LATENCY_DRAIN_MILLIS` environment variable.
+    pub(crate) drain_millis: Option<c_uint>,
+
+    /// Duration in milliseconds to sleep before closing an intercepted listening socket instead
+    /// of `drain_millis`, like a server whose shutdown takes a while to propagate while its
+    /// connections drain.
+    ///
+    /// Read from the `PRELOAD_LATENCY_LISTEN_CLOSE_MILLIS` environment variable.
+    pub(crate) listen_close_millis: Option<c_uint>,
+
+    /// Duration in milliseconds to sleep before `epoll_wait` reports events from an epoll
+    /// instance watching an intercepted socket, like readiness notifications arriving late.
+    ///
+    /// Read from the `PRELOAD_LATENCY_EPOLL_WAIT_MILLIS` environment variable.
+    pub(crate) epoll_wait_millis: Option<c_uint>,
+
+    /// Duration in milliseconds to sleep before the first connection to each host, like a
+    /// connection pool starting cold. Later connections aren't delayed, unless `cold_connect_every`
+    /// is configured.
+    ///
+    /// Read from the `PRELOAD_LATENCY_COLD_CONNECT_MILLIS` environment variable.
+    pub(crate) cold_connect_millis: Option<c_uint>,
+
+    /// If configured, every `cold_connect_every`th connection to each host sleeps for
+    /// `cold_connect_millis`, starting with the first, like a pool that recycles its connections.
+    ///
+    /// Read from the `PRELOAD_LATENCY_COLD_CONNECT_EVERY` environment variable.
+    pub(crate) cold_connect_every: Option<c_uint>,
+
+    /// Number of times a connection to each tracked IP is refused with `ECONNREFUSED` before
+    /// `connect` is allowed to go through.
+    ///
+    /// Read from the `PRELOAD_LATENCY_CONNECT_FAIL_COUNT` environment variable.
+    pub(crate) connect_fail_count: c_uint,
+
+    /// Maximum number of operations per second on sockets connected to each host. Operations over
+    /// the limit additionally sleep for `qps_penalty_millis`.
+    ///
+    /// Read from the `PRELOAD_LATENCY_QPS_LIMIT` environment variable.
+    pub(crate) qps_limit: Option<c_uint>,
+
+    /// Extra duration in milliseconds to sleep for operations over `qps_limit`.
+    ///
+    /// Read from the `PRELOAD_LATENCY_QPS_PENALTY_MILLIS` environment variable.
+    pub(crate) qps_penalty_millis: c_uint,
+
+    /// Number of bytes that may be transferred over sockets connected to each host before its
+    /// operations additionally sleep for `host_quota_penalty_millis`, like a provider throttling
+    /// heavy users. Counts start over when the hosts file is reloaded.
+    ///
+    /// Read from the `PRELOAD_LATENCY_HOST_QUOTA_BYTES` environment variable.
+    pub(crate) host_quota_bytes: Option<u64>,
+
+    /// Extra duration in milliseconds to sleep for operations on hosts over `host_quota_bytes`.
+    ///
+    /// Read from the `PRELOAD_LATENCY_HOST_QUOTA_PENALTY_MILLIS` environment variable.
+    pub(crate) host_quota_penalty_millis: c_uint,
+
+    /// Maximum duration in milliseconds of latency to inject over the lifetime of the process.
+    /// Once the hooks slept for this long in total, nothing is delayed anymore.
+    ///
+    /// Read from the `PRELOAD_LATENCY_BUDGET_MILLIS` environment variable.
+    pub(crate) budget_millis: Option<u64>,
+
+    /// Maximum number of intercepted operations per second across all sockets. Operations over
+    /// the limit pass through without any delay, so the hooks can't become the bottleneck of a
+    /// high-throughput test.
+    ///
+    /// Read from the `PRELOAD_LATENCY_MAX_OPS_PER_SEC` environment variable.
+    pub(crate) max_ops_per_sec: Option<c_uint>,
+
+    /// Only delay every other intercepted operation on each socket: the odd-numbered ones
+    /// (1st, 3rd, ...) for `odd` or the even-numbered ones for `even`. The rest pass through
+    /// without any delay.
+    ///
+    /// Read from the `PRELOAD_LATENCY_ALTERNATE` environment variable.
+    pub(crate) alternate: Option<Parity>,
+
+    /// File a JSON snapshot of the tracked addresses and sockets, the configuration and the stats
+    /// is written to whenever the process receives `SIGHUP`, unless the program replaces the
+    /// handler with its own. Requires the `json` feature.
+    ///
+    /// Read from the `PRELOAD_LATENCY_SNAPSHOT_FILE` environment variable.
+    #[cfg_attr(not(feature = "json"), allow(dead_code))]
+    pub(crate) snapshot_file: Option<PathBuf>,
+
+    /// Whether to log a summary of each tracked socket once it's closed: its peer, how many bytes
+    /// were sent and received, how long its sends and receives slept in total and how long it was
+    /// tracked. Implied by `conn_summary_file`.
+    ///
+    /// Enabled by setting the `PRELOAD_LATENCY_CONN_SUMMARY` environment variable.
+    pub(crate) conn_summary: bool,
+
+    /// File each connection summary is appended to as a line instead of being logged.
+    ///
+    /// Read from the `PRELOAD_LATENCY_CONN_SUMMARY_FILE` environment variable.
+    pub(crate) conn_summary_file: Option<PathBuf>,
+
+    /// Number of recent intercepted calls to include in snapshots written to `snapshot_file`, with
+    /// when they happened, their socket, peer, direction, result and how long they slept for.
+    /// Requires the `json` feature.
+    ///
+    /// Read from the `PRELOAD_LATENCY_RECENT_EVENTS` environment variable.
+    #[cfg_attr(not(feature = "json"), allow(dead_code))]
+    pub(crate) recent_events: Option<usize>,
+
+    /// Interval in seconds at which aggregate stats about intercepted calls are logged.
+    ///
+    /// Read from the `PRELOAD_LATENCY_REPORT_INTERVAL_SECS` environment variable.
+    pub(crate) report_interval: Option<c_uint>,
+
+    /// Whether to print the version and configuration to stderr and exit during initialization,
+    /// before the program runs.
+    ///
+    /// Enabled by setting the `PRELOAD_LATENCY_DIAGNOSE` environment variable.
+    pub(crate) diagnose: bool,
+
+    /// If configured, nothing is intercepted unless the process is in a cgroup whose path contains
+    /// this, e.g. a container ID. Checked once at initialization.
+    ///
+    /// Read from the `PRELOAD_LATENCY_ONLY_CGROUP` environment variable.
+    pub(crate) only_cgroup: Option<String>,
+
+    /// If configured, nothing is intercepted unless the process has this environment variable set
+    /// to something other than an empty string, `0`, `false`, `no` or `off`, e.g. so a test harness
+    /// can arm only some of its children. Checked once at initialization.
+    ///
+    /// Read from the `PRELOAD_LATENCY_REQUIRE_ENV` environment variable.
+    pub(crate) require_env: Option<String>,
+
+    /// If configured, only sockets created by the thread with this ID are intercepted, e.g. to
+    /// isolate one event loop. Sockets created before initialization or passed in from another
+    /// process have no creating thread and aren't intercepted, unless they're first connected on
+    /// this thread.
+    ///
+    /// Read from the `PRELOAD_LATENCY_ONLY_TID` environment variable.
+    pub(crate) only_tid: Option<u64>,
+
+    /// What to do if the configuration is invalid.
+    ///
+    /// Read from the `PRELOAD_LATENCY_ON_ERROR` environment variable.
+    pub(crate) on_error: OnError,
+
+    /// Whether the hooks should pass everything through without intercepting anything. Set when
+    /// the configuration is invalid and `on_error` is [`OnError::Passthrough`], or the process
+    /// isn't in `only_cgroup` or lacks `require_env`.
+    pub(crate) passthrough: bool,
 }
 
-impl HookConfig {
-    pub fn load() -> Self {
-        let hosts = match std::env::var("PRELOAD_LATENCY_HOSTS") {
-            Ok(hosts) => hosts.split(':').map(str::to_owned).collect(),
-            _ => BTreeSet::new(),
-        };
+/// A problem with the configuration found while loading it.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum ConfigError {
+    /// An environment variable couldn't be parsed.
+    Invalid { var: &'static str, value: String },
+    /// An environment variable was parsed but isn't in the accepted range.
+    OutOfRange {
+        var: &'static str,
+        value: String,
+        expected: &'static str,
+    },
+    /// An environment variable is malformed as a whole.
+    Unparseable { var: &'static str, reason: String },
+    /// An entry in a list in an environment variable is malformed.
+    InvalidEntry {
+        var: &'static str,
+        entry: String,
+        reason: String,
+    },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Invalid { var, value } => write!(f, "`{var}` has invalid value `{value}`"),
+            ConfigError::OutOfRange {
+                var,
+                value,
+                expected,
+            } => write!(f, "`{var}` must be {expected}, got `{value}`"),
+            ConfigError::Unparseable { var, reason } => write!(f, "`{var}` is malformed: {reason}"),
+            ConfigError::InvalidEntry { var, entry, reason } => {
+                write!(f, "`{var}` entry `{entry}`: {reason}")
+            }
+        }
+    }
+}
+
+/// Returned by [`HookConfig::try_load`] if the configuration is invalid.
+pub(crate) struct InvalidConfig {
+    /// The configuration with defaults in place of the invalid values.
+    pub(crate) config: Box<HookConfig>,
+    pub(crate) errors: Vec<ConfigError>,
+}
 
-        let sleep_duration_millis = std::env::var("PRELOAD_LATENCY_MILLIS")
-            .unwrap_or_default()
-            .parse()
-            .unwrap_or(200);
+/// Predefined settings for common network conditions.
+///
+/// Read from the `PRELOAD_LATENCY_PROFILE` environment variable. Any of the settings can still be
+/// overridden by their own environment variables.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Profile {
+    Satellite,
+    ThreeG,
+    Transatlantic,
+    Datacenter,
+}
 
-        let toggle_period = std::env::var("PRELOAD_LATENCY_TOGGLE_PERIOD")
-            .ok()
-            .and_then(|s| s.parse().ok());
+/// The settings a [`Profile`] expands into. The default is what's used without a profile.
+pub(crate) struct ProfileSettings {
+    pub(crate) sleep_duration_millis: c_uint,
+    pub(crate) jitter_millis: c_uint,
+    pub(crate) bytes_per_sec: Option<u64>,
+}
 
+impl Default for ProfileSettings {
+    fn default() -> Self {
         Self {
-            hosts,
+            sleep_duration_millis: 200,
+            jitter_millis: 0,
+            bytes_per_sec: None,
+        }
+    }
+}
+
+impl Profile {
+    /// Each intercepted call sleeps for roughly the one-way latency of the link.
+    pub(crate) fn settings(self) -> ProfileSettings {
+        let (sleep_duration_millis, jitter_millis, bytes_per_sec) = match self {
+            // Geostationary orbit, ~600ms round trip at 20 Mbit/s.
+            Profile::Satellite => (300, 50, 2_500_000),
+            // Mobile network, ~200ms round trip at 2 Mbit/s.
+            Profile::ThreeG => (100, 40, 250_000),
+            // Across the Atlantic, ~80ms round trip at 100 Mbit/s.
+            Profile::Transatlantic => (40, 5, 12_500_000),
+            // Within a datacenter, ~2ms round trip at 1 Gbit/s.
+            Profile::Datacenter => (1, 0, 125_000_000),
+        };
+        ProfileSettings {
             sleep_duration_millis,
-            toggle_period,
+            jitter_millis,
+            bytes_per_sec: Some(bytes_per_sec),
         }
     }
+}
 
-    pub(crate) fn maybe_proactively_resolve_hosts(&self) {
-        if std::env::var("PRELOAD_LATENCY_RESOLVE").is_ok() {
-            for host in self.hosts.iter() {
-                tracing::info!("Pre-resolving {host}...");
-                // `to_socket_addrs()` goes through `getaddrinfo()` which tracks the results for us.
-                let Ok(_resolved_addrs) = format!("{host}:80").as_str().to_socket_addrs() else {
-                    tracing::warn!("Failed to resolve `{host}:80`");
-                    continue;
-                };
+impl FromStr for Profile {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "satellite" => Ok(Self::Satellite),
+            "3g" => Ok(Self::ThreeG),
+            "transatlantic" => Ok(Self::Transatlantic),
+            "datacenter" => Ok(Self::Datacenter),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Which addresses may be intercepted, by [`util::IpClass`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Scope {
+    /// Loopback, link-local, RFC 1918 and ULA addresses, for internal service calls.
+    Private,
+    /// Any other address, for egress to the internet.
+    Public,
+    /// Any address.
+    Both,
+}
+
+impl Scope {
+    pub(crate) fn contains(&self, ip: IpAddr) -> bool {
+        match self {
+            Scope::Private => !util::classify_ip(ip).public(),
+            Scope::Public => util::classify_ip(ip).public(),
+            Scope::Both => true,
+        }
+    }
+}
+
+impl FromStr for Scope {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "private" => Ok(Self::Private),
+            "public" => Ok(Self::Public),
+            "both" => Ok(Self::Both),
+            _ => Err(()),
+        }
+    }
+}
+
+/// When intercepted calls sleep relative to the real call.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Timing {
+    /// Sleep before the real call, delaying when data starts to move.
+    Pre,
+    /// Sleep after the real call, delaying when the caller observes the result.
+    Post,
+}
+
+impl Timing {
+    /// Call `call`, running `sleep` before or after it.
+    pub(crate) fn around<T>(self, sleep: impl FnOnce(), call: impl FnOnce() -> T) -> T {
+        match self {
+            Self::Pre => {
+                sleep();
+                call()
             }
+            Self::Post => {
+                let result = call();
+                sleep();
+                result
+            }
+        }
+    }
+}
+
+impl FromStr for Timing {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "pre" => Ok(Self::Pre),
+            "post" => Ok(Self::Post),
+            _ => Err(()),
         }
     }
+}
 
-    pub(crate) fn sleep_duration(&self) -> c_uint {
-        self.sleep_duration_millis * 1000
+/// A preset for which calls are delayed.
+///
+/// Read from the `PRELOAD_LATENCY_MODE` environment variable.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Mode {
+    /// Delay sends and receives.
+    Data,
+    /// Delay `connect` and `getaddrinfo`, but leave sends and receives instantaneous.
+    HandshakeOnly,
+}
+
+impl FromStr for Mode {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "data" => Ok(Self::Data),
+            "handshake-only" => Ok(Self::HandshakeOnly),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Which of a socket's operations `PRELOAD_LATENCY_ALTERNATE` delays.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Parity {
+    Odd,
+    Even,
+}
+
+impl Parity {
+    /// Whether the `n`th operation, counting from 1, has this parity.
+    pub(crate) fn matches(self, n: u64) -> bool {
+        (n % 2 == 1) == (self == Self::Odd)
+    }
+}
+
+impl FromStr for Parity {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "odd" => Ok(Self::Odd),
+            "even" => Ok(Self::Even),
+            _ => Err(()),
+        }
+    }
+}
+
+/// A wall-clock time parsed from an RFC 3339 timestamp like `2026-01-31T14:00:00Z` or a number of
+/// seconds since the Unix epoch.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) struct Timestamp(pub(crate) SystemTime);
+
+impl FromStr for Timestamp {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(secs) = s.parse() {
+            return Ok(Self(UNIX_EPOCH + Duration::from_secs(secs)));
+        }
+        chrono::DateTime::parse_from_rfc3339(s)
+            .map(|time| Self(time.into()))
+            .map_err(|_| ())
+    }
+}
+
+impl fmt::Display for Timestamp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let time: chrono::DateTime<chrono::Utc> = self.0.into();
+        write!(f, "{}", time.to_rfc3339())
+    }
+}
+
+/// A DNS record type, i.e. an address family.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum RrType {
+    /// IPv4 addresses.
+    A,
+    /// IPv6 addresses.
+    Aaaa,
+}
+
+impl RrType {
+    pub(crate) fn matches(&self, ip: IpAddr) -> bool {
+        match self {
+            RrType::A => ip.is_ipv4(),
+            RrType::Aaaa => ip.is_ipv6(),
+        }
+    }
+}
+
+impl FromStr for RrType {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "A" => Ok(Self::A),
+            "AAAA" => Ok(Self::Aaaa),
+            _ => Err(()),
+        }
+    }
+}
+
+/// An error `getaddrinfo` can fail with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct DnsFailCode(pub(crate) c_int);
+
+impl FromStr for DnsFailCode {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let code = match s {
+            "EAI_AGAIN" => libc::EAI_AGAIN,
+            "EAI_FAIL" => libc::EAI_FAIL,
+            "EAI_MEMORY" => libc::EAI_MEMORY,
+            "EAI_NODATA" => libc::EAI_NODATA,
+            "EAI_NONAME" => libc::EAI_NONAME,
+            "EAI_SYSTEM" => libc::EAI_SYSTEM,
+            _ => return Err(()),
+        };
+        Ok(Self(code))
+    }
+}
+
+/// Bytes parsed from a hex string, e.g. `2a33` for `*3`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct HexBytes(pub(crate) Vec<u8>);
+
+impl FromStr for HexBytes {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if !s.len().is_multiple_of(2) {
+            return Err(());
+        }
+        (0..s.len())
+            .step_by(2)
+            .map(|i| {
+                s.get(i..i + 2)
+                    .and_then(|byte| u8::from_str_radix(byte, 16).ok())
+            })
+            .collect::<Option<_>>()
+            .map(Self)
+            .ok_or(())
+    }
+}
+
+/// What to do when the configuration is invalid.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum OnError {
+    /// Intercept nothing.
+    Passthrough,
+    /// Use the defaults in place of invalid values.
+    Default,
+}
+
+impl FromStr for OnError {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "passthrough" => Ok(Self::Passthrough),
+            "default" => Ok(Self::Default),
+            _ => Err(()),
+        }
+    }
+}
+
+impl HookConfig {
+    /// Load the configuration from the environment, applying [`HookConfig::on_error`] if it's
+    /// invalid.
+    pub fn load() -> Self {
+        let mut config = match Self::try_load() {
+            Ok(config) => config,
+            Err(InvalidConfig { mut config, errors }) => {
+                for error in errors.iter() {
+                    tracing::error!("Invalid configuration: {error}");
+                }
+                match config.on_error {
+                    OnError::Passthrough => {
+                        tracing::error!("Configuration is invalid, passing everything through");
+                        config.passthrough = true;
+                    }
+                    OnError::Default => {
+                        tracing::error!(
+                            "Configuration is invalid, using defaults for invalid values"
+                        );
+                    }
+                }
+                *config
+            }
+        };
+        if !config.passthrough && !config.in_only_cgroup() {
+            tracing::info!("Process isn't in the configured cgroup, passing everything through");
+            config.passthrough = true;
+        }
+        if !config.passthrough && !config.has_required_env() {
+            tracing::info!("Required environment variable isn't set, passing everything through");
+            config.passthrough = true;
+        }
+        config
+    }
+
+    /// Whether the variable named by `require_env` is set to a truthy value, or none is required.
+    fn has_required_env(&self) -> bool {
+        let Some(require_env) = &self.require_env else {
+            return true;
+        };
+        std::env::var(require_env).is_ok_and(|value| is_truthy(&value))
+    }
+
+    /// Whether the process is in a cgroup matching `only_cgroup`, or no cgroup is required.
+    fn in_only_cgroup(&self) -> bool {
+        let Some(only_cgroup) = &self.only_cgroup else {
+            return true;
+        };
+        match std::fs::read_to_string("/proc/self/cgroup") {
+            Ok(cgroups) => cgroups_contain(&cgroups, only_cgroup),
+            Err(e) => {
+                tracing::warn!("Failed to read /proc/self/cgroup: {e}");
+                false
+            }
+        }
+    }
+
+    /// Load the configuration from the environment. If anything is invalid, the error holds every
+    /// problem along with the configuration using defaults in place of the invalid values.
+    pub(crate) fn try_load() -> Result<Self, InvalidConfig> {
+        let mut errors = Vec::new();
+
+        let vars = Vars::load(&mut errors);
+
+        let hosts_var = vars.get("PRELOAD_LATENCY_HOSTS");
+        let no_hosts = hosts_var.as_deref() == Some("-");
+        let hosts = match hosts_var {
+            Some(hosts) if !no_hosts => split_hosts(&hosts),
+            _ => Vec::new(),
+        };
+
+        let hosts_file = env_var(&vars, "PRELOAD_LATENCY_HOSTS_FILE", &mut errors);
+
+        let match_so = env_var(&vars, "PRELOAD_LATENCY_MATCH_SO", &mut errors);
+
+        let hostmap_path = env_var::<PathBuf>(&vars, "PRELOAD_LATENCY_HOSTMAP", &mut errors);
+        let hostmap = hostmap_path
+            .as_deref()
+            .map(|path| load_hostmap(path, &mut errors))
+            .unwrap_or_default();
+
+        let all_hosts =
+            !no_hosts && hosts.is_empty() && hosts_file.is_none() && hostmap_path.is_none();
+
+        let profile = env_var::<Profile>(&vars, "PRELOAD_LATENCY_PROFILE", &mut errors)
+            .map(Profile::settings)
+            .unwrap_or_default();
+
+        let sleep_duration_millis = env_var(&vars, "PRELOAD_LATENCY_MILLIS", &mut errors)
+            .unwrap_or(profile.sleep_duration_millis);
+
+        let v4_millis = env_var(&vars, "PRELOAD_LATENCY_V4_MILLIS", &mut errors);
+
+        let v6_millis = env_var(&vars, "PRELOAD_LATENCY_V6_MILLIS", &mut errors);
+
+        let cdf = vars
+            .get("PRELOAD_LATENCY_CDF")
+            .and_then(|cdf| parse_cdf(&cdf, &mut errors));
+
+        let response_millis = env_var(&vars, "PRELOAD_LATENCY_RESPONSE_MILLIS", &mut errors);
+
+        let size_buckets = vars
+            .get("PRELOAD_LATENCY_SIZE_BUCKETS")
+            .map(|buckets| parse_size_buckets(&buckets, &mut errors))
+            .unwrap_or_default();
+
+        let coalesce_msg_more = vars.get("PRELOAD_LATENCY_COALESCE_MSG_MORE").is_some();
+
+        let coalesce_concurrent = vars.get("PRELOAD_LATENCY_COALESCE_CONCURRENT").is_some();
+
+        let tod_profile = vars
+            .get("PRELOAD_LATENCY_TOD_PROFILE")
+            .and_then(|profile| parse_tod_profile(&profile, &mut errors));
+
+        let load_threshold = env_var_where(
+            &vars,
+            "PRELOAD_LATENCY_LOAD_THRESHOLD",
+            &mut errors,
+            "a positive number",
+            |threshold: &f64| *threshold > 0.0 && threshold.is_finite(),
+        );
+
+        let decay_secs = env_var_where(
+            &vars,
+            "PRELOAD_LATENCY_DECAY_SECS",
+            &mut errors,
+            "a positive number",
+            |secs: &c_uint| *secs > 0,
+        );
+
+        let decay_factor = env_var_where(
+            &vars,
+            "PRELOAD_LATENCY_DECAY_FACTOR",
+            &mut errors,
+            "a number of at least 1",
+            |factor: &f64| *factor >= 1.0,
+        )
+        .unwrap_or(4.0);
+
+        let jitter_millis = env_var(&vars, "PRELOAD_LATENCY_JITTER_MILLIS", &mut errors)
+            .unwrap_or(profile.jitter_millis);
+
+        let host_offset_millis = env_var(&vars, "PRELOAD_LATENCY_HOST_OFFSET_MILLIS", &mut errors);
+
+        let seed = env_var(&vars, "PRELOAD_LATENCY_SEED", &mut errors);
+
+        let random_direction = vars.get("PRELOAD_LATENCY_RANDOM_DIRECTION").is_some();
+
+        let conn_factor = env_var_where(
+            &vars,
+            "PRELOAD_LATENCY_CONN_FACTOR",
+            &mut errors,
+            "a non-negative number",
+            |factor: &f64| *factor >= 0.0,
+        );
+
+        let conn_sample_rate = env_var_where(
+            &vars,
+            "PRELOAD_LATENCY_CONN_SAMPLE_RATE",
+            &mut errors,
+            "a number between 0 and 1",
+            |rate: &f64| (0.0..=1.0).contains(rate),
+        );
+
+        let wave_period_secs = env_var_where(
+            &vars,
+            "PRELOAD_LATENCY_WAVE_PERIOD_SECS",
+            &mut errors,
+            "a positive number",
+            |secs: &c_uint| *secs > 0,
+        );
+
+        let wave_amplitude_millis =
+            env_var(&vars, "PRELOAD_LATENCY_WAVE_AMPLITUDE_MILLIS", &mut errors).unwrap_or(0);
+
+        let shm = env_var_where(
+            &vars,
+            "PRELOAD_LATENCY_SHM",
+            &mut errors,
+            "a name starting with `/`",
+            |shm: &String| shm.starts_with('/'),
+        );
+
+        let flag_file = env_var(&vars, "PRELOAD_LATENCY_FLAG_FILE", &mut errors);
+
+        let dynamic_file = env_var(&vars, "PRELOAD_LATENCY_DYNAMIC_FILE", &mut errors);
+
+        let grace_period = env_var(&vars, "PRELOAD_LATENCY_GRACE_SECS", &mut errors);
+
+        let start_at = env_var(&vars, "PRELOAD_LATENCY_START_AT", &mut errors);
+
+        let stop_at = env_var_where(
+            &vars,
+            "PRELOAD_LATENCY_STOP_AT",
+            &mut errors,
+            "later than `PRELOAD_LATENCY_START_AT`",
+            |stop_at: &Timestamp| start_at.is_none_or(|start_at| *stop_at > start_at),
+        );
+
+        let toggle_period = env_var_where(
+            &vars,
+            "PRELOAD_LATENCY_TOGGLE_PERIOD",
+            &mut errors,
+            "a positive number",
+            |secs: &c_uint| *secs > 0,
+        );
+
+        let duty_cycle = env_var_where(
+            &vars,
+            "PRELOAD_LATENCY_DUTY_CYCLE",
+            &mut errors,
+            "a number between 0 and 1",
+            |duty_cycle: &f64| (0.0..=1.0).contains(duty_cycle),
+        );
+        if duty_cycle.is_some() && toggle_period.is_none() {
+            errors.push(ConfigError::Unparseable {
+                var: "PRELOAD_LATENCY_DUTY_CYCLE",
+                reason: "requires `PRELOAD_LATENCY_TOGGLE_PERIOD`".to_owned(),
+            });
+        }
+        let duty_cycle = duty_cycle.unwrap_or(1.0);
+
+        let nonblock_factor = env_var_where(
+            &vars,
+            "PRELOAD_LATENCY_NONBLOCK_FACTOR",
+            &mut errors,
+            "a non-negative number",
+            |factor: &f64| *factor >= 0.0,
+        )
+        .unwrap_or(1.0);
+
+        let burst = env_var(&vars, "PRELOAD_LATENCY_BURST_SECS", &mut errors);
+
+        let burst_interval = env_var_where(
+            &vars,
+            "PRELOAD_LATENCY_BURST_INTERVAL_SECS",
+            &mut errors,
+            "a positive number",
+            |secs: &c_uint| *secs > 0,
+        )
+        .unwrap_or(60);
+
+        let cidrs = vars
+            .get("PRELOAD_LATENCY_CIDRS")
+            .map(|cidrs| parse_cidrs(&cidrs, &mut errors))
+            .unwrap_or_default();
+
+        let local_cidrs = vars
+            .get("PRELOAD_LATENCY_LOCAL_CIDRS")
+            .map(|cidrs| parse_local_cidrs(&cidrs, &mut errors))
+            .unwrap_or_default();
+
+        let force_fds = vars
+            .get("PRELOAD_LATENCY_FORCE_FDS")
+            .map(|fds| parse_fds(&fds, &mut errors))
+            .unwrap_or_default();
+
+        let blanket = vars.get("PRELOAD_LATENCY_BLANKET").is_some();
+
+        let exclude_loopback = vars.get("PRELOAD_LATENCY_EXCLUDE_LOOPBACK").is_some();
+
+        let scope = env_var(&vars, "PRELOAD_LATENCY_SCOPE", &mut errors).unwrap_or(Scope::Both);
+
+        let asn_db = env_var::<PathBuf>(&vars, "PRELOAD_LATENCY_ASN_DB", &mut errors)
+            .and_then(|path| load_asn_db(&path, &mut errors));
+
+        let asns = vars
+            .get("PRELOAD_LATENCY_ASNS")
+            .map(|asns| parse_asns(&asns, &mut errors))
+            .unwrap_or_default();
+        if asn_db.is_some() == asns.is_empty() {
+            errors.push(ConfigError::Unparseable {
+                var: if asn_db.is_some() {
+                    "PRELOAD_LATENCY_ASN_DB"
+                } else {
+                    "PRELOAD_LATENCY_ASNS"
+                },
+                reason: "`PRELOAD_LATENCY_ASN_DB` and `PRELOAD_LATENCY_ASNS` must be set together"
+                    .to_owned(),
+            });
+        }
+
+        let intercept_socketpair = vars.get("PRELOAD_LATENCY_INTERCEPT_SOCKETPAIR").is_some();
+
+        let bytes_per_sec = env_var_where(
+            &vars,
+            "PRELOAD_LATENCY_BYTES_PER_SEC",
+            &mut errors,
+            "a positive number",
+            |bytes_per_sec: &u64| *bytes_per_sec > 0,
+        )
+        .or(profile.bytes_per_sec);
+
+        let shared_bps = env_var_where(
+            &vars,
+            "PRELOAD_LATENCY_SHARED_BPS",
+            &mut errors,
+            "a positive number",
+            |shared_bps: &u64| *shared_bps > 0,
+        );
+
+        let global_bytes = env_var(&vars, "PRELOAD_LATENCY_GLOBAL_BYTES", &mut errors);
+
+        let dns_rrtype = env_var(&vars, "PRELOAD_LATENCY_DNS_RRTYPE", &mut errors);
+
+        let first_dns_millis = env_var(&vars, "PRELOAD_LATENCY_FIRST_DNS_MILLIS", &mut errors);
+
+        let dns_fail_rate = env_var_where(
+            &vars,
+            "PRELOAD_LATENCY_DNS_FAIL_RATE",
+            &mut errors,
+            "a number between 0 and 1",
+            |rate: &f64| (0.0..=1.0).contains(rate),
+        );
+
+        let dns_fail_code = env_var(&vars, "PRELOAD_LATENCY_DNS_FAIL_CODE", &mut errors)
+            .unwrap_or(DnsFailCode(libc::EAI_AGAIN));
+
+        let log_resolutions = vars.get("PRELOAD_LATENCY_LOG_RESOLUTIONS").is_some();
+
+        let log_unmatched = vars.get("PRELOAD_LATENCY_LOG_UNMATCHED").is_some();
+
+        let log_ops = vars.get("PRELOAD_LATENCY_LOG_OPS").is_some();
+
+        let opcode_prefix = env_var(&vars, "PRELOAD_LATENCY_OPCODE_PREFIX", &mut errors);
+
+        let payload_contains = env_var(&vars, "PRELOAD_LATENCY_PAYLOAD_CONTAINS", &mut errors);
+
+        let alpn = vars
+            .get("PRELOAD_LATENCY_ALPN")
+            .map(|alpn| {
+                alpn.split(':')
+                    .filter(|protocol| !protocol.is_empty())
+                    .map(str::to_owned)
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        let sni_millis = vars
+            .get("PRELOAD_LATENCY_SNI_MILLIS")
+            .map(|sni_millis| parse_sni_millis(&sni_millis, &mut errors))
+            .unwrap_or_default();
+
+        let multiplier = env_var_where(
+            &vars,
+            "PRELOAD_LATENCY_MULTIPLIER",
+            &mut errors,
+            "a number of at least 1",
+            |multiplier: &f64| *multiplier >= 1.0,
+        );
+
+        let mode = env_var(&vars, "PRELOAD_LATENCY_MODE", &mut errors).unwrap_or(Mode::Data);
+        let delay_handshake = mode == Mode::HandshakeOnly;
+        let delay_data = mode == Mode::Data;
+
+        let timing = env_var(&vars, "PRELOAD_LATENCY_TIMING", &mut errors).unwrap_or(Timing::Pre);
+
+        let retry_eintr = vars.get("PRELOAD_LATENCY_RETRY_EINTR").is_some();
+
+        let skip_peek_delay = vars.get("PRELOAD_LATENCY_SKIP_PEEK_DELAY").is_some();
+
+        let short_io_rate = env_var_where(
+            &vars,
+            "PRELOAD_LATENCY_SHORT_IO_RATE",
+            &mut errors,
+            "a number between 0 and 1",
+            |rate: &f64| (0.0..=1.0).contains(rate),
+        );
+
+        let backpressure_rate = env_var_where(
+            &vars,
+            "PRELOAD_LATENCY_BACKPRESSURE_RATE",
+            &mut errors,
+            "a number between 0 and 1",
+            |rate: &f64| (0.0..=1.0).contains(rate),
+        );
+
+        let drain_millis = env_var(&vars, "PRELOAD_LATENCY_DRAIN_MILLIS", &mut errors);
+
+        let listen_close_millis =
+            env_var(&vars, "PRELOAD_LATENCY_LISTEN_CLOSE_MILLIS", &mut errors);
+
+        let epoll_wait_millis = env_var(&vars, "PRELOAD_LATENCY_EPOLL_WAIT_MILLIS", &mut errors);
+
+        let cold_connect_millis =
+            env_var(&vars, "PRELOAD_LATENCY_COLD_CONNECT_MILLIS", &mut errors);
+
+        let cold_connect_every = env_var_where(
+            &vars,
+            "PRELOAD_LATENCY_COLD_CONNECT_EVERY",
+            &mut errors,
+            "a positive number",
+            |every: &c_uint| *every > 0,
+        );
+
+        let connect_fail_count =
+            env_var(&vars, "PRELOAD_LATENCY_CONNECT_FAIL_COUNT", &mut errors).unwrap_or(0);
+
+        let qps_limit = env_var(&vars, "PRELOAD_LATENCY_QPS_LIMIT", &mut errors);
+
+        let qps_penalty_millis = env_var(&vars, "PRELOAD_LATENCY_QPS_PENALTY_MILLIS", &mut errors)
+            .unwrap_or(sleep_duration_millis);
+
+        let host_quota_bytes = env_var(&vars, "PRELOAD_LATENCY_HOST_QUOTA_BYTES", &mut errors);
+
+        let host_quota_penalty_millis = env_var(
+            &vars,
+            "PRELOAD_LATENCY_HOST_QUOTA_PENALTY_MILLIS",
+            &mut errors,
+        )
+        .unwrap_or(sleep_duration_millis);
+
+        let budget_millis = env_var(&vars, "PRELOAD_LATENCY_BUDGET_MILLIS", &mut errors);
+
+        let max_ops_per_sec = env_var_where(
+            &vars,
+            "PRELOAD_LATENCY_MAX_OPS_PER_SEC",
+            &mut errors,
+            "a positive number",
+            |ops: &c_uint| *ops > 0,
+        );
+
+        let alternate = env_var(&vars, "PRELOAD_LATENCY_ALTERNATE", &mut errors);
+
+        let resolve = vars.get("PRELOAD_LATENCY_RESOLVE").is_some();
+
+        let resolve_timeout_millis =
+            env_var(&vars, "PRELOAD_LATENCY_RESOLVE_TIMEOUT_MILLIS", &mut errors).unwrap_or(5000);
+
+        let reresolve_secs = env_var_where(
+            &vars,
+            "PRELOAD_LATENCY_RERESOLVE_SECS",
+            &mut errors,
+            "a positive number",
+            |secs: &c_uint| *secs > 0,
+        );
+
+        let report_interval = env_var_where(
+            &vars,
+            "PRELOAD_LATENCY_REPORT_INTERVAL_SECS",
+            &mut errors,
+            "a positive number",
+            |secs: &c_uint| *secs > 0,
+        );
+
+        let conn_summary_file =
+            env_var::<PathBuf>(&vars, "PRELOAD_LATENCY_CONN_SUMMARY_FILE", &mut errors);
+        let conn_summary =
+            vars.get("PRELOAD_LATENCY_CONN_SUMMARY").is_some() || conn_summary_file.is_some();
+
+        let snapshot_file: Option<PathBuf> =
+            env_var(&vars, "PRELOAD_LATENCY_SNAPSHOT_FILE", &mut errors);
+        #[cfg(not(feature = "json"))]
+        if snapshot_file.is_some() {
+            errors.push(ConfigError::Unparseable {
+                var: "PRELOAD_LATENCY_SNAPSHOT_FILE",
+                reason: "the hooks were built without the `json` feature".to_owned(),
+            });
+        }
+
+        let recent_events = env_var_where(
+            &vars,
+            "PRELOAD_LATENCY_RECENT_EVENTS",
+            &mut errors,
+            "a positive number",
+            |recent_events: &usize| *recent_events > 0,
+        );
+        // There's nothing to show the buffer in without snapshots.
+        #[cfg(not(feature = "json"))]
+        let recent_events = recent_events.and_then(|_| {
+            errors.push(ConfigError::Unparseable {
+                var: "PRELOAD_LATENCY_RECENT_EVENTS",
+                reason: "the hooks were built without the `json` feature".to_owned(),
+            });
+            None
+        });
+
+        let diagnose = vars.get("PRELOAD_LATENCY_DIAGNOSE").is_some();
+
+        let only_cgroup = vars
+            .get("PRELOAD_LATENCY_ONLY_CGROUP")
+            .filter(|only_cgroup| !only_cgroup.is_empty());
+
+        let require_env = vars
+            .get("PRELOAD_LATENCY_REQUIRE_ENV")
+            .filter(|require_env| !require_env.is_empty());
+        if require_env
+            .as_ref()
+            .is_some_and(|require_env| require_env.contains(['=', '\0']))
+        {
+            errors.push(ConfigError::Unparseable {
+                var: "PRELOAD_LATENCY_REQUIRE_ENV",
+                reason: "expected an environment variable name".to_owned(),
+            });
+        }
+
+        let only_tid = env_var(&vars, "PRELOAD_LATENCY_ONLY_TID", &mut errors);
+
+        let on_error =
+            env_var(&vars, "PRELOAD_LATENCY_ON_ERROR", &mut errors).unwrap_or(OnError::Default);
+
+        vars.check_unknown(&mut errors);
+
+        let config = Self {
+            hosts,
+            hosts_file,
+            hostmap,
+            match_so,
+            all_hosts,
+            resolve,
+            resolve_timeout_millis,
+            reresolve_secs,
+            sleep_duration_millis,
+            v4_millis,
+            v6_millis,
+            cdf,
+            response_millis,
+            size_buckets,
+            coalesce_msg_more,
+            coalesce_concurrent,
+            tod_profile,
+            load_threshold,
+            decay_secs,
+            decay_factor,
+            conn_factor,
+            conn_sample_rate,
+            wave_period_secs,
+            wave_amplitude_millis,
+            shm,
+            flag_file,
+            dynamic_file,
+            grace_period,
+            start_at,
+            stop_at,
+            jitter_millis,
+            host_offset_millis,
+            seed,
+            random_direction,
+            toggle_period,
+            duty_cycle,
+            nonblock_factor,
+            burst,
+            burst_interval,
+            cidrs,
+            local_cidrs,
+            force_fds,
+            blanket,
+            exclude_loopback,
+            scope,
+            asn_db,
+            asns,
+            intercept_socketpair,
+            bytes_per_sec,
+            shared_bps,
+            global_bytes,
+            dns_rrtype,
+            first_dns_millis,
+            dns_fail_rate,
+            dns_fail_code,
+            log_resolutions,
+            log_unmatched,
+            log_ops,
+            opcode_prefix,
+            payload_contains,
+            alpn,
+            sni_millis,
+            multiplier,
+            delay_handshake,
+            delay_data,
+            timing,
+            retry_eintr,
+            skip_peek_delay,
+            short_io_rate,
+            backpressure_rate,
+            drain_millis,
+            listen_close_millis,
+            epoll_wait_millis,
+            cold_connect_millis,
+            cold_connect_every,
+            connect_fail_count,
+            qps_limit,
+            qps_penalty_millis,
+            host_quota_bytes,
+            host_quota_penalty_millis,
+            budget_millis,
+            max_ops_per_sec,
+            alternate,
+            snapshot_file,
+            conn_summary,
+            conn_summary_file,
+            recent_events,
+            report_interval,
+            diagnose,
+            only_cgroup,
+            require_env,
+            only_tid,
+            on_error,
+            passthrough: false,
+        };
+        if errors.is_empty() {
+            Ok(config)
+        } else {
+            Err(InvalidConfig {
+                config: Box::new(config),
+                errors,
+            })
+        }
+    }
+
+    pub(crate) fn maybe_proactively_resolve_hosts(&self) {
+        if self.resolve {
+            self.resolve_hosts(|_, _| {});
+        }
+    }
+
+    /// Resolve `hosts` using `getaddrinfo`, calling `on_resolved` with the addresses of each host
+    /// that resolved.
+    pub(crate) fn resolve_hosts(
+        &self,
+        mut on_resolved: impl FnMut(&str, Vec<IpAddr>) + Send + 'static,
+    ) {
+        // Resolve on another thread so a hanging resolver can't block the caller forever. If it
+        // takes too long the thread keeps going, and whatever it resolves is still tracked.
+        let hosts: Vec<String> = self
+            .hosts
+            .iter()
+            .filter_map(HostRule::resolvable_host)
+            .collect();
+        let (done, resolved) = mpsc::channel();
+        let spawned = std::thread::Builder::new()
+            .name("preload_latency-resolve".to_owned())
+            .spawn(move || {
+                for host in hosts.iter() {
+                    tracing::info!("Pre-resolving {host}...");
+                    // `to_socket_addrs()` goes through `getaddrinfo()` which tracks the results
+                    // for us.
+                    let Ok(resolved_addrs) = format!("{host}:80").as_str().to_socket_addrs() else {
+                        tracing::warn!("Failed to resolve `{host}:80`");
+                        continue;
+                    };
+                    on_resolved(host, resolved_addrs.map(|addr| addr.ip()).collect());
+                }
+                let _ = done.send(());
+            });
+        if let Err(e) = spawned {
+            tracing::warn!("Failed to spawn a thread to pre-resolve hosts: {e}");
+            return;
+        }
+        let timeout = Duration::from_millis(self.resolve_timeout_millis.into());
+        if let Err(RecvTimeoutError::Timeout) = resolved.recv_timeout(timeout) {
+            tracing::warn!(
+                "Pre-resolving hosts took longer than {} milliseconds, continuing without them",
+                self.resolve_timeout_millis
+            );
+        }
+    }
+
+    pub(crate) fn sleep_duration(&self) -> c_uint {
+        match &self.cdf {
+            Some(cdf) => cdf.sample_micros(util::random_f64()),
+            None => dynamic::millis()
+                .unwrap_or(self.sleep_duration_millis)
+                .saturating_mul(1000),
+        }
+    }
+
+    /// Randomly move `duration` by up to `jitter_millis` in either direction.
+    pub(crate) fn jittered(&self, duration: c_uint) -> c_uint {
+        if self.jitter_millis == 0 {
+            return duration;
+        }
+        let jitter = u64::from(self.jitter_millis) * 1000;
+        let offset = (util::random_u64() % (2 * jitter + 1)) as i64 - jitter as i64;
+        (i64::from(duration) + offset).clamp(0, c_uint::MAX.into()) as c_uint
+    }
+
+    /// Scale `duration` for a socket that was tracked for `age`, given `decay_secs`.
+    pub(crate) fn decayed(&self, duration: c_uint, age: Duration) -> c_uint {
+        let Some(decay_secs) = self.decay_secs else {
+            return duration;
+        };
+        let remaining = 1.0 - (age.as_secs_f64() / f64::from(decay_secs)).min(1.0);
+        let factor = 1.0 + (self.decay_factor - 1.0) * remaining;
+        (f64::from(duration) * factor).min(c_uint::MAX.into()) as c_uint
+    }
+
+    /// Scale `duration` for `open` tracked sockets, given `conn_factor`.
+    pub(crate) fn conn_scaled(&self, duration: c_uint, open: u64) -> c_uint {
+        let Some(conn_factor) = self.conn_factor else {
+            return duration;
+        };
+        let factor = 1.0 + conn_factor * open.saturating_sub(1) as f64;
+        (f64::from(duration) * factor).min(c_uint::MAX.into()) as c_uint
+    }
+
+    /// Move `duration` by the value of the wave with `wave_period_secs` at `elapsed` since
+    /// initialization.
+    pub(crate) fn waved(&self, duration: c_uint, elapsed: Duration) -> c_uint {
+        let Some(wave_period_secs) = self.wave_period_secs else {
+            return duration;
+        };
+        let phase = elapsed.as_secs_f64() / f64::from(wave_period_secs) * std::f64::consts::TAU;
+        let offset = f64::from(self.wave_amplitude_millis) * 1000.0 * phase.sin();
+        (f64::from(duration) + offset).clamp(0.0, c_uint::MAX.into()) as c_uint
+    }
+
+    /// Whether a send (or receive, if `send` is false) is delayed given `random_direction`.
+    pub(crate) fn delays_direction(&self, send: bool) -> bool {
+        !self.random_direction || (util::random_u64() & 1 == 0) == send
+    }
+
+    /// Move `duration` by the stable offset for `host`, up to `host_offset_millis` in either
+    /// direction.
+    pub(crate) fn host_offset(&self, duration: c_uint, host: &str) -> c_uint {
+        let Some(host_offset_millis) = self.host_offset_millis else {
+            return duration;
+        };
+        let max_offset = u64::from(host_offset_millis) * 1000;
+        let hash = util::stable_hash(host.as_bytes(), self.seed.unwrap_or_default());
+        let offset = (hash % (2 * max_offset + 1)) as i64 - max_offset as i64;
+        (i64::from(duration) + offset).clamp(0, c_uint::MAX.into()) as c_uint
+    }
+
+    /// Scale a sleep duration for a socket with `O_NONBLOCK` set.
+    pub(crate) fn nonblock_sleep_duration(&self, duration: c_uint) -> c_uint {
+        (f64::from(duration) * self.nonblock_factor) as c_uint
+    }
+
+    /// Duration in microseconds it takes to transfer `bytes` at `bytes_per_sec`.
+    pub(crate) fn transfer_duration(&self, bytes: usize) -> c_uint {
+        let Some(bytes_per_sec) = self.bytes_per_sec else {
+            return 0;
+        };
+        let micros = (bytes as u128 * 1_000_000) / u128::from(bytes_per_sec);
+        micros.try_into().unwrap_or(c_uint::MAX)
+    }
+
+    /// The most specific entry in `cidrs` containing `ip`, if any.
+    pub(crate) fn matching_cidr(&self, ip: IpAddr) -> Option<&HostRule> {
+        self.cidrs
+            .iter()
+            .filter(|rule| rule.matches(Destination::Ip(ip)))
+            .max_by_key(|rule| rule.prefix_len())
+    }
+
+    /// Duration in microseconds to sleep for a call of `len` bytes, if it's in one of
+    /// `size_buckets`.
+    pub(crate) fn size_bucket_duration(&self, len: usize) -> Option<c_uint> {
+        self.size_buckets
+            .iter()
+            .find(|bucket| bucket.contains(len))
+            .map(|bucket| bucket.millis().saturating_mul(1000))
+    }
+
+    /// Whether `ip` may be intercepted according to `scope`, `asn_db` and `asns`.
+    pub(crate) fn in_scope(&self, ip: IpAddr) -> bool {
+        self.scope.contains(ip)
+            && (self.asns.is_empty()
+                || self
+                    .asn_db
+                    .as_ref()
+                    .and_then(|asn_db| asn_db.lookup(ip))
+                    .is_some_and(|asn| self.asns.contains(&asn)))
+    }
+
+    /// Whether `host` is one of the names in `hostmap`.
+    pub(crate) fn in_hostmap(&self, host: &str) -> bool {
+        self.hostmap
+            .iter()
+            .any(|(_, names)| names.iter().any(|name| name == host))
+    }
+
+    /// Sleep duration in microseconds for a socket connected to `ip`.
+    pub(crate) fn sleep_duration_for(&self, ip: Option<IpAddr>) -> c_uint {
+        ip.and_then(|ip| self.matching_cidr(ip))
+            .and_then(HostRule::millis)
+            .or_else(|| match ip?.to_canonical() {
+                IpAddr::V4(_) => self.v4_millis,
+                IpAddr::V6(_) => self.v6_millis,
+            })
+            .map(|millis| millis.saturating_mul(1000))
+            .unwrap_or_else(|| self.sleep_duration())
+    }
+}
+
+/// Parse the environment variable `name`. Unset or empty variables are `None`, and malformed ones
+/// are `None` with an entry pushed onto `errors`.
+fn env_var<T: FromStr>(
+    vars: &Vars,
+    name: &'static str,
+    errors: &mut Vec<ConfigError>,
+) -> Option<T> {
+    let value = vars.get(name).filter(|value| !value.is_empty())?;
+    let parsed = value.parse().ok();
+    if parsed.is_none() {
+        errors.push(ConfigError::Invalid { var: name, value });
+    }
+    parsed
+}
+
+/// Like [`env_var`], but values that parse and aren't `valid` are also treated as malformed.
+/// `expected` describes valid values.
+fn env_var_where<T: FromStr + fmt::Display>(
+    vars: &Vars,
+    name: &'static str,
+    errors: &mut Vec<ConfigError>,
+    expected: &'static str,
+    valid: impl FnOnce(&T) -> bool,
+) -> Option<T> {
+    let value = env_var(vars, name, errors)?;
+    if valid(&value) {
+        Some(value)
+    } else {
+        errors.push(ConfigError::OutOfRange {
+            var: name,
+            value: value.to_string(),
+            expected,
+        });
+        None
+    }
+}
+
+fn parse_local_cidrs(cidrs: &str, errors: &mut Vec<ConfigError>) -> Vec<Cidr> {
+    cidrs
+        .split(',')
+        .filter(|entry| !entry.is_empty())
+        .filter_map(|entry| match entry.parse() {
+            Ok(cidr) => Some(cidr),
+            Err(reason) => {
+                errors.push(ConfigError::InvalidEntry {
+                    var: "PRELOAD_LATENCY_LOCAL_CIDRS",
+                    entry: entry.to_owned(),
+                    reason,
+                });
+                None
+            }
+        })
+        .collect()
+}
+
+fn parse_size_buckets(buckets: &str, errors: &mut Vec<ConfigError>) -> Vec<SizeBucket> {
+    buckets
+        .split(',')
+        .filter(|entry| !entry.is_empty())
+        .filter_map(|entry| match entry.parse() {
+            Ok(bucket) => Some(bucket),
+            Err(reason) => {
+                errors.push(ConfigError::InvalidEntry {
+                    var: "PRELOAD_LATENCY_SIZE_BUCKETS",
+                    entry: entry.to_owned(),
+                    reason,
+                });
+                None
+            }
+        })
+        .collect()
+}
+
+/// Parse `PRELOAD_LATENCY_TOD_PROFILE`. Malformed ranges are skipped with an entry pushed onto
+/// `errors`.
+fn parse_tod_profile(profile: &str, errors: &mut Vec<ConfigError>) -> Option<TodProfile> {
+    let ranges: Vec<HourRange> = profile
+        .split(',')
+        .filter(|entry| !entry.is_empty())
+        .filter_map(|entry| match entry.parse() {
+            Ok(range) => Some(range),
+            Err(reason) => {
+                errors.push(ConfigError::InvalidEntry {
+                    var: "PRELOAD_LATENCY_TOD_PROFILE",
+                    entry: entry.to_owned(),
+                    reason,
+                });
+                None
+            }
+        })
+        .collect();
+    (!ranges.is_empty()).then(|| TodProfile::new(&ranges))
+}
+
+/// Load `PRELOAD_LATENCY_HOSTMAP` from `path`. Like in `/etc/hosts`, each line is an address
+/// followed by its names, and `#` starts a comment.
+fn load_hostmap(path: &Path, errors: &mut Vec<ConfigError>) -> Vec<(IpAddr, Vec<String>)> {
+    let contents = match std::fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) => {
+            errors.push(ConfigError::Unparseable {
+                var: "PRELOAD_LATENCY_HOSTMAP",
+                reason: format!("failed to read {}: {e}", path.display()),
+            });
+            return Vec::new();
+        }
+    };
+    let mut hostmap = Vec::new();
+    for line in contents.lines() {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        if line.is_empty() {
+            continue;
+        }
+        let mut fields = line.split_whitespace();
+        let ip = fields.next().unwrap_or_default().parse::<IpAddr>();
+        let names: Vec<String> = fields.map(str::to_owned).collect();
+        let reason = match ip {
+            Ok(ip) if !names.is_empty() => {
+                hostmap.push((ip, names));
+                continue;
+            }
+            Ok(_) => "expected at least one name after the address",
+            Err(_) => "invalid IP address",
+        };
+        errors.push(ConfigError::InvalidEntry {
+            var: "PRELOAD_LATENCY_HOSTMAP",
+            entry: line.to_owned(),
+            reason: reason.to_owned(),
+        });
+    }
+    hostmap
+}
+
+/// Load `PRELOAD_LATENCY_ASN_DB` from `path`. Blank lines, `#` comments and a header line are
+/// skipped, and malformed lines are skipped with an entry pushed onto `errors`.
+fn load_asn_db(path: &Path, errors: &mut Vec<ConfigError>) -> Option<AsnDb> {
+    let contents = match std::fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) => {
+            errors.push(ConfigError::Unparseable {
+                var: "PRELOAD_LATENCY_ASN_DB",
+                reason: format!("failed to read {}: {e}", path.display()),
+            });
+            return None;
+        }
+    };
+    let mut asn_db = AsnDb::default();
+    for (i, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut fields = line.split(',').map(str::trim);
+        let network = fields.next().unwrap_or_default().parse::<Cidr>();
+        let asn = fields
+            .next()
+            .map(|asn| asn.strip_prefix("AS").unwrap_or(asn).parse::<u32>());
+        let reason = match (network, asn) {
+            (Ok(network), Some(Ok(asn))) => {
+                asn_db.insert(network, asn);
+                continue;
+            }
+            // Column names, like `network,asn`.
+            (Err(_), _) if i == 0 => continue,
+            (Err(reason), _) => reason,
+            (Ok(_), _) => "expected `network,asn`".to_owned(),
+        };
+        errors.push(ConfigError::InvalidEntry {
+            var: "PRELOAD_LATENCY_ASN_DB",
+            entry: line.to_owned(),
+            reason,
+        });
+    }
+    Some(asn_db)
+}
+
+/// Parse `PRELOAD_LATENCY_ASNS`. ASNs may be prefixed with `AS`.
+fn parse_asns(asns: &str, errors: &mut Vec<ConfigError>) -> BTreeSet<u32> {
+    asns.split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .filter_map(
+            |entry| match entry.strip_prefix("AS").unwrap_or(entry).parse() {
+                Ok(asn) => Some(asn),
+                Err(_) => {
+                    errors.push(ConfigError::InvalidEntry {
+                        var: "PRELOAD_LATENCY_ASNS",
+                        entry: entry.to_owned(),
+                        reason: "expected an AS number".to_owned(),
+                    });
+                    None
+                }
+            },
+        )
+        .collect()
+}
+
+fn parse_fds(fds: &str, errors: &mut Vec<ConfigError>) -> Vec<c_int> {
+    fds.split(',')
+        .filter(|entry| !entry.is_empty())
+        .filter_map(|entry| match entry.parse() {
+            // stdin, stdout and stderr are never intercepted.
+            Ok(fd) if fd > 2 => Some(fd),
+            _ => {
+                errors.push(ConfigError::InvalidEntry {
+                    var: "PRELOAD_LATENCY_FORCE_FDS",
+                    entry: entry.to_owned(),
+                    reason: "expected an fd greater than 2".to_owned(),
+                });
+                None
+            }
+        })
+        .collect()
+}
+
+/// Split a colon-separated list of hosts, keeping `vsock:cid[:port]` and `unix:path` entries
+/// together.
+fn split_hosts(hosts: &str) -> Vec<HostRule> {
+    let mut split = Vec::new();
+    let mut parts = hosts.split(':').filter(|part| !part.is_empty()).peekable();
+    while let Some(part) = parts.next() {
+        let mut entry = part.to_owned();
+        match part {
+            "vsock" => {
+                for _ in 0..2 {
+                    match parts.next_if(|next| next.parse::<u32>().is_ok()) {
+                        Some(number) => entry = format!("{entry}:{number}"),
+                        None => break,
+                    }
+                }
+            }
+            "unix" => {
+                if let Some(path) = parts.next_if(|next| next.starts_with(['/', '@'])) {
+                    entry = format!("{entry}:{path}");
+                }
+            }
+            _ => {}
+        }
+        let rule = HostRule::parse(&entry);
+        if !split.contains(&rule) {
+            split.push(rule);
+        }
+    }
+    split
+}
+
+/// Whether any cgroup path in the contents of `/proc/<pid>/cgroup` contains `pattern`. Each line
+/// looks like `hierarchy-ID:controllers:path`.
+fn cgroups_contain(cgroups: &str, pattern: &str) -> bool {
+    cgroups
+        .lines()
+        .filter_map(|line| line.splitn(3, ':').nth(2))
+        .any(|path| path.contains(pattern))
+}
+
+/// Whether an environment variable's `value` means it's enabled.
+fn is_truthy(value: &str) -> bool {
+    let value = value.trim();
+    !(value.is_empty()
+        || value == "0"
+        || ["false", "no", "off"]
+            .iter()
+            .any(|falsy| value.eq_ignore_ascii_case(falsy)))
+}
+
+/// Parse `PRELOAD_LATENCY_CDF`. Malformed points are skipped with an entry pushed onto `errors`.
+fn parse_cdf(cdf: &str, errors: &mut Vec<ConfigError>) -> Option<Cdf> {
+    let points: Vec<(f64, f64)> = cdf
+        .split(',')
+        .filter(|entry| !entry.is_empty())
+        .filter_map(|entry| {
+            let point = entry
+                .split_once('=')
+                .and_then(|(p, millis)| Some((p.parse().ok()?, millis.parse().ok()?)))
+                .filter(|(p, millis): &(f64, f64)| {
+                    (0.0..=1.0).contains(p) && millis.is_finite() && *millis >= 0.0
+                });
+            if point.is_none() {
+                errors.push(ConfigError::InvalidEntry {
+                    var: "PRELOAD_LATENCY_CDF",
+                    entry: entry.to_owned(),
+                    reason: "expected `probability=millis` with a probability between 0 and 1"
+                        .to_owned(),
+                });
+            }
+            point
+        })
+        .collect();
+    if points.is_empty() {
+        return None;
+    }
+    Cdf::new(points)
+        .map_err(|reason| {
+            errors.push(ConfigError::Unparseable {
+                var: "PRELOAD_LATENCY_CDF",
+                reason,
+            })
+        })
+        .ok()
+}
+
+fn parse_sni_millis(sni_millis: &str, errors: &mut Vec<ConfigError>) -> BTreeMap<String, c_uint> {
+    sni_millis
+        .split(',')
+        .filter(|entry| !entry.is_empty())
+        .filter_map(|entry| {
+            let invalid = |reason: &str| ConfigError::InvalidEntry {
+                var: "PRELOAD_LATENCY_SNI_MILLIS",
+                entry: entry.to_owned(),
+                reason: reason.to_owned(),
+            };
+            let Some((name, millis)) = entry.split_once('=') else {
+                errors.push(invalid("expected `name=millis`"));
+                return None;
+            };
+            if name.is_empty() {
+                errors.push(invalid("empty server name"));
+                return None;
+            }
+            let Ok(millis) = millis.parse() else {
+                errors.push(invalid("invalid milliseconds"));
+                return None;
+            };
+            Some((name.to_ascii_lowercase(), millis))
+        })
+        .collect()
+}
+
+fn parse_cidrs(cidrs: &str, errors: &mut Vec<ConfigError>) -> Vec<HostRule> {
+    cidrs
+        .split(',')
+        .filter(|entry| !entry.is_empty())
+        .filter_map(|entry| match HostRule::parse_cidr(entry) {
+            Ok(rule) => Some(rule),
+            Err(reason) => {
+                errors.push(ConfigError::InvalidEntry {
+                    var: "PRELOAD_LATENCY_CIDRS",
+                    entry: entry.to_owned(),
+                    reason,
+                });
+                None
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Mutex, PoisonError};
+
+    use super::*;
+
+    // The configuration is read from the process's environment, which all tests share.
+    static ENV: Mutex<()> = Mutex::new(());
+
+    /// Load the configuration with only `vars` set out of the `PRELOAD_LATENCY_*` variables,
+    /// along with its problems.
+    fn load_with(vars: &[(&str, &str)]) -> (HookConfig, Vec<ConfigError>) {
+        with_env(vars, || match HookConfig::try_load() {
+            Ok(config) => (config, Vec::new()),
+            Err(InvalidConfig { config, errors }) => (*config, errors),
+        })
+    }
+
+    /// Run `f` with only `vars` set out of the `PRELOAD_LATENCY_*` variables.
+    fn with_env<T>(vars: &[(&str, &str)], f: impl FnOnce() -> T) -> T {
+        let _env = ENV.lock().unwrap_or_else(PoisonError::into_inner);
+        let previous: Vec<_> = std::env::vars()
+            .filter(|(name, _)| name.starts_with("PRELOAD_LATENCY_"))
+            .collect();
+        // Nothing else in the tests touches the environment while `ENV` is held.
+        unsafe {
+            for (name, _) in &previous {
+                std::env::remove_var(name);
+            }
+            for (name, value) in vars {
+                std::env::set_var(name, value);
+            }
+        }
+        let result = f();
+        unsafe {
+            for (name, _) in vars {
+                std::env::remove_var(name);
+            }
+            for (name, value) in previous {
+                std::env::set_var(name, value);
+            }
+        }
+        result
+    }
+
+    #[test]
+    fn empty_environment_is_valid() {
+        let (config, errors) = load_with(&[]);
+        assert_eq!(errors, []);
+        assert!(config.all_hosts);
+        assert!(config.cidrs.is_empty());
+        assert!(!config.passthrough);
+    }
+
+    #[test]
+    fn valid_variables_are_used() {
+        let (config, errors) = load_with(&[
+            ("PRELOAD_LATENCY_MILLIS", "250"),
+            ("PRELOAD_LATENCY_HOSTS", "example.com:10.0.0.0/8"),
+            ("PRELOAD_LATENCY_CIDRS", "fd00::/8=20"),
+        ]);
+        assert_eq!(errors, []);
+        assert_eq!(config.sleep_duration_millis, 250);
+        assert_eq!(
+            config.hosts,
+            [
+                HostRule::Name("example.com".to_owned()),
+                HostRule::parse("10.0.0.0/8")
+            ]
+        );
+        assert_eq!(config.cidrs, [HostRule::parse_cidr("fd00::/8=20").unwrap()]);
+        assert!(!config.all_hosts);
+    }
+
+    #[test]
+    fn every_problem_is_reported() {
+        let (_, errors) = load_with(&[
+            ("PRELOAD_LATENCY_MILLIS", "slow"),
+            ("PRELOAD_LATENCY_TOGGLE_PERIOD", "0"),
+            ("PRELOAD_LATENCY_NONBLOCK_FACTOR", "-1"),
+            ("PRELOAD_LATENCY_BURST_INTERVAL_SECS", "0"),
+            (
+                "PRELOAD_LATENCY_CIDRS",
+                "10.0.0.0/33,10.1.0.0/16,10.2.0.0/16=x",
+            ),
+            ("PRELOAD_LATENCY_RECENT_EVENTS", "0"),
+        ]);
+        assert_eq!(
+            errors,
+            [
+                ConfigError::Invalid {
+                    var: "PRELOAD_LATENCY_MILLIS",
+                    value: "slow".to_owned(),
+                },
+                ConfigError::OutOfRange {
+                    var: "PRELOAD_LATENCY_TOGGLE_PERIOD",
+                    value: "0".to_owned(),
+                    expected: "a positive number",
+                },
+                ConfigError::OutOfRange {
+                    var: "PRELOAD_LATENCY_NONBLOCK_FACTOR",
+                    value: "-1".to_owned(),
+                    expected: "a non-negative number",
+                },
+                ConfigError::OutOfRange {
+                    var: "PRELOAD_LATENCY_BURST_INTERVAL_SECS",
+                    value: "0".to_owned(),
+                    expected: "a positive number",
+                },
+                ConfigError::InvalidEntry {
+                    var: "PRELOAD_LATENCY_CIDRS",
+                    entry: "10.0.0.0/33".to_owned(),
+                    reason: "invalid prefix length in CIDR `10.0.0.0/33`".to_owned(),
+                },
+                ConfigError::InvalidEntry {
+                    var: "PRELOAD_LATENCY_CIDRS",
+                    entry: "10.2.0.0/16=x".to_owned(),
+                    reason: "invalid milliseconds".to_owned(),
+                },
+                ConfigError::OutOfRange {
+                    var: "PRELOAD_LATENCY_RECENT_EVENTS",
+                    value: "0".to_owned(),
+                    expected: "a positive number",
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn invalid_values_fall_back_to_defaults() {
+        let (default, _) = load_with(&[]);
+        let (config, errors) = load_with(&[
+            ("PRELOAD_LATENCY_MILLIS", "slow"),
+            ("PRELOAD_LATENCY_CIDRS", "10.0.0.0/33,10.1.0.0/16"),
+        ]);
+        assert_eq!(errors.len(), 2);
+        assert_eq!(config.sleep_duration_millis, default.sleep_duration_millis);
+        assert_eq!(config.cidrs, [HostRule::parse_cidr("10.1.0.0/16").unwrap()]);
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn json_settings_fill_in_unset_variables() {
+        let (config, errors) = load_with(&[
+            ("PRELOAD_LATENCY_MILLIS", "250"),
+            (
+                "PRELOAD_LATENCY_JSON",
+                r#"{"millis": 10, "cidrs": {"10.0.0.0/8": 5}, "typo": 1}"#,
+            ),
+        ]);
+        assert_eq!(config.sleep_duration_millis, 250);
+        assert_eq!(
+            config.cidrs,
+            [HostRule::parse_cidr("10.0.0.0/8=5").unwrap()]
+        );
+        assert_eq!(
+            errors,
+            [ConfigError::InvalidEntry {
+                var: "PRELOAD_LATENCY_JSON",
+                entry: "typo".to_owned(),
+                reason: "unknown setting".to_owned(),
+            }]
+        );
+    }
+
+    #[test]
+    fn timing_orders_the_sleep_around_the_call() {
+        for (timing, order) in [
+            (Timing::Pre, ["sleep", "call"]),
+            (Timing::Post, ["call", "sleep"]),
+        ] {
+            let events = std::cell::RefCell::new(Vec::new());
+            let result = timing.around(
+                || events.borrow_mut().push("sleep"),
+                || {
+                    events.borrow_mut().push("call");
+                    7
+                },
+            );
+            assert_eq!(result, 7);
+            assert_eq!(events.into_inner(), order);
+        }
+    }
+
+    #[test]
+    fn scopes_contain_their_addresses() {
+        let private = [
+            "10.0.0.1",
+            "192.168.1.1",
+            "127.0.0.1",
+            "fd00::1",
+            "fe80::1",
+            "::1",
+        ];
+        let public = ["8.8.8.8", "2001:4860:4860::8888", "::ffff:8.8.8.8"];
+        for ip in private {
+            let ip = ip.parse().unwrap();
+            assert!(Scope::Private.contains(ip), "{ip}");
+            assert!(!Scope::Public.contains(ip), "{ip}");
+            assert!(Scope::Both.contains(ip), "{ip}");
+        }
+        for ip in public {
+            let ip = ip.parse().unwrap();
+            assert!(!Scope::Private.contains(ip), "{ip}");
+            assert!(Scope::Public.contains(ip), "{ip}");
+            assert!(Scope::Both.contains(ip), "{ip}");
+        }
+    }
+
+    #[test]
+    fn scope_is_read_from_the_environment() {
+        assert_eq!(load_with(&[]).0.scope, Scope::Both);
+        let (config, errors) = load_with(&[("PRELOAD_LATENCY_SCOPE", "public")]);
+        assert_eq!(errors, []);
+        assert_eq!(config.scope, Scope::Public);
+        let (config, errors) = load_with(&[("PRELOAD_LATENCY_SCOPE", "bogus")]);
+        assert_eq!(
+            errors,
+            [ConfigError::Invalid {
+                var: "PRELOAD_LATENCY_SCOPE",
+                value: "bogus".to_owned(),
+            }]
+        );
+        assert_eq!(config.scope, Scope::Both);
+    }
+
+    #[test]
+    fn invalid_configurations_use_defaults_by_default() {
+        let config = with_env(&[("PRELOAD_LATENCY_MILLIS", "slow")], HookConfig::load);
+        assert_eq!(config.on_error, OnError::Default);
+        assert!(!config.passthrough);
+        assert_eq!(config.sleep_duration_millis, 200);
+    }
+
+    #[test]
+    fn invalid_configurations_can_pass_everything_through() {
+        let vars = [
+            ("PRELOAD_LATENCY_MILLIS", "slow"),
+            ("PRELOAD_LATENCY_ON_ERROR", "passthrough"),
+        ];
+        assert!(with_env(&vars, HookConfig::load).passthrough);
+        // Only invalid configurations are passed through.
+        let vars = [("PRELOAD_LATENCY_ON_ERROR", "passthrough")];
+        assert!(!with_env(&vars, HookConfig::load).passthrough);
+    }
+
+    #[test]
+    fn invalid_on_error_is_reported() {
+        let (config, errors) = load_with(&[("PRELOAD_LATENCY_ON_ERROR", "ignore")]);
+        assert_eq!(
+            errors,
+            [ConfigError::Invalid {
+                var: "PRELOAD_LATENCY_ON_ERROR",
+                value: "ignore".to_owned(),
+            }]
+        );
+        assert_eq!(config.on_error, OnError::Default);
+    }
+
+    #[test]
+    fn profiles_set_latency_jitter_and_bandwidth() {
+        let (config, errors) = load_with(&[("PRELOAD_LATENCY_PROFILE", "satellite")]);
+        assert_eq!(errors, []);
+        assert_eq!(config.sleep_duration_millis, 300);
+        assert_eq!(config.jitter_millis, 50);
+        assert_eq!(config.bytes_per_sec, Some(2_500_000));
+        let (config, _) = load_with(&[("PRELOAD_LATENCY_PROFILE", "datacenter")]);
+        assert_eq!(config.sleep_duration_millis, 1);
+        assert_eq!(config.jitter_millis, 0);
+        assert_eq!(config.bytes_per_sec, Some(125_000_000));
+    }
+
+    #[test]
+    fn variables_override_profiles() {
+        let (config, errors) = load_with(&[
+            ("PRELOAD_LATENCY_PROFILE", "3g"),
+            ("PRELOAD_LATENCY_MILLIS", "10"),
+            ("PRELOAD_LATENCY_BYTES_PER_SEC", "1000"),
+        ]);
+        assert_eq!(errors, []);
+        assert_eq!(config.sleep_duration_millis, 10);
+        assert_eq!(config.jitter_millis, 40);
+        assert_eq!(config.bytes_per_sec, Some(1000));
+    }
+
+    #[test]
+    fn unknown_profiles_are_reported() {
+        let (default, _) = load_with(&[]);
+        let (config, errors) = load_with(&[("PRELOAD_LATENCY_PROFILE", "dialup")]);
+        assert_eq!(
+            errors,
+            [ConfigError::Invalid {
+                var: "PRELOAD_LATENCY_PROFILE",
+                value: "dialup".to_owned(),
+            }]
+        );
+        assert_eq!(config.sleep_duration_millis, default.sleep_duration_millis);
+        assert_eq!(config.bytes_per_sec, None);
+    }
+
+    #[test]
+    fn hosts_pick_what_is_intercepted() {
+        let (config, _) = load_with(&[]);
+        assert!(config.hosts.is_empty());
+        assert!(config.all_hosts);
+        let (config, _) = load_with(&[("PRELOAD_LATENCY_HOSTS", "")]);
+        assert!(config.hosts.is_empty());
+        assert!(config.all_hosts);
+        let (config, _) = load_with(&[("PRELOAD_LATENCY_HOSTS", "-")]);
+        assert!(config.hosts.is_empty());
+        assert!(!config.all_hosts);
+        let (config, _) = load_with(&[("PRELOAD_LATENCY_HOSTS", "example.com")]);
+        assert_eq!(config.hosts, [HostRule::Name("example.com".to_owned())]);
+        assert!(!config.all_hosts);
+    }
+
+    #[test]
+    fn handshake_only_mode_delays_only_handshakes() {
+        let (config, _) = load_with(&[]);
+        assert!(!config.delay_handshake);
+        assert!(config.delay_data);
+        let (config, errors) = load_with(&[("PRELOAD_LATENCY_MODE", "handshake-only")]);
+        assert_eq!(errors, []);
+        assert!(config.delay_handshake);
+        assert!(!config.delay_data);
+        let (config, errors) = load_with(&[("PRELOAD_LATENCY_MODE", "connect")]);
+        assert_eq!(errors.len(), 1);
+        assert!(!config.delay_handshake);
+        assert!(config.delay_data);
     }
 }
@@ -0,0 +1,145 @@
+use std::path::{Path, PathBuf};
+use std::sync::{OnceLock, RwLock};
+use std::time::{Duration, Instant};
+
+use libc::c_uint;
+
+/// How long the file's latency is remembered, so most sleeps don't touch the filesystem.
+const DYNAMIC_FILE_CACHE: Duration = Duration::from_millis(100);
+
+/// A file holding the current latency in milliseconds, with when it was last read, the last
+/// latency that parsed and whether the contents parsed then.
+struct DynamicFile {
+    path: PathBuf,
+    state: RwLock<(Instant, c_uint, bool)>,
+}
+
+impl DynamicFile {
+    fn new(path: PathBuf, fallback_millis: c_uint, now: Instant) -> Self {
+        let (millis, valid) = match read(&path) {
+            Some(millis) => (millis, true),
+            None => {
+                tracing::warn!(
+                    "{} doesn't hold a latency in milliseconds, using {fallback_millis}",
+                    path.display()
+                );
+                (fallback_millis, false)
+            }
+        };
+        Self {
+            path,
+            state: RwLock::new((now, millis, valid)),
+        }
+    }
+
+    /// The latency in milliseconds as of `now`, re-reading the file once the cached latency is
+    /// too old.
+    fn millis(&self, now: Instant) -> Option<c_uint> {
+        let Ok(&(read_at, previous, was_valid)) = self.state.read().as_deref() else {
+            tracing::warn!("Failed to access dynamic latency state");
+            return None;
+        };
+        if now.duration_since(read_at) < DYNAMIC_FILE_CACHE {
+            return Some(previous);
+        }
+
+        let current = read(&self.path);
+        match current {
+            Some(millis) if millis != previous || !was_valid => {
+                tracing::info!("Latency in {} changed to {millis}", self.path.display());
+            }
+            None if was_valid => tracing::warn!(
+                "{} doesn't hold a latency in milliseconds anymore, keeping {previous}",
+                self.path.display()
+            ),
+            _ => {}
+        }
+        let Ok(mut state) = self.state.write() else {
+            tracing::warn!("Failed to access dynamic latency state");
+            return Some(previous);
+        };
+        let millis = current.unwrap_or(previous);
+        *state = (now, millis, current.is_some());
+        Some(millis)
+    }
+}
+
+static DYNAMIC_FILE: OnceLock<DynamicFile> = OnceLock::new();
+
+/// Read the latency from `path` from now on, starting with `fallback_millis` until the file holds
+/// a valid latency.
+pub fn init(path: PathBuf, fallback_millis: c_uint) {
+    tracing::info!("Reading the latency from {}", path.display());
+    DYNAMIC_FILE.get_or_init(|| DynamicFile::new(path, fallback_millis, Instant::now()));
+}
+
+/// The latency in milliseconds the file held when it was last read, unless [`init`] wasn't called.
+/// Keeps the last valid latency while the file is missing or malformed.
+pub fn millis() -> Option<c_uint> {
+    DYNAMIC_FILE.get()?.millis(Instant::now())
+}
+
+fn read(path: &Path) -> Option<c_uint> {
+    std::fs::read_to_string(path).ok()?.trim().parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("preload_latency-{}-{name}", std::process::id()))
+    }
+
+    #[test]
+    fn latency_is_reread_once_the_cache_expires() {
+        let path = temp_path("dynamic-cache");
+        std::fs::write(&path, "7\n").unwrap();
+        let start = Instant::now();
+        let dynamic_file = DynamicFile::new(path.clone(), 200, start);
+        assert_eq!(dynamic_file.millis(start), Some(7));
+
+        std::fs::write(&path, "9\n").unwrap();
+        let almost = start + DYNAMIC_FILE_CACHE - Duration::from_millis(1);
+        assert_eq!(dynamic_file.millis(almost), Some(7));
+        let read_at = start + DYNAMIC_FILE_CACHE;
+        assert_eq!(dynamic_file.millis(read_at), Some(9));
+
+        std::fs::write(&path, "11\n").unwrap();
+        assert_eq!(
+            dynamic_file.millis(read_at + Duration::from_millis(1)),
+            Some(9)
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn last_valid_latency_is_kept() {
+        let path = temp_path("dynamic-invalid");
+        std::fs::write(&path, "7").unwrap();
+        let start = Instant::now();
+        let dynamic_file = DynamicFile::new(path.clone(), 200, start);
+        let later = |reads| start + DYNAMIC_FILE_CACHE * reads;
+
+        std::fs::write(&path, "slow").unwrap();
+        assert_eq!(dynamic_file.millis(later(1)), Some(7));
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(dynamic_file.millis(later(2)), Some(7));
+        std::fs::write(&path, "9").unwrap();
+        assert_eq!(dynamic_file.millis(later(3)), Some(9));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn fallback_is_used_until_the_file_is_valid() {
+        let path = temp_path("dynamic-fallback");
+        let _ = std::fs::remove_file(&path);
+        let start = Instant::now();
+        let dynamic_file = DynamicFile::new(path.clone(), 200, start);
+        assert_eq!(dynamic_file.millis(start), Some(200));
+
+        std::fs::write(&path, "7").unwrap();
+        assert_eq!(dynamic_file.millis(start + DYNAMIC_FILE_CACHE), Some(7));
+        std::fs::remove_file(&path).unwrap();
+    }
+}
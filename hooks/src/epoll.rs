@@ -0,0 +1,164 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::RwLock;
+
+use libc::c_int;
+
+/// The fds registered with each epoll instance, keyed by the epoll instance's fd. Lets the hooks
+/// tell which fds an instance watches without asking the kernel.
+#[derive(Default)]
+struct Members(BTreeMap<c_int, BTreeSet<c_int>>);
+
+static MEMBERS: RwLock<Members> = RwLock::new(Members(BTreeMap::new()));
+
+impl Members {
+    fn control(&mut self, epfd: c_int, op: c_int, fd: c_int) {
+        match op {
+            // Modifying an fd that isn't registered fails, so `EPOLL_CTL_MOD` is only recorded in
+            // case the fd was registered before the hooks were loaded.
+            libc::EPOLL_CTL_ADD | libc::EPOLL_CTL_MOD => {
+                self.0.entry(epfd).or_default().insert(fd);
+            }
+            libc::EPOLL_CTL_DEL => {
+                if let Some(fds) = self.0.get_mut(&epfd) {
+                    fds.remove(&fd);
+                    if fds.is_empty() {
+                        self.0.remove(&epfd);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn watched_by(&self, epfd: c_int) -> Vec<c_int> {
+        self.0
+            .get(&epfd)
+            .map(|fds| fds.iter().copied().collect())
+            .unwrap_or_default()
+    }
+
+    fn any_closed(&self, closed: &impl Fn(c_int) -> bool) -> bool {
+        self.0
+            .iter()
+            .any(|(epfd, fds)| closed(*epfd) || fds.iter().any(|fd| closed(*fd)))
+    }
+
+    fn forget_closed(&mut self, closed: &impl Fn(c_int) -> bool) {
+        self.0.retain(|epfd, fds| {
+            fds.retain(|fd| !closed(*fd));
+            !closed(*epfd) && !fds.is_empty()
+        });
+    }
+}
+
+/// Record a successful `epoll_ctl(epfd, op, fd)`.
+pub fn control(epfd: c_int, op: c_int, fd: c_int) {
+    let Ok(mut members) = MEMBERS.write() else {
+        tracing::warn!("Failed to access epoll state");
+        return;
+    };
+    members.control(epfd, op, fd);
+}
+
+/// Whether the epoll instance `epfd` watches any fd that `is_tracked`.
+pub fn watches_tracked(epfd: c_int, is_tracked: impl Fn(c_int) -> bool) -> bool {
+    // Copied out so `is_tracked` can take other locks.
+    let watched = match MEMBERS.read() {
+        Ok(members) => members.watched_by(epfd),
+        Err(_) => return false,
+    };
+    watched.into_iter().any(is_tracked)
+}
+
+/// Forget the fds that were `closed`, both as epoll instances and as their members. The kernel
+/// drops closed fds from every instance they were registered with.
+pub fn forget_closed(closed: impl Fn(c_int) -> bool) {
+    // Most closed fds have nothing to do with epoll, so only those that do take the write lock.
+    if !MEMBERS
+        .read()
+        .is_ok_and(|members| members.any_closed(&closed))
+    {
+        return;
+    }
+    let Ok(mut members) = MEMBERS.write() else {
+        return;
+    };
+    members.forget_closed(&closed);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn members(entries: &[(c_int, c_int, c_int)]) -> Members {
+        let mut members = Members::default();
+        for &(epfd, op, fd) in entries {
+            members.control(epfd, op, fd);
+        }
+        members
+    }
+
+    #[test]
+    fn added_fds_are_watched() {
+        let members = members(&[
+            (10, libc::EPOLL_CTL_ADD, 3),
+            (10, libc::EPOLL_CTL_ADD, 4),
+            (11, libc::EPOLL_CTL_ADD, 5),
+        ]);
+        assert_eq!(members.watched_by(10), [3, 4]);
+        assert_eq!(members.watched_by(11), [5]);
+        assert!(members.watched_by(12).is_empty());
+    }
+
+    #[test]
+    fn modified_fds_are_watched() {
+        let members = members(&[(10, libc::EPOLL_CTL_MOD, 3), (10, libc::EPOLL_CTL_MOD, 3)]);
+        assert_eq!(members.watched_by(10), [3]);
+    }
+
+    #[test]
+    fn deleted_fds_arent_watched() {
+        let members = members(&[
+            (10, libc::EPOLL_CTL_ADD, 3),
+            (10, libc::EPOLL_CTL_ADD, 4),
+            (10, libc::EPOLL_CTL_DEL, 3),
+            (11, libc::EPOLL_CTL_ADD, 5),
+            (11, libc::EPOLL_CTL_DEL, 5),
+            (12, libc::EPOLL_CTL_DEL, 6),
+        ]);
+        assert_eq!(members.watched_by(10), [4]);
+        assert!(!members.0.contains_key(&11));
+        assert!(!members.0.contains_key(&12));
+    }
+
+    #[test]
+    fn closed_fds_are_forgotten() {
+        let mut members = members(&[
+            (10, libc::EPOLL_CTL_ADD, 3),
+            (10, libc::EPOLL_CTL_ADD, 4),
+            (11, libc::EPOLL_CTL_ADD, 3),
+            (12, libc::EPOLL_CTL_ADD, 5),
+        ]);
+        assert!(!members.any_closed(&|fd| fd == 6));
+        assert!(members.any_closed(&|fd| fd == 3));
+        members.forget_closed(&|fd| fd == 3);
+        assert_eq!(members.watched_by(10), [4]);
+        assert!(!members.0.contains_key(&11));
+        assert!(members.any_closed(&|fd| fd == 12));
+        members.forget_closed(&|fd| fd == 12);
+        assert!(!members.0.contains_key(&12));
+    }
+
+    #[test]
+    fn watches_tracked_checks_every_member() {
+        // Negative fds can't be real epoll instances, so nothing else touches these.
+        control(-10, libc::EPOLL_CTL_ADD, 3);
+        control(-10, libc::EPOLL_CTL_ADD, 4);
+        control(-11, libc::EPOLL_CTL_ADD, 5);
+        assert!(watches_tracked(-10, |fd| fd == 4));
+        assert!(!watches_tracked(-11, |fd| fd == 4));
+        assert!(!watches_tracked(-12, |_| true));
+        forget_closed(|fd| fd == -10 || fd == -11);
+        assert!(!watches_tracked(-10, |_| true));
+    }
+}
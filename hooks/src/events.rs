@@ -0,0 +1,117 @@
+use std::collections::VecDeque;
+use std::net::IpAddr;
+use std::sync::{Mutex, OnceLock};
+use std::time::SystemTime;
+
+use libc::{c_int, ssize_t};
+
+/// An intercepted call on a tracked socket.
+#[derive(Clone, Copy, Debug)]
+pub struct Event {
+    pub at: SystemTime,
+    pub fd: c_int,
+    /// Tracked address the call went to or came from, if known.
+    pub peer: Option<IpAddr>,
+    /// Whether the call was send-like rather than receive-like.
+    pub send: bool,
+    /// What the real call returned.
+    pub result: ssize_t,
+    pub slept_micros: u64,
+}
+
+/// The most recent events, oldest first, with room for at most this many.
+struct Recent {
+    events: VecDeque<Event>,
+    capacity: usize,
+}
+
+impl Recent {
+    fn new(capacity: usize) -> Self {
+        Self {
+            events: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    fn push(&mut self, event: Event) {
+        if self.events.len() == self.capacity {
+            self.events.pop_front();
+        }
+        self.events.push_back(event);
+    }
+}
+
+// Only set if recent events are kept. The lock is only held to push or copy events.
+static RECENT: OnceLock<Mutex<Recent>> = OnceLock::new();
+
+/// Keep the last `capacity` events from now on.
+pub fn init(capacity: usize) {
+    tracing::info!("Keeping the last {capacity} intercepted calls");
+    RECENT.get_or_init(|| Mutex::new(Recent::new(capacity)));
+}
+
+/// Remember `event`, dropping the oldest one if there's no room left. Does nothing unless
+/// [`init`] was called.
+pub fn record(event: Event) {
+    let Some(recent) = RECENT.get() else {
+        return;
+    };
+    let Ok(mut recent) = recent.lock() else {
+        return;
+    };
+    recent.push(event);
+}
+
+/// The events that were kept, oldest first.
+pub fn recent() -> Vec<Event> {
+    RECENT
+        .get()
+        .and_then(|recent| recent.lock().ok())
+        .map(|recent| recent.events.iter().copied().collect())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(fd: c_int) -> Event {
+        Event {
+            at: SystemTime::UNIX_EPOCH,
+            fd,
+            peer: None,
+            send: true,
+            result: 0,
+            slept_micros: 0,
+        }
+    }
+
+    fn fds(events: impl IntoIterator<Item = Event>) -> Vec<c_int> {
+        events.into_iter().map(|event| event.fd).collect()
+    }
+
+    #[test]
+    fn oldest_events_are_dropped_first() {
+        let mut recent = Recent::new(3);
+        for fd in 0..3 {
+            recent.push(event(fd));
+        }
+        assert_eq!(fds(recent.events.iter().copied()), [0, 1, 2]);
+        recent.push(event(3));
+        recent.push(event(4));
+        assert_eq!(fds(recent.events.iter().copied()), [2, 3, 4]);
+    }
+
+    // The only test touching `RECENT`, since it can only be initialized once.
+    #[test]
+    fn events_are_only_kept_after_init() {
+        record(event(0));
+        assert!(recent().is_empty());
+        init(2);
+        assert!(recent().is_empty());
+        for fd in 1..4 {
+            record(event(fd));
+        }
+        assert_eq!(fds(recent()), [2, 3]);
+    }
+}
@@ -1,20 +1,235 @@
-use std::collections::BTreeSet;
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::Write;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::{OnceLock, RwLock};
-use std::time::Duration;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-use libc::{addrinfo, c_char, c_int, c_void, hostent, iovec, size_t, sockaddr, socklen_t, ssize_t};
+use libc::{
+    addrinfo, c_char, c_int, c_uint, c_ulong, c_void, hostent, iovec, msghdr, off_t, size_t,
+    sockaddr, socklen_t, ssize_t,
+};
+use tracing_subscriber::filter::{LevelFilter, Targets};
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
 
-use crate::config::HookConfig;
+use crate::config::{HookConfig, Scope, Timing};
+use crate::dynamic;
+#[cfg(target_os = "linux")]
+use crate::epoll;
+#[cfg(feature = "json")]
+use crate::events;
+use crate::host_rule::Destination;
+use crate::hosts_file;
+use crate::matcher;
+use crate::payload::Payload;
+use crate::rate::{SlidingWindow, TokenBucket};
+#[cfg(feature = "json")]
+use crate::snapshot;
+use crate::stats;
+use crate::syslog;
+use crate::tls;
 use crate::toggle;
 use crate::util;
 
 static CONFIG: OnceLock<HookConfig> = OnceLock::new();
 
-// List of addresses resolved for the hosts in `HOSTS`.
-static HOST_ADDRS: RwLock<BTreeSet<String>> = RwLock::new(BTreeSet::new());
+/// Return from the enclosing hook with `errno` set to `ENOSYS` if the real `$name` can't be found,
+/// instead of crashing when calling it. Returns `-1` unless another `$failure` is given. Whether
+/// it can be found is only looked up once.
+macro_rules! require_real {
+    ($name:ident) => {
+        require_real!($name, -1)
+    };
+    ($name:ident, $failure:expr) => {
+        static STATE: ::std::sync::atomic::AtomicU8 = ::std::sync::atomic::AtomicU8::new(0);
+        let symbol =
+            ::std::ffi::CStr::from_bytes_with_nul(concat!(stringify!($name), "\0").as_bytes());
+        if !symbol.is_ok_and(|symbol| $crate::util::resolves_next(symbol, &STATE)) {
+            $crate::util::set_errno(libc::ENOSYS);
+            return $failure;
+        }
+    };
+}
+
+/// How a socket came to be tracked.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Origin {
+    /// `connect` to a tracked address.
+    Connect,
+    /// `bind` to a tracked address.
+    Bind,
+    /// `accept` of a connection from a peer in a tracked network.
+    Accept,
+    /// One end of a `socketpair`, used for local IPC.
+    Ipc,
+    /// Listed in `PRELOAD_LATENCY_FORCE_FDS`.
+    Forced,
+}
+
+// List of addresses resolved for the hosts in `HOSTS`, each with the host it was resolved for.
+static HOST_ADDRS: RwLock<BTreeMap<IpAddr, String>> = RwLock::new(BTreeMap::new());
+
+// Cached `should_intercept_ip` decisions, so connection-churny programs don't search `HOST_ADDRS`
+// on every `connect`. Must be cleared whenever `HOST_ADDRS` or the config changes.
+static IP_DECISIONS: RwLock<BTreeMap<IpAddr, bool>> = RwLock::new(BTreeMap::new());
+
+// Maximum number of entries in `IP_DECISIONS`. The cache is simply cleared once it's full.
+const IP_DECISIONS_CAPACITY: usize = 1024;
+
+/// What a socket is, for `PRELOAD_LATENCY_BLANKET`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Endpoint {
+    /// Whether it's an IPv4 or IPv6 socket.
+    ip: bool,
+    /// IP address it's connected to, whether or not it's tracked.
+    peer: Option<IpAddr>,
+}
+
+/// Everything known about a single fd. Fds in the default state aren't stored at all.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+struct SocketState {
+    /// How the socket came to be tracked, if it's connected or bound to a tracked address.
+    origin: Option<Origin>,
+    /// When the socket started being tracked, for `PRELOAD_LATENCY_DECAY_SECS`.
+    tracked_at: Option<Instant>,
+    /// Tracked IP address the socket is connected to.
+    peer: Option<IpAddr>,
+    /// Local IP address the socket was explicitly bound to with `bind`.
+    local: Option<IpAddr>,
+    /// Whether `O_NONBLOCK` is set, via `socket()`, `fcntl()` or `ioctl()`.
+    nonblocking: bool,
+    /// ID of the thread that created the socket. Only recorded for `PRELOAD_LATENCY_ONLY_TID`.
+    creator: Option<u64>,
+    /// Whether data was sent since the last receive, for `PRELOAD_LATENCY_RESPONSE_MILLIS`.
+    awaiting_response: bool,
+    /// Bytes sent with `MSG_MORE` since the last send without it, for
+    /// `PRELOAD_LATENCY_COALESCE_MSG_MORE`.
+    pending_bytes: usize,
+    /// Whether the connection was left out by `PRELOAD_LATENCY_CONN_SAMPLE_RATE`, so it's never
+    /// delayed.
+    unsampled: bool,
+    /// Whether the connection's ClientHello advertised a protocol in `PRELOAD_LATENCY_ALPN`, once
+    /// it was sent.
+    alpn_matched: Option<bool>,
+    /// Intercepted operations on the socket so far, for `PRELOAD_LATENCY_ALTERNATE`.
+    ops: u64,
+    /// Whether `listen` succeeded on the socket, for `PRELOAD_LATENCY_LISTEN_CLOSE_MILLIS`.
+    listening: bool,
+    /// Latency in `PRELOAD_LATENCY_SNI_MILLIS` for the server name in the connection's
+    /// ClientHello, once it was sent. `Some(None)` if the name isn't listed.
+    sni_millis: Option<Option<c_uint>>,
+    /// When the latest sleep of an operation on the socket ends, for
+    /// `PRELOAD_LATENCY_COALESCE_CONCURRENT`.
+    delayed_until: Option<Instant>,
+    /// Bytes sent and received and microseconds slept in sends and receives, for
+    /// `PRELOAD_LATENCY_CONN_SUMMARY`.
+    sent: u64,
+    received: u64,
+    slept_micros: u64,
+    /// What the socket is, recorded by `socket()`, `connect()` and `accept()` or looked up the
+    /// first time it's needed, for `PRELOAD_LATENCY_BLANKET`.
+    endpoint: Option<Endpoint>,
+}
+
+impl SocketState {
+    /// Start tracking the socket.
+    fn track(&mut self, origin: Origin) {
+        if self.origin.is_none() {
+            OPEN_TRACKED.fetch_add(1, Ordering::Relaxed);
+        }
+        self.origin = Some(origin);
+        self.tracked_at = Some(Instant::now());
+    }
+
+    /// Stop tracking the socket, e.g. because a datagram socket was connected to another peer.
+    fn untrack(&mut self) {
+        if self.origin.is_some() {
+            OPEN_TRACKED.fetch_sub(1, Ordering::Relaxed);
+        }
+        self.origin = None;
+        self.tracked_at = None;
+        self.peer = None;
+    }
+
+    /// The part of a sleep of `duration` microseconds starting at `now` that doesn't overlap the
+    /// sleep in `delayed_until`. If there's none in progress, the new sleep is recorded instead.
+    fn coalesce(&mut self, duration: c_uint, now: Instant) -> c_uint {
+        match self.delayed_until {
+            Some(until) if until > now => {
+                let remaining = until.duration_since(now).as_micros();
+                remaining.try_into().unwrap_or(c_uint::MAX).min(duration)
+            }
+            _ => {
+                self.delayed_until = Some(now + Duration::from_micros(duration.into()));
+                duration
+            }
+        }
+    }
+}
+
+// State of every fd that isn't in the default state, removed once it's closed.
+static SOCKETS: RwLock<BTreeMap<c_int, SocketState>> = RwLock::new(BTreeMap::new());
+
+// Number of tracked sockets that are currently open, for `PRELOAD_LATENCY_CONN_FACTOR`.
+static OPEN_TRACKED: AtomicU64 = AtomicU64::new(0);
+
+// Recent operations per host, for `PRELOAD_LATENCY_QPS_LIMIT`.
+static HOST_RATES: RwLock<BTreeMap<String, SlidingWindow>> = RwLock::new(BTreeMap::new());
+
+// Recent intercepted operations across all sockets, for `PRELOAD_LATENCY_MAX_OPS_PER_SEC`.
+static OPS: OnceLock<RwLock<SlidingWindow>> = OnceLock::new();
+
+// Bandwidth shared by both directions of all tracked sockets, for `PRELOAD_LATENCY_SHARED_BPS`.
+static SHARED_PIPE: OnceLock<RwLock<TokenBucket>> = OnceLock::new();
+
+// Number of connection attempts refused so far per tracked IP, for
+// `PRELOAD_LATENCY_CONNECT_FAIL_COUNT`.
+static CONNECT_ATTEMPTS: RwLock<BTreeMap<IpAddr, c_uint>> = RwLock::new(BTreeMap::new());
+
+// Bytes transferred so far per tracked host, for `PRELOAD_LATENCY_HOST_QUOTA_BYTES`.
+static HOST_BYTES: RwLock<BTreeMap<String, u64>> = RwLock::new(BTreeMap::new());
+
+// Number of connections made so far per tracked host, for `PRELOAD_LATENCY_COLD_CONNECT_MILLIS`.
+static HOST_CONNECTIONS: RwLock<BTreeMap<String, u64>> = RwLock::new(BTreeMap::new());
+
+// Total bytes transferred over tracked sockets. Only counted if `PRELOAD_LATENCY_GLOBAL_BYTES` is
+// set.
+static GLOBAL_BYTES: AtomicU64 = AtomicU64::new(0);
 
-// List of sockets connected to the IP addresses in `HOST_ADDRS`.
-static HOST_SOCKETS: RwLock<BTreeSet<c_int>> = RwLock::new(BTreeSet::new());
+// Whether the process has called `getaddrinfo` yet, for `PRELOAD_LATENCY_FIRST_DNS_MILLIS`.
+static FIRST_DNS_DONE: AtomicBool = AtomicBool::new(false);
+
+// Whether `PRELOAD_LATENCY_BUDGET_MILLIS` ran out, so the warning is only logged once.
+static BUDGET_EXHAUSTED: AtomicBool = AtomicBool::new(false);
+
+// Hosts that were resolved without matching the hosts and were logged for
+// `PRELOAD_LATENCY_LOG_UNMATCHED`, so each one is only logged once.
+static UNMATCHED_HOSTS: RwLock<BTreeSet<String>> = RwLock::new(BTreeSet::new());
+
+// Maximum number of entries in `UNMATCHED_HOSTS`. Once it's full, unmatched hosts aren't logged
+// anymore, so programs resolving endless distinct names can't flood the log.
+const UNMATCHED_HOSTS_CAPACITY: usize = 256;
+
+// How many bytes at the start of a send are searched for `PRELOAD_LATENCY_PAYLOAD_CONTAINS`, so
+// large sends don't cost much more to check.
+const PAYLOAD_CONTAINS_SCAN_LEN: usize = 4096;
+
+// When the hooks were initialized, for `PRELOAD_LATENCY_WAVE_PERIOD_SECS`.
+static STARTED_AT: OnceLock<Instant> = OnceLock::new();
+
+// The `HookConfig::tod_profile` multiplier for the current hour as `f64` bits, and the Unix time in
+// seconds until which it's valid. Cached so `localtime_r` isn't called on every intercepted call.
+static TOD_MULTIPLIER: AtomicU64 = AtomicU64::new(0);
+static TOD_VALID_UNTIL: AtomicU64 = AtomicU64::new(0);
+
+// How long the load average is cached for, so it isn't read on every intercepted call.
+const LOAD_CACHE_NANOS: u64 = 1_000_000_000;
+
+// The `HookConfig::load_threshold` multiplier for the current load average as `f64` bits, and the
+// monotonic time in nanoseconds until which it's valid.
+static LOAD_MULTIPLIER: AtomicU64 = AtomicU64::new(0);
+static LOAD_VALID_UNTIL: AtomicU64 = AtomicU64::new(0);
 
 /// Runs [`_ld_preload_init`] when the library is loaded.
 #[unsafe(no_mangle)]
@@ -30,57 +245,1321 @@ pub static LD_PRELOAD_INIT: extern "C" fn() = _ld_preload_init;
 /// the main binary somehow bypasses `getaddrinfo` you may set the `PRELOAD_LATENCY_RESOLVE`
 /// environment variable to resolve each host using `getaddrinfo` proactively at startup.
 pub extern "C" fn _ld_preload_init() {
-    tracing_subscriber::fmt::init();
+    init_tracing();
     tracing::info!("Initializing hooks...");
+    STARTED_AT.get_or_init(Instant::now);
+    util::seed_rng(
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or_default()
+            ^ u64::from(std::process::id()),
+    );
     let config = CONFIG.get_or_init(HookConfig::load);
+    if let Some(seed) = config.seed {
+        util::seed_rng(seed);
+    }
+    if config.diagnose {
+        eprintln!("preload_latency hooks {}", env!("CARGO_PKG_VERSION"));
+        eprintln!("{config:#?}");
+        unsafe { libc::_exit(0) };
+    }
+    if config.passthrough {
+        tracing::info!("Initialization done, hooks will pass through.");
+        return;
+    }
+    if let Some(hosts_file) = &config.hosts_file {
+        hosts_file::init(hosts_file.clone());
+    }
+    if !config.hostmap.is_empty() {
+        track_hostmap(&config.hostmap);
+    }
+    if let Some(match_so) = &config.match_so {
+        matcher::init(match_so);
+    }
+    #[cfg(feature = "json")]
+    if let Some(recent_events) = config.recent_events {
+        events::init(recent_events);
+    }
+    #[cfg(feature = "json")]
+    if let Some(snapshot_file) = &config.snapshot_file {
+        snapshot::init(snapshot_file.clone(), snapshot);
+    }
+    if let Some(report_interval) = config.report_interval {
+        stats::start_reporting(Duration::from_secs(report_interval.into()));
+    }
+    for fd in config.force_fds.iter() {
+        tracing::info!("Tracking forced fd {fd}");
+        update_socket_state(*fd, |state| state.track(Origin::Forced));
+    }
     config.maybe_proactively_resolve_hosts();
+    if let Some(reresolve_secs) = config.reresolve_secs {
+        start_reresolving(Duration::from_secs(reresolve_secs.into()));
+    }
+    if let Some(shm) = &config.shm {
+        toggle::init_kill_switch(shm);
+    }
+    if let Some(flag_file) = &config.flag_file {
+        toggle::init_flag_file(flag_file.clone());
+    }
+    if let Some(dynamic_file) = &config.dynamic_file {
+        dynamic::init(dynamic_file.clone(), config.sleep_duration_millis);
+    }
+    if let Some(grace_period) = config.grace_period {
+        toggle::init_grace_period(Duration::from_secs(grace_period.into()));
+    }
+    if config.start_at.is_some() || config.stop_at.is_some() {
+        toggle::init_schedule(
+            config.start_at.map(|start_at| start_at.0),
+            config.stop_at.map(|stop_at| stop_at.0),
+        );
+    }
     if let Some(toggle_period) = config.toggle_period {
         let toggle_period = Duration::from_secs(toggle_period.into());
-        toggle::init(toggle_period);
+        toggle::init(toggle_period, config.duty_cycle);
+    }
+    if let Some(burst) = config.burst {
+        let burst = Duration::from_secs(burst.into());
+        let burst_interval = Duration::from_secs(config.burst_interval.into());
+        toggle::init_burst(burst, burst_interval);
     }
     tracing::info!("Initialization done.");
 }
 
+/// The tracked addresses and sockets, the configuration and the stats, for
+/// [`HookConfig::snapshot_file`].
+#[cfg(feature = "json")]
+fn snapshot() -> serde_json::Value {
+    use serde_json::json;
+
+    let addrs: serde_json::Map<_, _> = HOST_ADDRS
+        .read()
+        .map(|addrs| {
+            addrs
+                .iter()
+                .map(|(ip, host)| (ip.to_string(), json!(host)))
+                .collect()
+        })
+        .unwrap_or_default();
+    let sockets: Vec<_> = SOCKETS
+        .read()
+        .map(|sockets| {
+            sockets
+                .iter()
+                .filter_map(|(fd, state)| {
+                    let origin = state.origin?;
+                    Some(json!({
+                        "fd": fd,
+                        "origin": format!("{origin:?}"),
+                        "peer": state.peer.map(|peer| peer.to_string()),
+                        "nonblocking": state.nonblocking,
+                        "listening": state.listening,
+                        "tracked_secs": state.tracked_at.map(|at| at.elapsed().as_secs_f64()),
+                    }))
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+    let events: Vec<_> = events::recent()
+        .iter()
+        .map(|event| {
+            let at = event
+                .at
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap_or_default();
+            let host = event.peer.and_then(|peer| {
+                HOST_ADDRS
+                    .read()
+                    .ok()
+                    .and_then(|addrs| addrs.get(&peer).cloned())
+            });
+            json!({
+                "at_millis": at.as_millis() as u64,
+                "fd": event.fd,
+                "peer": event.peer.map(|peer| peer.to_string()),
+                "host": host,
+                "direction": if event.send { "send" } else { "recv" },
+                "result": event.result,
+                "slept_micros": event.slept_micros,
+            })
+        })
+        .collect();
+    let counters = stats::counters();
+    json!({
+        "version": env!("CARGO_PKG_VERSION"),
+        "pid": std::process::id(),
+        "tracked_addresses": addrs,
+        "sockets": sockets,
+        "recent_events": events,
+        "stats": {
+            "calls": counters.calls,
+            "delayed": counters.delayed,
+            "slept_micros": counters.slept_micros,
+        },
+        "config": format!("{:?}", CONFIG.wait()),
+    })
+}
+
+/// Installs the `tracing` subscriber. `PRELOAD_LATENCY_LOG_LEVEL` takes precedence over `RUST_LOG`
+/// if it's set to a valid level.
+fn init_tracing() {
+    let log_level = std::env::var("PRELOAD_LATENCY_LOG_LEVEL").ok();
+    let level = log_level.as_deref().map(str::parse::<LevelFilter>);
+    let filter = match level {
+        Some(Ok(level)) => Targets::new().with_default(level),
+        _ => rust_log_filter(),
+    };
+
+    let syslog = std::env::var("PRELOAD_LATENCY_SYSLOG").ok();
+    let syslog_mode = syslog.as_deref().map(str::parse::<syslog::Mode>);
+    let fmt = (syslog_mode != Some(Ok(syslog::Mode::Only))).then(tracing_subscriber::fmt::layer);
+    let syslog_layer = matches!(syslog_mode, Some(Ok(_))).then(syslog::SyslogLayer::new);
+
+    tracing_subscriber::registry()
+        .with(fmt)
+        .with(syslog_layer)
+        .with(filter)
+        .init();
+
+    if let Some(Err(_)) = level {
+        tracing::warn!(
+            "Ignoring invalid `PRELOAD_LATENCY_LOG_LEVEL`: {}",
+            log_level.unwrap_or_default()
+        );
+    }
+    if let Some(Err(_)) = syslog_mode {
+        tracing::warn!(
+            "Ignoring invalid `PRELOAD_LATENCY_SYSLOG`: {}",
+            syslog.unwrap_or_default()
+        );
+    }
+}
+
+/// The filter `tracing_subscriber::fmt::init` would use, from the `RUST_LOG` environment variable.
+fn rust_log_filter() -> Targets {
+    std::env::var("RUST_LOG")
+        .ok()
+        .and_then(|targets| targets.parse().ok())
+        .unwrap_or_else(|| Targets::new().with_default(LevelFilter::INFO))
+}
+
 fn should_intercept_host(host: &str) -> bool {
     let config = CONFIG.wait();
-    config.hosts.contains(host) || config.hosts.is_empty()
+    config.all_hosts || is_listed(Destination::Host(host)) || config.in_hostmap(host)
 }
 
-fn should_intercept_ip(ip: &String) -> bool {
-    HOST_ADDRS
+/// Whether `destination` is matched by a rule in the hosts. Unlike for IP hosts, nothing is
+/// matched if no hosts are configured.
+fn is_listed(destination: Destination) -> bool {
+    CONFIG
+        .wait()
+        .hosts
+        .iter()
+        .any(|rule| rule.matches(destination))
+        || hosts_file::matches(destination)
+}
+
+fn should_intercept_ip(ip: IpAddr) -> bool {
+    let config = CONFIG.wait();
+    if (config.exclude_loopback && util::classify_ip(ip).loopback) || !config.in_scope(ip) {
+        return false;
+    }
+
+    if let Ok(decisions) = IP_DECISIONS.read()
+        && let Some(decision) = decisions.get(&ip)
+    {
+        return *decision;
+    }
+
+    let decision = HOST_ADDRS
         .read()
-        .map(|addrs| addrs.contains(ip))
-        .unwrap_or_else(|_| CONFIG.wait().hosts.is_empty())
+        .map(|addrs| addrs.contains_key(&ip))
+        .unwrap_or_else(|_| CONFIG.wait().all_hosts)
+        || matcher::should_delay(None, ip) == Some(true);
+    if let Ok(mut decisions) = IP_DECISIONS.write() {
+        if decisions.len() >= IP_DECISIONS_CAPACITY {
+            decisions.clear();
+        }
+        decisions.insert(ip, decision);
+    }
+    decision
+}
+
+/// Whether sockets connected to `ip` are intercepted, because it's an address of an intercepted
+/// host or it's matched by an address or network in the hosts or [`HookConfig::cidrs`]. Listed
+/// addresses are intercepted regardless of `exclude_loopback` and `scope`, and a loaded matcher
+/// replaces the hosts for them too.
+fn is_tracked_ip(ip: IpAddr) -> bool {
+    CONFIG.wait().matching_cidr(ip).is_some()
+        || (!matcher::is_loaded() && is_listed(Destination::Ip(ip)))
+        || should_intercept_ip(ip)
 }
 
-fn should_intercept_socket(socket: c_int) -> bool {
+fn invalidate_ip_decisions() {
+    if let Ok(mut decisions) = IP_DECISIONS.write() {
+        decisions.clear();
+    }
+}
+
+fn is_tracked_socket(socket: c_int) -> bool {
     // Definitely don't want to intercept stdin, stdout, stderr
     if socket <= 2 {
+        return false;
+    }
+    // Nothing is tracked before the configuration is loaded. Files read while loading it mustn't
+    // wait for it.
+    let Some(config) = CONFIG.get() else {
+        return false;
+    };
+    if config.passthrough {
+        return false;
+    }
+    let state = socket_state(socket);
+    if state.unsampled
+        || config
+            .only_tid
+            .is_some_and(|only_tid| state.creator != Some(only_tid))
+    {
+        false
+    } else if config.blanket {
+        let endpoint = state.endpoint.unwrap_or_else(|| look_up_endpoint(socket));
+        endpoint.ip
+            && ((config.scope == Scope::Both && config.asns.is_empty())
+                || endpoint.peer.is_some_and(|ip| config.in_scope(ip)))
+    } else {
+        state.origin.is_some()
+    }
+}
+
+/// Looks up what `socket` is for an fd created without going through the hooks, e.g. inherited.
+/// Remembered until it's closed, unless that can't be seen.
+fn look_up_endpoint(socket: c_int) -> Endpoint {
+    let endpoint = Endpoint {
+        ip: util::is_ip_socket(socket),
+        peer: util::get_peer_ip(socket),
+    };
+    if cfg!(feature = "hooks-lifecycle") {
+        update_socket_state(socket, |state| state.endpoint = Some(endpoint));
+    }
+    endpoint
+}
+
+/// Records what `socket` is for `PRELOAD_LATENCY_BLANKET`, so it never has to be looked up.
+fn record_endpoint(socket: c_int, endpoint: Endpoint) {
+    if socket >= 0 && CONFIG.get().is_some_and(|config| config.blanket) {
+        update_socket_state(socket, |state| state.endpoint = Some(endpoint));
+    }
+}
+
+/// Whether a datagram to or from `peer` on `socket` is intercepted. Decided by `peer` rather than
+/// whatever `socket` is connected to, since a datagram socket can talk to any number of peers.
+fn is_tracked_datagram(socket: c_int, peer: IpAddr) -> bool {
+    let Some(config) = CONFIG.get() else {
+        return false;
+    };
+    if socket <= 2
+        || config.passthrough
+        || config
+            .only_tid
+            .is_some_and(|only_tid| socket_state(socket).creator != Some(only_tid))
+    {
+        return false;
+    }
+    (config.blanket && config.in_scope(peer)) || is_tracked_ip(peer)
+}
+
+/// Records the calling thread as the creator of `fd` for [`HookConfig::only_tid`], unless one was
+/// already recorded.
+fn record_creator(fd: c_int) {
+    if fd < 0 || CONFIG.get().is_none_or(|config| config.only_tid.is_none()) {
+        return;
+    }
+    let tid = util::current_tid();
+    update_socket_state(fd, |state| {
+        state.creator.get_or_insert(tid);
+    });
+}
+
+/// Whether a socket with the local address `ip` may be tracked, i.e. `ip` is in one of
+/// [`HookConfig::local_cidrs`] if any are configured.
+fn is_tracked_local_ip(ip: Option<IpAddr>) -> bool {
+    let local_cidrs = &CONFIG.wait().local_cidrs;
+    local_cidrs.is_empty() || ip.is_some_and(|ip| local_cidrs.iter().any(|cidr| cidr.contains(ip)))
+}
+
+/// Whether a new connection should be delayed at all, per [`HookConfig::conn_sample_rate`].
+fn is_sampled_connection(socket: c_int) -> bool {
+    let sampled = CONFIG
+        .wait()
+        .conn_sample_rate
+        .is_none_or(util::random_chance);
+    if !sampled {
+        tracing::debug!("Not sampling connection on socket {socket}, it won't be delayed");
+    }
+    sampled
+}
+
+/// Whether tracked sockets should currently be intercepted at all.
+fn is_intercepting() -> bool {
+    toggle::is_active() && global_bytes_threshold_reached() && !budget_exhausted()
+}
+
+/// Whether the hooks already slept for [`HookConfig::budget_millis`] in total.
+fn budget_exhausted() -> bool {
+    let Some(budget_millis) = CONFIG.wait().budget_millis else {
+        return false;
+    };
+    let exhausted = stats::slept_micros() >= budget_millis.saturating_mul(1000);
+    if exhausted && !BUDGET_EXHAUSTED.swap(true, Ordering::Relaxed) {
+        tracing::warn!("Injected {budget_millis} ms of latency in total, not injecting any more");
+    }
+    exhausted
+}
+
+fn global_bytes_threshold_reached() -> bool {
+    CONFIG
+        .wait()
+        .global_bytes
+        .is_none_or(|threshold| GLOBAL_BYTES.load(Ordering::Relaxed) >= threshold)
+}
+
+/// The state of `fd`, or the default state if nothing is known about it.
+fn socket_state(fd: c_int) -> SocketState {
+    SOCKETS
+        .read()
+        .ok()
+        .and_then(|sockets| sockets.get(&fd).copied())
+        .unwrap_or_default()
+}
+
+/// Changes the state of `fd` with `update`.
+fn update_socket_state(fd: c_int, update: impl FnOnce(&mut SocketState)) {
+    let Ok(mut sockets) = SOCKETS.write() else {
+        tracing::warn!("Failed to access socket state");
+        return;
+    };
+    let state = sockets.entry(fd).or_default();
+    update(state);
+    if *state == SocketState::default() {
+        sockets.remove(&fd);
+    }
+}
+
+/// Forgets everything about `fd` once it's closed, so a new fd with the same number starts from
+/// scratch.
+fn forget_fd(fd: c_int) {
+    let state = SOCKETS
+        .write()
+        .ok()
+        .and_then(|mut sockets| sockets.remove(&fd));
+    if let Some(state) = state
+        && state.origin.is_some()
+    {
+        tracing::debug!("Closed socket {fd}");
+        OPEN_TRACKED.fetch_sub(1, Ordering::Relaxed);
+        summarize_connection(fd, &state);
+    }
+    #[cfg(target_os = "linux")]
+    epoll::forget_closed(|closed| closed == fd);
+}
+
+/// Forgets everything about the fds from `first` to `last` inclusive, once `close_range` closed
+/// them.
+#[cfg(target_os = "linux")]
+fn forget_fd_range(first: c_uint, last: c_uint) {
+    let Ok(mut sockets) = SOCKETS.write() else {
+        return;
+    };
+    let mut closed_tracked = Vec::new();
+    sockets.retain(|fd, state| {
+        let closed = (first..=last).contains(&(*fd as c_uint));
+        if closed && state.origin.is_some() {
+            tracing::debug!("Closed socket {fd}");
+            OPEN_TRACKED.fetch_sub(1, Ordering::Relaxed);
+            closed_tracked.push((*fd, *state));
+        }
+        !closed
+    });
+    drop(sockets);
+    for (fd, state) in closed_tracked {
+        summarize_connection(fd, &state);
+    }
+    epoll::forget_closed(|fd| (first..=last).contains(&(fd as c_uint)));
+}
+
+/// Logs or appends to [`HookConfig::conn_summary_file`] a line summarizing the traffic on the
+/// tracked socket `fd`, now that it's closed, if [`HookConfig::conn_summary`] is enabled.
+fn summarize_connection(fd: c_int, state: &SocketState) {
+    let config = CONFIG.wait();
+    if !config.conn_summary {
+        return;
+    }
+    let (peer, host) = match state.peer {
+        Some(peer) => (peer.to_string(), host_for_ip(peer)),
+        None => ("-".to_owned(), "-".to_owned()),
+    };
+    let duration = state.tracked_at.map(|at| at.elapsed()).unwrap_or_default();
+    let summary = format!(
+        "fd={fd} peer={peer} host={host} sent={} received={} slept_ms={:.3} duration_ms={:.3}",
+        state.sent,
+        state.received,
+        state.slept_micros as f64 / 1000.0,
+        duration.as_secs_f64() * 1000.0,
+    );
+    let Some(path) = &config.conn_summary_file else {
+        tracing::info!("Connection summary: {summary}");
+        return;
+    };
+    // The socket is closed already, so opening the file can reuse its fd without picking up its
+    // state.
+    let appended = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .and_then(|mut file| file.write_all(format!("{summary}\n").as_bytes()));
+    if let Err(e) = appended {
+        tracing::warn!(
+            "Failed to append connection summary to {}: {e}",
+            path.display()
+        );
+    }
+}
+
+fn is_nonblocking(socket: c_int) -> bool {
+    socket_state(socket).nonblocking
+}
+
+fn set_nonblocking(socket: c_int, nonblocking: bool) {
+    update_socket_state(socket, |state| state.nonblocking = nonblocking);
+}
+
+/// Whether a connection to the tracked `ip` should be refused because it hasn't been attempted
+/// [`HookConfig::connect_fail_count`] times yet. Counts the attempt.
+fn should_fail_connect(ip: IpAddr) -> bool {
+    let config = CONFIG.wait();
+    let fail_count = config.connect_fail_count;
+    if fail_count == 0 || config.passthrough || !is_intercepting() {
+        return false;
+    }
+    let Ok(mut attempts) = CONNECT_ATTEMPTS.write() else {
+        return false;
+    };
+    let attempts = attempts.entry(ip).or_default();
+    if *attempts < fail_count {
+        *attempts += 1;
+        true
+    } else {
         false
+    }
+}
+
+/// Whether a connection to `ip` pays [`HookConfig::cold_connect_millis`], because it's the first
+/// one to its host or one of every [`HookConfig::cold_connect_every`]. Counts the connection.
+fn is_cold_connect(ip: IpAddr) -> bool {
+    let host = host_for_ip(ip);
+    let Ok(mut connections) = HOST_CONNECTIONS.write() else {
+        return false;
+    };
+    let count = connections.entry(host).or_default();
+    let cold = match CONFIG.wait().cold_connect_every {
+        Some(every) => *count % u64::from(every) == 0,
+        None => *count == 0,
+    };
+    *count += 1;
+    cold
+}
+
+/// Scale `duration` for properties of the tracked `socket`.
+fn scaled_duration(socket: c_int, duration: c_uint) -> c_uint {
+    if is_nonblocking(socket) {
+        CONFIG.wait().nonblock_sleep_duration(duration)
+    } else {
+        duration
+    }
+}
+
+/// Scale `duration` by the [`HookConfig::tod_profile`] multiplier for the current local hour.
+fn time_of_day_scaled(duration: c_uint) -> c_uint {
+    let Some(profile) = &CONFIG.wait().tod_profile else {
+        return duration;
+    };
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let multiplier = if now < TOD_VALID_UNTIL.load(Ordering::Relaxed) {
+        f64::from_bits(TOD_MULTIPLIER.load(Ordering::Relaxed))
+    } else {
+        let Some((hour, secs_left)) = util::local_hour() else {
+            return duration;
+        };
+        let multiplier = profile.multiplier(hour);
+        tracing::debug!("Scaling sleeps by {multiplier} during hour {hour}");
+        TOD_MULTIPLIER.store(multiplier.to_bits(), Ordering::Relaxed);
+        TOD_VALID_UNTIL.store(now + secs_left, Ordering::Relaxed);
+        multiplier
+    };
+    (f64::from(duration) * multiplier).min(c_uint::MAX.into()) as c_uint
+}
+
+/// Scale `duration` by how far the 1-minute load average is above [`HookConfig::load_threshold`].
+fn load_scaled(duration: c_uint) -> c_uint {
+    let Some(threshold) = CONFIG.wait().load_threshold else {
+        return duration;
+    };
+    let now = util::monotonic_nanos();
+    let multiplier = if now < LOAD_VALID_UNTIL.load(Ordering::Relaxed) {
+        f64::from_bits(LOAD_MULTIPLIER.load(Ordering::Relaxed))
+    } else {
+        let mut load = 0.0;
+        if unsafe { libc::getloadavg(&mut load, 1) } != 1 {
+            return duration;
+        }
+        let multiplier = (load / threshold).max(1.0);
+        tracing::debug!("Scaling sleeps by {multiplier} at a load average of {load}");
+        LOAD_MULTIPLIER.store(multiplier.to_bits(), Ordering::Relaxed);
+        LOAD_VALID_UNTIL.store(now + LOAD_CACHE_NANOS, Ordering::Relaxed);
+        multiplier
+    };
+    (f64::from(duration) * multiplier).min(c_uint::MAX.into()) as c_uint
+}
+
+/// Duration in microseconds to sleep for an operation of `len` bytes on the tracked `socket`, not
+/// including the time it takes to transfer the bytes.
+fn sleep_duration(socket: c_int, len: usize, peer: Option<IpAddr>) -> c_uint {
+    let config = CONFIG.wait();
+    let state = socket_state(socket);
+    let peer = peer.or(state.peer);
+    let mut duration =
+        config
+            .size_bucket_duration(len)
+            .unwrap_or_else(|| match state.sni_millis.flatten() {
+                Some(millis) => millis.saturating_mul(1000),
+                None => config.sleep_duration_for(peer),
+            });
+    if let Some(peer) = peer
+        && config.host_offset_millis.is_some()
+    {
+        duration = config.host_offset(duration, &host_for_ip(peer));
+    }
+    if let Some(tracked_at) = state.tracked_at {
+        duration = config.decayed(duration, tracked_at.elapsed());
+    }
+    duration = config.conn_scaled(duration, OPEN_TRACKED.load(Ordering::Relaxed));
+    if let Some(started_at) = STARTED_AT.get() {
+        duration = config.waved(duration, started_at.elapsed());
+    }
+    let mut duration = config.jittered(load_scaled(time_of_day_scaled(duration)));
+    if let Some(peer) = peer {
+        duration = duration.saturating_add(qps_penalty(peer));
+        duration = duration.saturating_add(host_quota_penalty(peer));
+    }
+    scaled_duration(socket, duration)
+}
+
+/// Duration in microseconds to sleep for a send (or receive, if `sending` is false) on `socket`
+/// with [`HookConfig::response_millis`]: nothing, except for the first receive after a send.
+fn response_duration(socket: c_int, sending: bool, response_millis: c_uint) -> c_uint {
+    let mut awaiting_response = false;
+    update_socket_state(socket, |state| {
+        awaiting_response = state.awaiting_response;
+        state.awaiting_response = sending;
+    });
+    if sending || !awaiting_response {
+        return 0;
+    }
+    let duration = load_scaled(time_of_day_scaled(response_millis.saturating_mul(1000)));
+    let duration = CONFIG.wait().jittered(duration);
+    scaled_duration(socket, duration)
+}
+
+/// Returns and resets the number of bytes sent with `MSG_MORE` on `socket` since the last send
+/// without it.
+fn take_pending_bytes(socket: c_int) -> usize {
+    let mut pending_bytes = 0;
+    update_socket_state(socket, |state| {
+        pending_bytes = std::mem::take(&mut state.pending_bytes);
+    });
+    pending_bytes
+}
+
+/// Whether an intercepted operation on `socket` falls on the parity configured by
+/// [`HookConfig::alternate`], so it's delayed. Counts the operation.
+fn alternate_allows(socket: c_int) -> bool {
+    let Some(parity) = CONFIG.wait().alternate else {
+        return true;
+    };
+    let mut ops = 0;
+    update_socket_state(socket, |state| {
+        state.ops += 1;
+        ops = state.ops;
+    });
+    parity.matches(ops)
+}
+
+/// Duration in microseconds to wait for `bytes` to get through the pipe shared by all tracked
+/// sockets, if [`HookConfig::shared_bps`] is configured.
+fn shared_pipe_duration(bytes: usize) -> c_uint {
+    let Some(shared_bps) = CONFIG.wait().shared_bps else {
+        return 0;
+    };
+    let now = Instant::now();
+    let Ok(mut pipe) = SHARED_PIPE
+        .get_or_init(|| RwLock::new(TokenBucket::new(shared_bps, now)))
+        .write()
+    else {
+        return 0;
+    };
+    pipe.take(bytes, now)
+        .as_micros()
+        .try_into()
+        .unwrap_or(c_uint::MAX)
+}
+
+/// Whether an intercepted operation should pass through without delay because there were more
+/// than [`HookConfig::max_ops_per_sec`] in the last second. Counts the operation.
+fn shed_op() -> bool {
+    let Some(max_ops_per_sec) = CONFIG.wait().max_ops_per_sec else {
+        return false;
+    };
+    let Ok(mut ops) = OPS
+        .get_or_init(|| {
+            RwLock::new(SlidingWindow::new(
+                max_ops_per_sec as usize,
+                Duration::from_secs(1),
+            ))
+        })
+        .write()
+    else {
+        return false;
+    };
+    let shed = ops.record(Instant::now());
+    if shed {
+        tracing::trace!("Over {max_ops_per_sec} operations per second, not delaying");
+    }
+    shed
+}
+
+/// The host `ip` was resolved for, or the IP itself if it wasn't resolved through `getaddrinfo`.
+fn host_for_ip(ip: IpAddr) -> String {
+    HOST_ADDRS
+        .read()
+        .ok()
+        .and_then(|addrs| addrs.get(&ip).cloned())
+        .unwrap_or_else(|| ip.to_string())
+}
+
+/// Extra duration in microseconds to sleep for an operation on a socket connected to `ip`, if its
+/// host is over [`HookConfig::qps_limit`]. Counts the operation.
+fn qps_penalty(ip: IpAddr) -> c_uint {
+    let config = CONFIG.wait();
+    let Some(qps_limit) = config.qps_limit else {
+        return 0;
+    };
+    let host = host_for_ip(ip);
+    let Ok(mut rates) = HOST_RATES.write() else {
+        return 0;
+    };
+    let window = rates
+        .entry(host.clone())
+        .or_insert_with(|| SlidingWindow::new(qps_limit as usize, Duration::from_secs(1)));
+    if window.record(Instant::now()) {
+        tracing::debug!("{host} is over {qps_limit} operations per second");
+        config.qps_penalty_millis.saturating_mul(1000)
+    } else {
+        0
+    }
+}
+
+/// Extra duration in microseconds to sleep for an operation on a socket connected to `ip`, if its
+/// host transferred more than [`HookConfig::host_quota_bytes`].
+fn host_quota_penalty(ip: IpAddr) -> c_uint {
+    let config = CONFIG.wait();
+    let Some(host_quota_bytes) = config.host_quota_bytes else {
+        return 0;
+    };
+    let host = host_for_ip(ip);
+    let transferred = HOST_BYTES
+        .read()
+        .ok()
+        .and_then(|bytes| bytes.get(&host).copied())
+        .unwrap_or(0);
+    if transferred > host_quota_bytes {
+        tracing::debug!("{host} is over its quota of {host_quota_bytes} bytes");
+        config.host_quota_penalty_millis.saturating_mul(1000)
     } else {
-        HOST_SOCKETS
-            .read()
-            .map(|sockets| sockets.contains(&socket))
-            .unwrap_or(false)
-            && toggle::is_active()
+        0
     }
 }
 
+/// Counts `bytes` transferred over a socket connected to `ip` towards its host's quota.
+fn count_host_bytes(ip: IpAddr, bytes: u64) {
+    let host = host_for_ip(ip);
+    if let Ok(mut transferred) = HOST_BYTES.write() {
+        let transferred = transferred.entry(host).or_default();
+        *transferred = transferred.saturating_add(bytes);
+    }
+}
+
+/// The length to pass to the real function for an I/O call of `len` bytes on `socket`. Usually
+/// `len`, but a random shorter length at [`HookConfig::short_io_rate`] on intercepted stream
+/// sockets.
+fn io_len(socket: c_int, len: size_t) -> size_t {
+    if len <= 1 || !is_tracked_socket(socket) || !is_intercepting() {
+        return len;
+    }
+    let Some(short_io_rate) = CONFIG.wait().short_io_rate else {
+        return len;
+    };
+    if !util::random_chance(short_io_rate) || !util::is_stream_socket(socket) {
+        return len;
+    }
+    let short = 1 + (util::random_u64() % (len as u64 - 1)) as size_t;
+    tracing::debug!("Shortening I/O on socket {socket} from {len} to {short} bytes");
+    short
+}
+
+/// The part of a sleep of `duration` microseconds on `socket` that doesn't overlap a sleep already
+/// in progress on it, per [`HookConfig::coalesce_concurrent`]. An operation starting during another
+/// one's sleep only waits for that sleep to end, so concurrent operations share one delay.
+fn coalesced_duration(socket: c_int, duration: c_uint) -> c_uint {
+    let now = Instant::now();
+    let mut coalesced = duration;
+    update_socket_state(socket, |state| coalesced = state.coalesce(duration, now));
+    if coalesced < duration {
+        tracing::debug!(
+            "Sleeping for {coalesced} instead of {duration} us on socket {socket}, overlapping another operation"
+        );
+    }
+    coalesced
+}
+
+/// Whether a call with `flags` on the tracked `socket` should fail with `EAGAIN` without being
+/// made, as if its buffer were full or empty. Only non-blocking calls fail, at
+/// [`HookConfig::backpressure_rate`].
+fn is_backpressured(socket: c_int, flags: c_int) -> bool {
+    let Some(backpressure_rate) = CONFIG.wait().backpressure_rate else {
+        return false;
+    };
+    (flags & libc::MSG_DONTWAIT != 0 || is_nonblocking(socket))
+        && is_intercepting()
+        && util::random_chance(backpressure_rate)
+}
+
+/// Performs a send-like `op` of `payload` with `flags` on `socket` with `call`, sleeping before or
+/// after it according to [`HookConfig::timing`] if the socket is intercepted.
+unsafe fn with_send_delay(
+    socket: c_int,
+    op: &str,
+    payload: Payload,
+    flags: c_int,
+    call: impl FnMut() -> ssize_t,
+) -> ssize_t {
+    unsafe { with_delay(socket, op, Some(payload), payload.len(), flags, None, call) }
+}
+
+/// Performs a receive-like `op` into a buffer of `len` bytes on `socket` with `call`, sleeping
+/// before or after it according to [`HookConfig::timing`] if the socket is intercepted. The time it
+/// takes to transfer the received bytes is always slept after the call, unless `flags` contain
+/// `MSG_PEEK`.
+unsafe fn with_recv_delay(
+    socket: c_int,
+    op: &str,
+    len: usize,
+    flags: c_int,
+    call: impl FnMut() -> ssize_t,
+) -> ssize_t {
+    unsafe { with_delay(socket, op, None, len, flags, None, call) }
+}
+
+/// Performs a receive-like `op` into a buffer of `len` bytes on `socket` with `call`, which fills
+/// in the source address `addr` of what it receives. A datagram's source decides whether it's
+/// intercepted, so the real call is made first and any delay is slept after it.
+unsafe fn with_recvfrom_delay(
+    socket: c_int,
+    op: &str,
+    len: usize,
+    flags: c_int,
+    addr: *const sockaddr,
+    addrlen: *const socklen_t,
+    mut call: impl FnMut() -> ssize_t,
+) -> ssize_t {
+    if addr.is_null() || addrlen.is_null() {
+        return unsafe { with_recv_delay(socket, op, len, flags, call) };
+    }
+    let result = call();
+    // Stream sockets set the length to 0 instead of filling in a source.
+    let peer = (result >= 0 && unsafe { *addrlen } > 0)
+        .then(|| unsafe { util::get_ip_addr(addr) })
+        .flatten();
+    let mut received = Some(result);
+    let delayed = unsafe {
+        with_delay(socket, op, None, len, flags, peer, || {
+            received.take().unwrap_or_else(&mut call)
+        })
+    };
+    // Backpressure skips the call, but what was already received mustn't be dropped.
+    received.unwrap_or(delayed)
+}
+
+/// `payload` is `None` for receive-like calls. `len` is the size of the payload or the receive
+/// buffer, and `flags` are the `MSG_*` flags of the call. `peer` is the address of a datagram's
+/// destination or source, which decides whether the call is intercepted instead of `socket`.
+unsafe fn with_delay(
+    socket: c_int,
+    op: &str,
+    payload: Option<Payload>,
+    len: usize,
+    flags: c_int,
+    peer: Option<IpAddr>,
+    mut call: impl FnMut() -> ssize_t,
+) -> ssize_t {
+    let tracked = match peer {
+        Some(peer) => is_tracked_datagram(socket, peer),
+        None => is_tracked_socket(socket),
+    };
+    if !tracked || !unsafe { alpn_allows(socket, payload) } {
+        return call();
+    }
+    unsafe { note_server_name(socket, payload) };
+    if is_backpressured(socket, flags) {
+        tracing::debug!("Failing {op} on socket {socket} with EAGAIN");
+        util::set_errno(libc::EAGAIN);
+        return -1;
+    }
+
+    let config = CONFIG.wait();
+    let started_nanos = config.log_ops.then(util::monotonic_nanos);
+    let mut call_duration = Duration::ZERO;
+    let mut errno = None;
+    let mut call = || {
+        let started = Instant::now();
+        let mut result = call();
+        while config.retry_eintr
+            && result == -1
+            && std::io::Error::last_os_error().raw_os_error() == Some(libc::EINTR)
+        {
+            tracing::debug!("Retrying {op}() on socket {socket} after EINTR");
+            result = call();
+        }
+        call_duration = started.elapsed();
+        // Sleeping and accounting after the call may clobber `errno`, so it's restored at the end.
+        errno = (result == -1).then(|| std::io::Error::last_os_error().raw_os_error().unwrap_or(0));
+        result
+    };
+    // Peeks leave the data in the socket's queue, so their bytes aren't accounted for.
+    let peek = payload.is_none() && flags & libc::MSG_PEEK != 0;
+    // Sends with `MSG_MORE` are coalesced with the next send, which sleeps once for all of them.
+    let coalescing = config.coalesce_msg_more && payload.is_some();
+    let more = coalescing && flags & libc::MSG_MORE != 0;
+    let len = if coalescing && !more {
+        len.saturating_add(take_pending_bytes(socket))
+    } else {
+        len
+    };
+    let intercepting = config.delay_data
+        && is_intercepting()
+        && config.opcode_prefix.as_ref().is_none_or(|prefix| {
+            // Only sends matching the prefix are delayed; their responses aren't.
+            payload.is_some_and(|payload| unsafe { payload.starts_with(&prefix.0) })
+        })
+        && config.payload_contains.as_ref().is_none_or(|needle| {
+            payload.is_some_and(|payload| unsafe {
+                payload.contains(&needle.0, PAYLOAD_CONTAINS_SCAN_LEN)
+            })
+        })
+        && !shed_op()
+        && alternate_allows(socket);
+    let delaying = intercepting
+        && !more
+        && !(peek && config.skip_peek_delay)
+        && config.delays_direction(payload.is_some());
+    // With a multiplier, the real call's own duration determines the sleep instead.
+    let multiplier = config.multiplier.filter(|_| delaying);
+    let mut duration = if delaying && multiplier.is_none() {
+        match config.response_millis {
+            Some(response_millis) => response_duration(socket, payload.is_some(), response_millis),
+            None => sleep_duration(socket, len, peer),
+        }
+    } else {
+        0
+    };
+    if let Some(payload) = payload.filter(|_| intercepting && config.bytes_per_sec.is_some()) {
+        let transfer = scaled_duration(socket, config.transfer_duration(unsafe { payload.len() }));
+        duration = duration.saturating_add(transfer);
+    }
+    if let Some(payload) = payload.filter(|_| intercepting && config.shared_bps.is_some()) {
+        duration = duration.saturating_add(shared_pipe_duration(unsafe { payload.len() }));
+    }
+    if config.coalesce_concurrent && duration > 0 {
+        duration = coalesced_duration(socket, duration);
+    }
+    let mut slept = u64::from(duration);
+    let result = if duration == 0 {
+        call()
+    } else {
+        let sleep = || {
+            let when = match config.timing {
+                Timing::Pre => "before",
+                Timing::Post => "after",
+            };
+            tracing::debug!("Sleeping {when} {op}() on socket {socket}...");
+            unsafe { libc::usleep(duration) };
+        };
+        config.timing.around(sleep, &mut call)
+    };
+
+    if more && result > 0 {
+        update_socket_state(socket, |state| {
+            state.pending_bytes = state.pending_bytes.saturating_add(result as usize);
+        });
+    }
+
+    if let Some(multiplier) = multiplier {
+        let amplified = call_duration.mul_f64(multiplier - 1.0).as_micros();
+        let amplified = scaled_duration(socket, amplified.try_into().unwrap_or(c_uint::MAX));
+        if amplified > 0 {
+            tracing::debug!(
+                "Sleeping after {op}() on socket {socket} to make it {multiplier} times slower..."
+            );
+            unsafe { libc::usleep(amplified) };
+            slept += u64::from(amplified);
+        }
+    }
+
+    // The bytes of a peek are accounted for by the call that consumes them.
+    let consumed = if peek { 0 } else { result };
+    if intercepting && payload.is_none() && consumed > 0 && config.bytes_per_sec.is_some() {
+        let transfer = scaled_duration(socket, config.transfer_duration(result as usize));
+        if transfer > 0 {
+            tracing::debug!(
+                "Sleeping after {op}() on socket {socket} to receive {result} bytes..."
+            );
+            unsafe { libc::usleep(transfer) };
+            slept += u64::from(transfer);
+        }
+    }
+
+    if intercepting && payload.is_none() && consumed > 0 && config.shared_bps.is_some() {
+        let queued = shared_pipe_duration(result as usize);
+        if queued > 0 {
+            tracing::debug!(
+                "Sleeping after {op}() on socket {socket} for {result} bytes to get through the shared pipe..."
+            );
+            unsafe { libc::usleep(queued) };
+            slept += u64::from(queued);
+        }
+    }
+
+    if consumed > 0 && config.global_bytes.is_some() {
+        GLOBAL_BYTES.fetch_add(result as u64, Ordering::Relaxed);
+    }
+    if consumed > 0
+        && config.host_quota_bytes.is_some()
+        && let Some(peer) = peer.or_else(|| socket_state(socket).peer)
+    {
+        count_host_bytes(peer, result as u64);
+    }
+    if config.conn_summary {
+        update_socket_state(socket, |state| {
+            match payload {
+                Some(_) => state.sent = state.sent.saturating_add(consumed.max(0) as u64),
+                None => state.received = state.received.saturating_add(consumed.max(0) as u64),
+            }
+            state.slept_micros = state.slept_micros.saturating_add(slept);
+        });
+    }
+    stats::record_call(slept);
+    #[cfg(feature = "json")]
+    events::record(events::Event {
+        at: std::time::SystemTime::now(),
+        fd: socket,
+        peer: peer.or_else(|| socket_state(socket).peer),
+        send: payload.is_some(),
+        result,
+        slept_micros: slept,
+    });
+    if let Some(started_nanos) = started_nanos {
+        tracing::info!(
+            "{op}() on socket {socket} at {started_nanos} ns returned {result} after sleeping {slept} us"
+        );
+    }
+    if let Some(errno) = errno {
+        util::set_errno(errno);
+    }
+    result
+}
+
+/// Whether `socket` may be intercepted according to [`HookConfig::alpn`]. Decided on the first
+/// send, which is `payload` if it's `Some`, by the protocols its ClientHello advertises.
+unsafe fn alpn_allows(socket: c_int, payload: Option<Payload>) -> bool {
+    let alpn = &CONFIG.wait().alpn;
+    if alpn.is_empty() {
+        return true;
+    }
+    if let Some(matched) = socket_state(socket).alpn_matched {
+        return matched;
+    }
+    let Some(payload) = payload else {
+        return false;
+    };
+    let bytes = unsafe { payload.prefix(tls::MAX_RECORD_LEN) };
+    let protocols = tls::ClientHello::parse(&bytes)
+        .map(|hello| hello.alpn_protocols())
+        .unwrap_or_default();
+    let matched = protocols
+        .iter()
+        .any(|protocol| alpn.iter().any(|listed| listed.as_bytes() == *protocol));
+    tracing::debug!(
+        "Socket {socket} advertises ALPN protocols {:?}, {}intercepting it",
+        protocols
+            .iter()
+            .map(|protocol| String::from_utf8_lossy(protocol))
+            .collect::<Vec<_>>(),
+        if matched { "" } else { "not " }
+    );
+    update_socket_state(socket, |state| state.alpn_matched = Some(matched));
+    matched
+}
+
+/// Looks up the server name in the ClientHello that `payload` starts with in
+/// [`HookConfig::sni_millis`], if this is the first send on `socket` and any are configured.
+unsafe fn note_server_name(socket: c_int, payload: Option<Payload>) {
+    let sni_millis = &CONFIG.wait().sni_millis;
+    let Some(payload) = payload.filter(|_| !sni_millis.is_empty()) else {
+        return;
+    };
+    if socket_state(socket).sni_millis.is_some() {
+        return;
+    }
+    let bytes = unsafe { payload.prefix(tls::MAX_RECORD_LEN) };
+    let hello = tls::ClientHello::parse(&bytes);
+    let server_name = hello.as_ref().and_then(|hello| hello.server_name());
+    let millis = server_name
+        .and_then(|server_name| sni_millis.get(&server_name.to_ascii_lowercase()))
+        .copied();
+    tracing::debug!(
+        "Socket {socket} connects to server name {server_name:?}, {}",
+        match millis {
+            Some(millis) => format!("sleeping for {millis} milliseconds"),
+            None => "using the usual latency".to_owned(),
+        }
+    );
+    update_socket_state(socket, |state| state.sni_millis = Some(millis));
+}
+
+/// Stops tracking addresses of hosts that are no longer intercepted, e.g. because they were
+/// removed from the hosts file. Sockets that are already connected stay intercepted.
+fn forget_untracked_hosts() {
+    let Ok(mut addrs) = HOST_ADDRS.write() else {
+        return;
+    };
+    let before = addrs.len();
+    addrs.retain(|ip, host| {
+        let keep =
+            matcher::should_delay(Some(host), *ip).unwrap_or_else(|| should_intercept_host(host));
+        if !keep {
+            tracing::info!("No longer tracking {ip} for {host}");
+        }
+        keep
+    });
+    let removed = addrs.len() != before;
+    drop(addrs);
+    if removed {
+        invalidate_ip_decisions();
+    }
+}
+
+/// Resolves [`HookConfig::hosts`] again every `interval` on a background thread, to follow DNS
+/// changes.
+fn start_reresolving(interval: Duration) {
+    tracing::info!("Re-resolving hosts every {} seconds", interval.as_secs());
+    let spawned = std::thread::Builder::new()
+        .name("preload_latency-reresolve".to_owned())
+        .spawn(move || {
+            loop {
+                std::thread::sleep(interval);
+                CONFIG.wait().resolve_hosts(forget_stale_addrs);
+            }
+        });
+    if let Err(e) = spawned {
+        tracing::warn!("Failed to spawn a thread to re-resolve hosts: {e}");
+    }
+}
+
+/// Tracks the addresses in [`HookConfig::hostmap`] as if each had been resolved for its first
+/// name.
+fn track_hostmap(hostmap: &[(IpAddr, Vec<String>)]) {
+    let Ok(mut addrs) = HOST_ADDRS.write() else {
+        tracing::warn!("Failed to access tracked addresses");
+        return;
+    };
+    for (ip, names) in hostmap {
+        tracing::info!("Tracking {ip} for {} from the host map", names[0]);
+        addrs.insert(*ip, names[0].clone());
+    }
+    drop(addrs);
+    invalidate_ip_decisions();
+}
+
+/// Stops tracking addresses of `host` that aren't among its `current` ones anymore. Sockets that
+/// are already connected stay intercepted.
+fn forget_stale_addrs(host: &str, current: Vec<IpAddr>) {
+    let Ok(mut addrs) = HOST_ADDRS.write() else {
+        return;
+    };
+    let before = addrs.len();
+    addrs.retain(|ip, tracked_host| {
+        let keep = tracked_host != host || current.contains(ip);
+        if !keep {
+            tracing::info!("No longer tracking {ip} for {host}");
+        }
+        keep
+    });
+    let removed = addrs.len() != before;
+    drop(addrs);
+    if removed {
+        invalidate_ip_decisions();
+    }
+}
+
+/// Logs every address in the `addrinfo` chain returned by `getaddrinfo` for `node`.
+unsafe fn log_resolution(node: *const c_char, res: *const addrinfo) {
+    let node_str = if node.is_null() {
+        "<null>"
+    } else {
+        unsafe { util::utf8_from_ptr(node) }.unwrap_or("<invalid utf8>")
+    };
+    tracing::info!("Resolved {node_str}:");
+    let mut addr = res;
+    while !addr.is_null() {
+        let (family, socktype, sockaddr) = unsafe {
+            (
+                (*addr).ai_family,
+                (*addr).ai_socktype,
+                util::get_socket_addr((*addr).ai_addr),
+            )
+        };
+        let family = util::family_name(family);
+        match sockaddr {
+            Some(sockaddr) => tracing::info!("> {family} socktype={socktype} {sockaddr}"),
+            None => tracing::info!("> {family} socktype={socktype} <unsupported sockaddr>"),
+        }
+        addr = unsafe { (*addr).ai_next };
+    }
+}
+
+/// Logs `host` the first time it resolves without matching the hosts, unless too many were logged
+/// already.
+fn log_unmatched(host: &str) {
+    if UNMATCHED_HOSTS
+        .read()
+        .is_ok_and(|logged| logged.contains(host))
+    {
+        return;
+    }
+    let Ok(mut logged) = UNMATCHED_HOSTS.write() else {
+        return;
+    };
+    if logged.len() >= UNMATCHED_HOSTS_CAPACITY || !logged.insert(host.to_owned()) {
+        return;
+    }
+    tracing::info!("Resolved {host}, which doesn't match any of the hosts");
+    if logged.len() == UNMATCHED_HOSTS_CAPACITY {
+        tracing::info!("Logged {UNMATCHED_HOSTS_CAPACITY} unmatched hosts, not logging any more");
+    }
+}
+
+#[cfg(feature = "hooks-dns")]
 hook! {
     unsafe fn getaddrinfo(node: *const c_char, service: *const c_char, hints: *const addrinfo, res: *mut *mut addrinfo) -> c_int => w_getaddrinfo {
         unsafe {
+            require_real!(getaddrinfo, libc::EAI_SYSTEM);
             tracing::trace!("Entering getaddrinfo");
+            let config = CONFIG.wait();
+            if !config.passthrough && hosts_file::maybe_reload() {
+                forget_untracked_hosts();
+                if let Ok(mut transferred) = HOST_BYTES.write() {
+                    transferred.clear();
+                }
+            }
+            if !config.passthrough
+                && let Some(first_dns_millis) = config.first_dns_millis
+                && !FIRST_DNS_DONE.swap(true, Ordering::Relaxed)
+                && !budget_exhausted()
+            {
+                tracing::debug!("Sleeping before the first getaddrinfo()...");
+                libc::usleep(first_dns_millis.saturating_mul(1000));
+                stats::record_sleep(u64::from(first_dns_millis) * 1000);
+            }
+            if let Some(dns_fail_rate) = config.dns_fail_rate
+                && !config.passthrough
+                && !node.is_null()
+                && let Ok(node_str) = util::utf8_from_ptr(node)
+                && should_intercept_host(node_str)
+                && is_intercepting()
+                && util::random_chance(dns_fail_rate)
+            {
+                tracing::info!("Failing getaddrinfo() for tracked host: {node_str}");
+                return config.dns_fail_code.0;
+            }
+            if config.delay_handshake
+                && !config.passthrough
+                && !node.is_null()
+                && let Ok(node_str) = util::utf8_from_ptr(node)
+                && should_intercept_host(node_str)
+                && is_intercepting()
+            {
+                let duration = config.jittered(config.sleep_duration());
+                tracing::debug!("Sleeping before getaddrinfo() for {node_str}...");
+                libc::usleep(duration);
+                stats::record_sleep(duration.into());
+            }
             let result = real!(getaddrinfo)(node, service, hints, res);
 
-            if result == 0 && let Ok(node_str) = util::utf8_from_ptr(node) && should_intercept_host(node_str) && let Ok(mut addrs) = HOST_ADDRS.write() {
-                tracing::info!("Resolving tracked host: {node_str}");
+            if result == 0 && config.log_resolutions {
+                log_resolution(node, *res);
+            }
+
+            if result == 0
+                && config.log_unmatched
+                && !config.passthrough
+                && !matcher::is_loaded()
+                && !node.is_null()
+                && let Ok(node_str) = util::utf8_from_ptr(node)
+                && !should_intercept_host(node_str)
+            {
+                log_unmatched(node_str);
+            }
+
+            if result == 0
+                && !config.passthrough
+                && !node.is_null()
+                && let Ok(node_str) = util::utf8_from_ptr(node)
+                && (matcher::is_loaded() || should_intercept_host(node_str))
+                && let Ok(mut addrs) = HOST_ADDRS.write()
+            {
+                let host_matched = should_intercept_host(node_str);
+                if host_matched {
+                    tracing::info!("Resolving tracked host: {node_str}");
+                }
+                let mut added = false;
                 let mut addr = *res;
                 while !addr.is_null() {
-                    let ip = util::get_in_addr((*addr).ai_addr);
-                    tracing::info!("> Tracking {ip}");
-                    addrs.insert(ip);
+                    // A loaded matching function decides for every address instead of the hosts.
+                    if let Some(ip) = util::get_ip_addr((*addr).ai_addr)
+                        && config.dns_rrtype.is_none_or(|rrtype| rrtype.matches(ip))
+                        && matcher::should_delay(Some(node_str), ip).unwrap_or(host_matched)
+                    {
+                        tracing::info!("> Tracking {ip}");
+                        added |= addrs.insert(ip, node_str.to_owned()).is_none();
+                    }
                     addr = (*addr).ai_next;
                 }
+                drop(addrs);
+                if added {
+                    invalidate_ip_decisions();
+                }
             }
 
             result
@@ -88,53 +1567,399 @@ hook! {
     }
 }
 
+#[cfg(feature = "hooks-dns")]
 hook! {
     unsafe fn gethostbyname(name: *const c_char) -> hostent => w_gethostbyname {
         unsafe {
+            require_real!(gethostbyname, std::mem::zeroed());
             tracing::trace!("Entering gethostbyname");
             real!(gethostbyname)(name)
         }
     }
 }
 
+#[cfg(feature = "hooks-dns")]
 hook! {
     unsafe fn gethostbyaddr(addr: *const c_void, size: socklen_t, addr_type: c_int) -> hostent => w_gethostbyaddr {
         unsafe {
+            require_real!(gethostbyaddr, std::mem::zeroed());
             tracing::trace!("Entering gethostbyname");
             real!(gethostbyaddr)(addr, size, addr_type)
         }
     }
 }
 
+// `hook!` defines a static named after the hooked function, which would clash with every `socket`
+// parameter in this module.
+#[cfg(feature = "hooks-lifecycle")]
+mod socket_hook {
+    use libc::c_int;
+
+    use super::{Endpoint, record_creator, record_endpoint, set_nonblocking};
+    use crate::util;
+
+    hook! {
+        unsafe fn socket(domain: c_int, ty: c_int, protocol: c_int) -> c_int => w_socket {
+            unsafe {
+                require_real!(socket);
+                tracing::trace!("Entering socket");
+                let result = real!(socket)(domain, ty, protocol);
+                record_creator(result);
+                let ip = matches!(domain, libc::AF_INET | libc::AF_INET6);
+                record_endpoint(result, Endpoint { ip, peer: None });
+
+                if result >= 0 && ty & util::SOCK_NONBLOCK != 0 {
+                    tracing::debug!("Created non-blocking socket {result}");
+                    set_nonblocking(result, true);
+                }
+
+                result
+            }
+        }
+    }
+}
+
+/// Tracks a socket returned by `accept` or `accept4` if its peer is tracked, the same way as for
+/// `connect`.
+fn track_accepted(socket: c_int, op: &str) {
+    record_creator(socket);
+    if socket < 0 || CONFIG.wait().passthrough {
+        return;
+    }
+    let peer = util::get_peer_ip(socket);
+    record_endpoint(
+        socket,
+        Endpoint {
+            ip: peer.is_some(),
+            peer,
+        },
+    );
+    let Some(ip) = peer else {
+        return;
+    };
+    if !is_tracked_ip(ip) || !is_tracked_local_ip(util::get_local_ip(socket)) {
+        tracing::debug!("Not tracking socket {socket} from {op}() with peer {ip}");
+        return;
+    }
+    tracing::info!("Accepted socket from tracked IP: {ip}");
+    tracing::info!("> {socket}");
+    let unsampled = !is_sampled_connection(socket);
+    update_socket_state(socket, |state| {
+        state.track(Origin::Accept);
+        state.peer = Some(ip);
+        state.unsampled = unsampled;
+    });
+}
+
+/// Records `O_NONBLOCK` changes made through `fcntl(F_SETFL)`.
+fn track_fcntl(fd: c_int, cmd: c_int, arg: c_ulong, result: c_int) {
+    if result != -1 && cmd == libc::F_SETFL {
+        let nonblocking = arg as c_int & libc::O_NONBLOCK != 0;
+        tracing::debug!("Setting O_NONBLOCK={nonblocking} on fd {fd}");
+        set_nonblocking(fd, nonblocking);
+    }
+}
+
+#[cfg(feature = "hooks-lifecycle")]
+hook! {
+    // `fcntl` is variadic, but every command takes at most one argument which is passed through
+    // in a register either way.
+    unsafe fn fcntl(fd: c_int, cmd: c_int, arg: c_ulong) -> c_int => w_fcntl {
+        unsafe {
+            require_real!(fcntl);
+            tracing::trace!("Entering fcntl");
+            let result = real!(fcntl)(fd, cmd, arg);
+            track_fcntl(fd, cmd, arg, result);
+            result
+        }
+    }
+}
+
+// glibc redirects `fcntl` to `fcntl64` when built with `_FILE_OFFSET_BITS=64`.
+#[cfg(target_os = "linux")]
+#[cfg(feature = "hooks-lifecycle")]
+hook! {
+    unsafe fn fcntl64(fd: c_int, cmd: c_int, arg: c_ulong) -> c_int => w_fcntl64 {
+        unsafe {
+            require_real!(fcntl64);
+            tracing::trace!("Entering fcntl64");
+            let result = real!(fcntl64)(fd, cmd, arg);
+            track_fcntl(fd, cmd, arg, result);
+            result
+        }
+    }
+}
+
+#[cfg(feature = "hooks-lifecycle")]
+hook! {
+    // `ioctl` is variadic like `fcntl`. `FIONBIO` is how Rust's std and Python toggle `O_NONBLOCK`.
+    unsafe fn ioctl(fd: c_int, request: c_ulong, arg: *mut c_void) -> c_int => w_ioctl {
+        unsafe {
+            require_real!(ioctl);
+            tracing::trace!("Entering ioctl");
+            let result = real!(ioctl)(fd, request, arg);
+
+            if result != -1 && request == libc::FIONBIO && !arg.is_null() {
+                let nonblocking = *arg.cast::<c_int>() != 0;
+                tracing::debug!("Setting FIONBIO={nonblocking} on fd {fd}");
+                set_nonblocking(fd, nonblocking);
+            }
+
+            result
+        }
+    }
+}
+
+// Records which fds each epoll instance watches.
+#[cfg(target_os = "linux")]
+#[cfg(feature = "hooks-lifecycle")]
+hook! {
+    unsafe fn epoll_ctl(epfd: c_int, op: c_int, fd: c_int, event: *mut libc::epoll_event) -> c_int => w_epoll_ctl {
+        unsafe {
+            require_real!(epoll_ctl);
+            tracing::trace!("Entering epoll_ctl");
+            let result = real!(epoll_ctl)(epfd, op, fd, event);
+
+            if result == 0 {
+                if op == libc::EPOLL_CTL_ADD && is_tracked_socket(fd) {
+                    tracing::debug!("Watching tracked socket {fd} with epoll instance {epfd}");
+                }
+                epoll::control(epfd, op, fd);
+            }
+
+            result
+        }
+    }
+}
+
+// Reports events late from epoll instances watching tracked sockets.
+#[cfg(target_os = "linux")]
+#[cfg(feature = "hooks-lifecycle")]
+hook! {
+    unsafe fn epoll_wait(epfd: c_int, events: *mut libc::epoll_event, maxevents: c_int, timeout: c_int) -> c_int => w_epoll_wait {
+        unsafe {
+            require_real!(epoll_wait);
+            tracing::trace!("Entering epoll_wait");
+            let result = real!(epoll_wait)(epfd, events, maxevents, timeout);
+
+            if result > 0
+                && let Some(millis) = CONFIG.wait().epoll_wait_millis
+                && epoll::watches_tracked(epfd, is_tracked_socket)
+                && is_intercepting()
+            {
+                tracing::debug!("Sleeping before epoll_wait() on instance {epfd} returns...");
+                let duration = millis.saturating_mul(1000);
+                libc::usleep(duration);
+                stats::record_sleep(duration.into());
+            }
+
+            result
+        }
+    }
+}
+
+#[cfg(feature = "hooks-lifecycle")]
+hook! {
+    unsafe fn socketpair(domain: c_int, ty: c_int, protocol: c_int, sv: *mut c_int) -> c_int => w_socketpair {
+        unsafe {
+            require_real!(socketpair);
+            tracing::trace!("Entering socketpair");
+            let result = real!(socketpair)(domain, ty, protocol, sv);
+
+            if result == 0 && !sv.is_null() {
+                let fds = [*sv, *sv.add(1)];
+                fds.iter().for_each(|fd| record_creator(*fd));
+                if ty & util::SOCK_NONBLOCK != 0 {
+                    fds.iter().for_each(|fd| set_nonblocking(*fd, true));
+                }
+                if CONFIG.wait().intercept_socketpair {
+                    tracing::info!("Tracking socket pair {} and {}", fds[0], fds[1]);
+                    for fd in fds {
+                        update_socket_state(fd, |state| state.track(Origin::Ipc));
+                    }
+                }
+            }
+
+            result
+        }
+    }
+}
+
+#[cfg(feature = "hooks-lifecycle")]
 hook! {
     unsafe fn connect(socket: c_int, address: *const sockaddr, len: socklen_t) -> c_int => w_connect {
         unsafe {
+            require_real!(connect);
             tracing::trace!("Entering connect");
+            record_creator(socket);
+            let tracked_ip = util::get_ip_addr(address).filter(|ip| is_tracked_ip(*ip));
+
+            // The local address is only known this early if the socket was bound explicitly.
+            let bound = socket_state(socket).local;
+            if let Some(ip) = tracked_ip
+                && bound.is_none_or(|local| is_tracked_local_ip(Some(local)))
+                && should_fail_connect(ip)
+            {
+                tracing::info!("Refusing connection to tracked IP: {ip}");
+                util::set_errno(libc::ECONNREFUSED);
+                return -1;
+            }
+
+            let config = CONFIG.wait();
+            let unsampled = (tracked_ip.is_some() || config.blanket)
+                && !config.passthrough
+                && !is_sampled_connection(socket);
+            if unsampled {
+                update_socket_state(socket, |state| state.unsampled = true);
+            }
+            if let Some(ip) = tracked_ip
+                && !config.passthrough
+                && !unsampled
+                && bound.is_none_or(|local| is_tracked_local_ip(Some(local)))
+                && is_intercepting()
+            {
+                let mut duration = 0;
+                if config.delay_handshake {
+                    duration = config.jittered(config.sleep_duration_for(Some(ip)));
+                }
+                if let Some(cold_connect_millis) = config.cold_connect_millis
+                    && is_cold_connect(ip)
+                {
+                    tracing::debug!("Connection on socket {socket} to {ip} is cold");
+                    duration = duration.saturating_add(cold_connect_millis.saturating_mul(1000));
+                }
+                let duration = scaled_duration(socket, duration);
+                if duration > 0 {
+                    tracing::debug!("Sleeping before connect() on socket {socket}...");
+                    libc::usleep(duration);
+                    stats::record_sleep(duration.into());
+                }
+            }
+
+            #[cfg(target_os = "linux")]
+            let tracked_vsock = util::get_vsock_addr(address)
+                .filter(|addr| is_listed(Destination::Vsock(*addr)));
+
+            let tracked_unix = util::get_unix_path(address, len)
+                .filter(|path| is_listed(Destination::Unix(path)));
+
             let result = real!(connect)(socket, address, len);
+            let errno = (result == -1)
+                .then(|| std::io::Error::last_os_error().raw_os_error().unwrap_or(0));
+            // A non-blocking or interrupted connect goes on in the background. Anything else that
+            // fails, including `EISCONN` on a socket that's already connected, changes nothing.
+            if let Some(errno) = errno
+                && errno != libc::EINPROGRESS
+                && errno != libc::EINTR
+            {
+                util::set_errno(errno);
+                return result;
+            }
+
+            match util::get_ip_addr(address) {
+                Some(peer) => record_endpoint(socket, Endpoint { ip: true, peer: Some(peer) }),
+                // Connecting a datagram socket to `AF_UNSPEC` disconnects it.
+                None if !address.is_null()
+                    && c_int::from((*address).sa_family) == libc::AF_UNSPEC =>
+                {
+                    update_socket_state(socket, |state| {
+                        if let Some(endpoint) = &mut state.endpoint {
+                            endpoint.peer = None;
+                        }
+                    });
+                }
+                None => {}
+            }
+
+            // Datagram sockets can be connected again to another peer, which may not be tracked.
+            let untracked_peer = tracked_ip.is_none() && tracked_unix.is_none();
+            #[cfg(target_os = "linux")]
+            let untracked_peer = untracked_peer && tracked_vsock.is_none();
+            if untracked_peer && socket_state(socket).origin == Some(Origin::Connect) {
+                tracing::info!(
+                    "Socket {socket} was connected to an untracked address, no longer tracking it"
+                );
+                update_socket_state(socket, SocketState::untrack);
+            }
 
-            let ip = util::get_in_addr(address);
-            if should_intercept_ip(&ip) && let Ok(mut sockets) = HOST_SOCKETS.write() {
+            if let Some(path) = tracked_unix {
+                tracing::info!("Connecting socket to tracked unix socket: {path}");
+                tracing::info!("> {socket}");
+                update_socket_state(socket, |state| state.track(Origin::Connect));
+            }
+
+            #[cfg(target_os = "linux")]
+            if let Some(addr) = tracked_vsock {
+                tracing::info!("Connecting socket to tracked vsock address: {addr}");
+                tracing::info!("> {socket}");
+                update_socket_state(socket, |state| state.track(Origin::Connect));
+            }
+
+            let tracked_ip = tracked_ip
+                .filter(|_| is_tracked_local_ip(util::get_local_ip(socket).or(bound)));
+            if let Some(ip) = tracked_ip {
                 tracing::info!("Connecting socket to tracked IP: {ip}");
                 tracing::info!("> {socket}");
-                sockets.insert(socket);
+                update_socket_state(socket, |state| {
+                    state.track(Origin::Connect);
+                    state.peer = Some(ip);
+                });
+            }
+
+            if let Some(errno) = errno {
+                util::set_errno(errno);
             }
+            result
+        }
+    }
+}
 
+#[cfg(feature = "hooks-lifecycle")]
+hook! {
+    unsafe fn accept(socket: c_int, address: *mut sockaddr, address_len: *mut socklen_t) -> c_int => w_accept {
+        unsafe {
+            require_real!(accept);
+            tracing::trace!("Entering accept");
+            let result = real!(accept)(socket, address, address_len);
+            track_accepted(result, "accept");
             result
         }
     }
 }
 
+#[cfg(target_os = "linux")]
+#[cfg(feature = "hooks-lifecycle")]
+hook! {
+    unsafe fn accept4(socket: c_int, address: *mut sockaddr, address_len: *mut socklen_t, flags: c_int) -> c_int => w_accept4 {
+        unsafe {
+            require_real!(accept4);
+            tracing::trace!("Entering accept4");
+            let result = real!(accept4)(socket, address, address_len, flags);
+            if result >= 0 && flags & libc::SOCK_NONBLOCK != 0 {
+                set_nonblocking(result, true);
+            }
+            track_accepted(result, "accept4");
+            result
+        }
+    }
+}
+
+#[cfg(feature = "hooks-lifecycle")]
 hook! {
     unsafe fn bind(socket: c_int, address: *const sockaddr, address_len: socklen_t) -> c_int => w_bind {
         unsafe {
+            require_real!(bind);
             tracing::trace!("Entering bind");
             let result = real!(bind)(socket, address, address_len);
 
-            let ip = util::get_in_addr(address);
-            if should_intercept_ip(&ip) && let Ok(mut sockets) = HOST_SOCKETS.write() {
+            let ip = util::get_ip_addr(address);
+            if result == 0 && let Some(ip) = ip && !ip.is_unspecified() {
+                update_socket_state(socket, |state| state.local = Some(ip));
+            }
+            if let Some(ip) = ip && should_intercept_ip(ip) && is_tracked_local_ip(Some(ip)) {
                 tracing::info!("Binding socket to tracked IP: {ip}");
                 tracing::info!("> {socket}");
-                sockets.insert(socket);
+                update_socket_state(socket, |state| state.track(Origin::Bind));
             }
 
             result
@@ -142,125 +1967,391 @@ hook! {
     }
 }
 
+#[cfg(feature = "hooks-lifecycle")]
 hook! {
-    unsafe fn send(socket: c_int, buf: *const c_void, len: size_t, flags: c_int) -> ssize_t => w_send {
+    unsafe fn listen(socket: c_int, backlog: c_int) -> c_int => w_listen {
         unsafe {
-            tracing::trace!("Entering send");
-            if should_intercept_socket(socket) {
-                tracing::debug!("Sleeping before send() on socket {socket}...");
-                libc::usleep(CONFIG.wait().sleep_duration());
+            require_real!(listen);
+            tracing::trace!("Entering listen");
+            let result = real!(listen)(socket, backlog);
+
+            if result == 0 {
+                update_socket_state(socket, |state| state.listening = true);
+            }
+
+            result
+        }
+    }
+}
+
+/// Duration in milliseconds to sleep before closing the tracked socket `fd`, if any.
+/// [`HookConfig::listen_close_millis`] takes precedence over [`HookConfig::drain_millis`] for
+/// listening sockets.
+#[cfg(feature = "hooks-lifecycle")]
+fn close_millis(fd: c_int) -> Option<c_uint> {
+    let config = CONFIG.wait();
+    let listening = SOCKETS
+        .read()
+        .is_ok_and(|sockets| sockets.get(&fd).is_some_and(|state| state.listening));
+    match config.listen_close_millis {
+        Some(listen_close_millis) if listening => Some(listen_close_millis),
+        _ => config.drain_millis,
+    }
+}
+
+/// Logs options that affect how a tracked socket behaves under latency when they're set.
+#[cfg(feature = "hooks-lifecycle")]
+unsafe fn log_sockopt(
+    socket: c_int,
+    level: c_int,
+    name: c_int,
+    value: *const c_void,
+    len: socklen_t,
+) {
+    let name = match (level, name) {
+        (libc::IPPROTO_TCP, libc::TCP_NODELAY) => "TCP_NODELAY",
+        (libc::SOL_SOCKET, libc::SO_RCVBUF) => "SO_RCVBUF",
+        (libc::SOL_SOCKET, libc::SO_SNDBUF) => "SO_SNDBUF",
+        _ => return,
+    };
+    if value.is_null()
+        || (len as usize) < std::mem::size_of::<c_int>()
+        || !is_tracked_socket(socket)
+    {
+        return;
+    }
+    let value = unsafe { value.cast::<c_int>().read_unaligned() };
+    tracing::info!("Setting {name}={value} on socket {socket}");
+}
+
+#[cfg(feature = "hooks-lifecycle")]
+hook! {
+    unsafe fn setsockopt(socket: c_int, level: c_int, name: c_int, value: *const c_void, len: socklen_t) -> c_int => w_setsockopt {
+        unsafe {
+            require_real!(setsockopt);
+            tracing::trace!("Entering setsockopt");
+            let result = real!(setsockopt)(socket, level, name, value, len);
+
+            if result == 0 {
+                log_sockopt(socket, level, name, value, len);
             }
 
-            real!(send)(socket, buf, len, flags)
+            result
         }
     }
 }
 
+#[cfg(feature = "hooks-data")]
+hook! {
+    unsafe fn send(socket: c_int, buf: *const c_void, len: size_t, flags: c_int) -> ssize_t => w_send {
+        unsafe {
+            require_real!(send);
+            tracing::trace!("Entering send");
+            let len = io_len(socket, len);
+            with_send_delay(socket, "send", Payload::Buf { buf, len }, flags, || {
+                real!(send)(socket, buf, len, flags)
+            })
+        }
+    }
+}
+
+#[cfg(feature = "hooks-data")]
 hook! {
     unsafe fn recv(socket: c_int, buf: *mut c_void, len: size_t, flags: c_int) -> ssize_t => w_recv {
         unsafe {
+            require_real!(recv);
             tracing::trace!("Entering recv");
-            if should_intercept_socket(socket) {
-                tracing::debug!("Sleeping before recv() on socket {socket}...");
-                libc::usleep(CONFIG.wait().sleep_duration());
-            }
-
-            real!(recv)(socket, buf, len, flags)
+            let len = io_len(socket, len);
+            with_recv_delay(socket, "recv", len, flags, || real!(recv)(socket, buf, len, flags))
         }
     }
 }
 
+#[cfg(feature = "hooks-data")]
 hook! {
     unsafe fn sendto(socket: c_int, buf: *const c_void, len: size_t, flags: c_int, addr: *const sockaddr, addrlen: socklen_t) -> ssize_t => w_sendto {
         unsafe {
+            require_real!(sendto);
             tracing::trace!("Entering sendto");
-            if should_intercept_socket(socket) {
-                tracing::debug!("Sleeping before sendto() on socket {socket}...");
-                libc::usleep(CONFIG.wait().sleep_duration());
-            }
-
-            real!(sendto)(socket, buf, len, flags, addr, addrlen)
+            let len = io_len(socket, len);
+            // A datagram's destination decides whether it's intercepted. Connected sockets may
+            // leave it out.
+            let peer = util::get_ip_addr(addr);
+            with_delay(socket, "sendto", Some(Payload::Buf { buf, len }), len, flags, peer, || {
+                real!(sendto)(socket, buf, len, flags, addr, addrlen)
+            })
         }
     }
 }
 
+#[cfg(feature = "hooks-data")]
 hook! {
     unsafe fn recvfrom(socket: c_int, buf: *mut c_void, len: size_t, flags: c_int, addr: *mut sockaddr, addrlen: *mut socklen_t) -> ssize_t => w_recvfrom {
         unsafe {
+            require_real!(recvfrom);
             tracing::trace!("Entering recvfrom");
-            if should_intercept_socket(socket) {
-                tracing::debug!("Sleeping before recvfrom() on socket {socket}...");
-                libc::usleep(CONFIG.wait().sleep_duration());
-            }
+            let len = io_len(socket, len);
+            with_recvfrom_delay(socket, "recvfrom", len, flags, addr, addrlen, || {
+                real!(recvfrom)(socket, buf, len, flags, addr, addrlen)
+            })
+        }
+    }
+}
 
-            real!(recvfrom)(socket, buf, len, flags, addr, addrlen)
+// Messages passing fds over unix sockets are control-plane traffic, so they're neither delayed
+// nor counted. Whether a received message passes fds is only known once it arrived, so receives
+// with room for ancillary data on unix sockets are passed through as well.
+#[cfg(feature = "hooks-data")]
+hook! {
+    unsafe fn sendmsg(socket: c_int, msg: *const msghdr, flags: c_int) -> ssize_t => w_sendmsg {
+        unsafe {
+            require_real!(sendmsg);
+            tracing::trace!("Entering sendmsg");
+            if msg.is_null() {
+                return real!(sendmsg)(socket, msg, flags);
+            }
+            if is_tracked_socket(socket) && util::passes_fds(msg) {
+                tracing::debug!("Not delaying sendmsg() on socket {socket} passing fds");
+                return real!(sendmsg)(socket, msg, flags);
+            }
+            with_send_delay(socket, "sendmsg", Payload::from_msghdr(msg), flags, || {
+                real!(sendmsg)(socket, msg, flags)
+            })
         }
     }
 }
 
+#[cfg(feature = "hooks-data")]
 hook! {
-    unsafe fn write(fd: c_int, buf: *const c_void, count: size_t) -> ssize_t => w_write {
+    unsafe fn recvmsg(socket: c_int, msg: *mut msghdr, flags: c_int) -> ssize_t => w_recvmsg {
         unsafe {
-            if should_intercept_socket(fd) {
-                tracing::debug!("Sleeping before write() on socket {fd}...");
-                libc::usleep(CONFIG.wait().sleep_duration());
+            require_real!(recvmsg);
+            tracing::trace!("Entering recvmsg");
+            if msg.is_null() {
+                return real!(recvmsg)(socket, msg, flags);
             }
+            if is_tracked_socket(socket)
+                && (*msg).msg_controllen > 0
+                && util::is_unix_socket(socket)
+            {
+                tracing::debug!("Not delaying recvmsg() on socket {socket} that may receive fds");
+                return real!(recvmsg)(socket, msg, flags);
+            }
+            let len = Payload::from_msghdr(msg).len();
+            with_recv_delay(socket, "recvmsg", len, flags, || real!(recvmsg)(socket, msg, flags))
+        }
+    }
+}
 
-            real!(write)(fd, buf, count)
+#[cfg(feature = "hooks-data")]
+hook! {
+    unsafe fn write(fd: c_int, buf: *const c_void, count: size_t) -> ssize_t => w_write {
+        unsafe {
+            require_real!(write);
+            let count = io_len(fd, count);
+            with_send_delay(fd, "write", Payload::Buf { buf, len: count }, 0, || {
+                real!(write)(fd, buf, count)
+            })
         }
     }
 }
 
+#[cfg(feature = "hooks-data")]
 hook! {
     unsafe fn read(fd: c_int, buf: *mut c_void, count: size_t) -> ssize_t => w_read {
         unsafe {
-            if should_intercept_socket(fd) {
-                tracing::debug!("Sleeping before read() on socket {fd}...");
-                libc::usleep(CONFIG.wait().sleep_duration());
-            }
-
-            real!(read)(fd, buf, count)
+            require_real!(read);
+            let count = io_len(fd, count);
+            with_recv_delay(fd, "read", count, 0, || real!(read)(fd, buf, count))
         }
     }
 }
 
+#[cfg(feature = "hooks-data")]
 hook! {
     unsafe fn writev(fd: c_int, iov: *const iovec, count: c_int) -> ssize_t => w_writev {
         unsafe {
-            if should_intercept_socket(fd) {
-                tracing::debug!("Sleeping before writev() on socket {fd}...");
-                libc::usleep(CONFIG.wait().sleep_duration());
-            }
-
-            real!(writev)(fd, iov, count)
+            require_real!(writev);
+            with_send_delay(fd, "writev", Payload::Iovecs { iov, count }, 0, || {
+                real!(writev)(fd, iov, count)
+            })
         }
     }
 }
 
+#[cfg(feature = "hooks-data")]
 hook! {
     unsafe fn readv(fd: c_int, iov: *const iovec, count: c_int) -> ssize_t => w_readv {
         unsafe {
-            if should_intercept_socket(fd) {
-                tracing::debug!("Sleeping before readv() on socket {fd}...");
-                libc::usleep(CONFIG.wait().sleep_duration());
+            require_real!(readv);
+            with_recv_delay(fd, "readv", Payload::Iovecs { iov, count }.len(), 0, || {
+                real!(readv)(fd, iov, count)
+            })
+        }
+    }
+}
+
+// `splice` moves data between a pipe and another fd without copying it through a buffer, so it's
+// delayed like a send to a tracked `fd_out` or a receive from a tracked `fd_in`. The offsets are
+// passed through untouched. `tee` isn't hooked, since both of its ends must be pipes.
+#[cfg(target_os = "linux")]
+#[cfg(feature = "hooks-data")]
+hook! {
+    unsafe fn splice(fd_in: c_int, off_in: *mut libc::loff_t, fd_out: c_int, off_out: *mut libc::loff_t, len: size_t, flags: c_uint) -> ssize_t => w_splice {
+        unsafe {
+            require_real!(splice);
+            let call = || real!(splice)(fd_in, off_in, fd_out, off_out, len, flags);
+            if is_tracked_socket(fd_out) {
+                with_send_delay(fd_out, "splice", Payload::Pipe { len }, 0, call)
+            } else {
+                with_recv_delay(fd_in, "splice", len, 0, call)
             }
+        }
+    }
+}
+
+// Fortified variants that glibc calls instead of `read`, `recv` and `recvfrom` when a program is
+// built with `_FORTIFY_SOURCE` and the buffer size is known at compile time. `buflen` is only
+// checked by the real function. There is no fortified `write`.
+#[cfg(target_os = "linux")]
+#[cfg(feature = "hooks-data")]
+hook! {
+    unsafe fn __read_chk(fd: c_int, buf: *mut c_void, count: size_t, buflen: size_t) -> ssize_t => w_read_chk {
+        unsafe {
+            require_real!(__read_chk);
+            let count = io_len(fd, count);
+            with_recv_delay(fd, "__read_chk", count, 0, || {
+                real!(__read_chk)(fd, buf, count, buflen)
+            })
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+#[cfg(feature = "hooks-data")]
+hook! {
+    unsafe fn __recv_chk(socket: c_int, buf: *mut c_void, len: size_t, buflen: size_t, flags: c_int) -> ssize_t => w_recv_chk {
+        unsafe {
+            require_real!(__recv_chk);
+            tracing::trace!("Entering __recv_chk");
+            let len = io_len(socket, len);
+            with_recv_delay(socket, "__recv_chk", len, flags, || {
+                real!(__recv_chk)(socket, buf, len, buflen, flags)
+            })
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+#[cfg(feature = "hooks-data")]
+hook! {
+    unsafe fn __recvfrom_chk(socket: c_int, buf: *mut c_void, len: size_t, buflen: size_t, flags: c_int, addr: *mut sockaddr, addrlen: *mut socklen_t) -> ssize_t => w_recvfrom_chk {
+        unsafe {
+            require_real!(__recvfrom_chk);
+            tracing::trace!("Entering __recvfrom_chk");
+            let len = io_len(socket, len);
+            with_recvfrom_delay(socket, "__recvfrom_chk", len, flags, addr, addrlen, || {
+                real!(__recvfrom_chk)(socket, buf, len, buflen, flags, addr, addrlen)
+            })
+        }
+    }
+}
 
-            real!(readv)(fd, iov, count)
+#[cfg(feature = "hooks-lifecycle")]
+hook! {
+    // Seeking is meaningless on sockets (it fails with `ESPIPE`) so this doesn't touch any per-fd
+    // state. Per-fd state is keyed by fd number alone and only ever purged by `close`, so a file
+    // that gets seeked around on a reused fd number can't pick up a closed socket's state.
+    unsafe fn lseek(fd: c_int, offset: off_t, whence: c_int) -> off_t => w_lseek {
+        unsafe {
+            require_real!(lseek);
+            real!(lseek)(fd, offset, whence)
         }
     }
 }
 
+#[cfg(feature = "hooks-lifecycle")]
 hook! {
     unsafe fn close(fd: c_int) -> c_int => w_close {
         unsafe {
+            require_real!(close);
             tracing::trace!("Entering close");
+            if is_tracked_socket(fd)
+                && let Some(drain_millis) = close_millis(fd)
+                && is_intercepting()
+            {
+                tracing::debug!("Sleeping before close() on socket {fd}...");
+                let duration = drain_millis.saturating_mul(1000);
+                libc::usleep(duration);
+                stats::record_sleep(duration.into());
+            }
             let result = real!(close)(fd);
 
-            if result == 0 && let Ok(mut sockets) = HOST_SOCKETS.write() && sockets.remove(&fd) {
-                tracing::debug!("Closed socket {fd}");
+            if result == 0 {
+                forget_fd(fd);
             }
 
             result
         }
     }
 }
+
+// Closes every fd from `first` to `last` at once, e.g. before `exec`. With `CLOSE_RANGE_CLOEXEC`
+// the fds are only marked close-on-exec and stay open, so they're still tracked.
+#[cfg(target_os = "linux")]
+#[cfg(feature = "hooks-lifecycle")]
+hook! {
+    unsafe fn close_range(first: c_uint, last: c_uint, flags: c_int) -> c_int => w_close_range {
+        unsafe {
+            require_real!(close_range);
+            tracing::trace!("Entering close_range");
+            let result = real!(close_range)(first, last, flags);
+
+            if result == 0 && flags as c_uint & libc::CLOSE_RANGE_CLOEXEC == 0 {
+                forget_fd_range(first, last);
+            }
+
+            result
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sleeps_overlapping_another_only_wait_for_it_to_end() {
+        let start = Instant::now();
+        let mut state = SocketState::default();
+        assert_eq!(state.coalesce(1_000, start), 1_000);
+        assert_eq!(
+            state.delayed_until,
+            Some(start + Duration::from_micros(1_000))
+        );
+
+        // Operations during the first sleep wait for what's left of it, and don't extend it.
+        assert_eq!(
+            state.coalesce(1_000, start + Duration::from_micros(400)),
+            600
+        );
+        assert_eq!(state.coalesce(100, start + Duration::from_micros(400)), 100);
+        assert_eq!(
+            state.delayed_until,
+            Some(start + Duration::from_micros(1_000))
+        );
+    }
+
+    #[test]
+    fn sleeps_after_another_ended_wait_in_full() {
+        let start = Instant::now();
+        let mut state = SocketState::default();
+        state.coalesce(1_000, start);
+        let later = start + Duration::from_micros(1_000);
+        assert_eq!(state.coalesce(500, later), 500);
+        assert_eq!(
+            state.delayed_until,
+            Some(later + Duration::from_micros(500))
+        );
+    }
+}
@@ -0,0 +1,276 @@
+use std::fmt;
+use std::net::IpAddr;
+
+use libc::c_uint;
+
+use crate::cidr::Cidr;
+use crate::util::VsockAddr;
+
+/// An entry in `PRELOAD_LATENCY_HOSTS` or the hosts file, parsed into what it matches.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum HostRule {
+    /// A host name, matching `getaddrinfo` calls for exactly that name.
+    Name(String),
+    /// An IP address literal, matching `getaddrinfo` calls for any spelling of that address and
+    /// sockets connected to it.
+    Ip(IpAddr),
+    /// A network like `10.0.0.0/8`, matching `getaddrinfo` calls for addresses inside it and
+    /// sockets connected to them. Networks in `PRELOAD_LATENCY_CIDRS` may have their own latency.
+    Cidr { cidr: Cidr, millis: Option<c_uint> },
+    /// `vsock:cid[:port]`, matching vsock sockets connected to that CID and port, or to any port
+    /// if there's none.
+    Vsock { cid: u32, port: Option<u32> },
+    /// `unix:path`, matching unix sockets connected to that path. Abstract names start with `@`.
+    Unix(String),
+}
+
+/// What a socket is connected to, or the host it's being resolved for, to match against
+/// [`HostRule`]s.
+#[derive(Clone, Copy, Debug)]
+pub enum Destination<'a> {
+    Host(&'a str),
+    Ip(IpAddr),
+    Vsock(VsockAddr),
+    Unix(&'a str),
+}
+
+impl HostRule {
+    /// Parse a single entry. Anything that isn't a well-formed address is a host name.
+    pub fn parse(entry: &str) -> Self {
+        if let Some(vsock) = entry.strip_prefix("vsock:") {
+            let (cid, port) = match vsock.split_once(':') {
+                Some((cid, port)) => (cid, Some(port)),
+                None => (vsock, None),
+            };
+            let port = port.map(str::parse).transpose();
+            if let (Ok(cid), Ok(port)) = (cid.parse(), port) {
+                return Self::Vsock { cid, port };
+            }
+        }
+        if let Some(path) = entry.strip_prefix("unix:")
+            && path.starts_with(['/', '@'])
+        {
+            return Self::Unix(path.to_owned());
+        }
+        if entry.contains('/')
+            && let Ok(cidr) = entry.parse()
+        {
+            return Self::Cidr { cidr, millis: None };
+        }
+        match entry.parse() {
+            Ok(ip) => Self::Ip(ip),
+            Err(_) => Self::Name(entry.to_owned()),
+        }
+    }
+
+    /// Parse a `cidr[=millis]` entry of `PRELOAD_LATENCY_CIDRS`.
+    pub fn parse_cidr(entry: &str) -> Result<Self, String> {
+        let (cidr, millis) = match entry.split_once('=') {
+            Some((cidr, millis)) => (cidr, Some(millis)),
+            None => (entry, None),
+        };
+        let cidr = cidr.parse()?;
+        let millis = millis
+            .map(str::parse)
+            .transpose()
+            .map_err(|_| "invalid milliseconds".to_owned())?;
+        Ok(Self::Cidr { cidr, millis })
+    }
+
+    pub fn matches(&self, destination: Destination) -> bool {
+        match (self, destination) {
+            (Self::Name(name), Destination::Host(host)) => name == host,
+            (Self::Ip(ip), Destination::Host(host)) => host.parse() == Ok(*ip),
+            (Self::Ip(ip), Destination::Ip(connected)) => {
+                ip.to_canonical() == connected.to_canonical()
+            }
+            (Self::Cidr { cidr, .. }, Destination::Host(host)) => {
+                host.parse().is_ok_and(|ip| cidr.contains(ip))
+            }
+            (Self::Cidr { cidr, .. }, Destination::Ip(ip)) => cidr.contains(ip),
+            (Self::Vsock { cid, port }, Destination::Vsock(addr)) => {
+                *cid == addr.cid && port.is_none_or(|port| port == addr.port)
+            }
+            (Self::Unix(path), Destination::Unix(connected)) => path == connected,
+            _ => false,
+        }
+    }
+
+    /// The host to resolve with `getaddrinfo` to find the addresses this rule matches, if any.
+    pub fn resolvable_host(&self) -> Option<String> {
+        match self {
+            Self::Name(name) => Some(name.clone()),
+            Self::Ip(ip) => Some(ip.to_string()),
+            Self::Cidr { .. } | Self::Vsock { .. } | Self::Unix(_) => None,
+        }
+    }
+
+    /// Latency in milliseconds for sockets connected to addresses this rule matches, instead of
+    /// the default one.
+    pub fn millis(&self) -> Option<c_uint> {
+        match self {
+            Self::Cidr { millis, .. } => *millis,
+            _ => None,
+        }
+    }
+
+    /// Length of the network's prefix, to pick the most specific network matching an address.
+    pub fn prefix_len(&self) -> Option<u8> {
+        match self {
+            Self::Cidr { cidr, .. } => Some(cidr.prefix_len()),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for HostRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Name(name) => write!(f, "{name}"),
+            Self::Ip(ip) => write!(f, "{ip}"),
+            Self::Cidr {
+                cidr,
+                millis: Some(millis),
+            } => write!(f, "{}/{}={millis}", cidr.addr(), cidr.prefix_len()),
+            Self::Cidr { cidr, millis: None } => {
+                write!(f, "{}/{}", cidr.addr(), cidr.prefix_len())
+            }
+            Self::Vsock {
+                cid,
+                port: Some(port),
+            } => write!(f, "vsock:{cid}:{port}"),
+            Self::Vsock { cid, port: None } => write!(f, "vsock:{cid}"),
+            Self::Unix(path) => write!(f, "unix:{path}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> Destination<'_> {
+        Destination::Ip(s.parse().unwrap())
+    }
+
+    fn vsock(cid: u32, port: u32) -> Destination<'static> {
+        Destination::Vsock(VsockAddr { cid, port })
+    }
+
+    #[test]
+    fn names_match_exactly() {
+        let rule = HostRule::parse("example.com");
+        assert_eq!(rule, HostRule::Name("example.com".to_owned()));
+        assert!(rule.matches(Destination::Host("example.com")));
+        assert!(!rule.matches(Destination::Host("www.example.com")));
+        assert!(!rule.matches(Destination::Host("EXAMPLE.COM")));
+        assert!(!rule.matches(Destination::Unix("example.com")));
+    }
+
+    #[test]
+    fn addresses_match_any_spelling_and_connections() {
+        let rule = HostRule::parse("::1");
+        assert_eq!(rule, HostRule::Ip("::1".parse().unwrap()));
+        assert!(rule.matches(Destination::Host("0:0::1")));
+        assert!(!rule.matches(Destination::Host("::2")));
+        assert!(rule.matches(ip("::1")));
+        assert!(!rule.matches(ip("127.0.0.1")));
+    }
+
+    #[test]
+    fn ipv4_addresses_match_ipv4_mapped_connections() {
+        let rule = HostRule::parse("10.1.2.3");
+        assert!(rule.matches(ip("::ffff:10.1.2.3")));
+        assert!(HostRule::parse("::ffff:10.1.2.3").matches(ip("10.1.2.3")));
+    }
+
+    #[test]
+    fn networks_match_addresses_inside_them() {
+        let rule = HostRule::parse("10.0.0.0/8");
+        assert_eq!(rule.prefix_len(), Some(8));
+        assert_eq!(rule.millis(), None);
+        assert!(rule.matches(ip("10.200.0.1")));
+        assert!(!rule.matches(ip("11.0.0.1")));
+        assert!(rule.matches(Destination::Host("10.1.1.1")));
+        assert!(!rule.matches(Destination::Host("ten.example.com")));
+    }
+
+    #[test]
+    fn cidr_entries_have_latencies() {
+        let rule = HostRule::parse_cidr("10.1.0.0/16=20").unwrap();
+        assert_eq!(rule.millis(), Some(20));
+        assert_eq!(rule.prefix_len(), Some(16));
+        assert_eq!(rule.to_string(), "10.1.0.0/16=20");
+        assert_eq!(HostRule::parse_cidr("fd00::/8").unwrap().millis(), None);
+        assert!(HostRule::parse_cidr("10.0.0.0/33").is_err());
+        assert!(HostRule::parse_cidr("10.0.0.0/8=fast").is_err());
+    }
+
+    #[test]
+    fn malformed_networks_are_names() {
+        assert!(matches!(HostRule::parse("10.0.0.0/33"), HostRule::Name(_)));
+    }
+
+    #[test]
+    fn vsock_rules_match_a_port_or_any() {
+        let rule = HostRule::parse("vsock:3:5000");
+        assert!(rule.matches(vsock(3, 5000)));
+        assert!(!rule.matches(vsock(3, 5001)));
+        assert!(!rule.matches(vsock(4, 5000)));
+        let rule = HostRule::parse("vsock:3");
+        assert!(rule.matches(vsock(3, 1)));
+        assert!(!rule.matches(vsock(4, 1)));
+        assert!(!rule.matches(Destination::Host("vsock:3")));
+    }
+
+    #[test]
+    fn malformed_vsock_entries_are_names() {
+        for entry in ["vsock:", "vsock:x", "vsock:3:y", "vsock:3:5:6"] {
+            assert_eq!(HostRule::parse(entry), HostRule::Name(entry.to_owned()));
+        }
+    }
+
+    #[test]
+    fn unix_rules_match_the_same_path() {
+        let rule = HostRule::parse("unix:/run/app.sock");
+        assert!(rule.matches(Destination::Unix("/run/app.sock")));
+        assert!(!rule.matches(Destination::Unix("/run/other.sock")));
+        assert!(HostRule::parse("unix:@name").matches(Destination::Unix("@name")));
+        assert_eq!(
+            HostRule::parse("unix:relative"),
+            HostRule::Name("unix:relative".to_owned())
+        );
+    }
+
+    #[test]
+    fn only_names_and_addresses_are_resolvable() {
+        assert_eq!(
+            HostRule::parse("example.com").resolvable_host().as_deref(),
+            Some("example.com")
+        );
+        assert_eq!(
+            HostRule::parse("10.0.0.1").resolvable_host().as_deref(),
+            Some("10.0.0.1")
+        );
+        assert_eq!(HostRule::parse("10.0.0.0/8").resolvable_host(), None);
+        assert_eq!(HostRule::parse("vsock:3").resolvable_host(), None);
+        assert_eq!(
+            HostRule::parse("unix:/run/app.sock").resolvable_host(),
+            None
+        );
+    }
+
+    #[test]
+    fn rules_display_as_parsed() {
+        for entry in [
+            "example.com",
+            "10.0.0.1",
+            "10.0.0.0/8",
+            "vsock:3:5000",
+            "vsock:3",
+            "unix:@x",
+        ] {
+            assert_eq!(HostRule::parse(entry).to_string(), entry);
+        }
+    }
+}
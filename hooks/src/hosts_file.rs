@@ -0,0 +1,175 @@
+use std::path::PathBuf;
+use std::sync::{OnceLock, RwLock};
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::host_rule::{Destination, HostRule};
+
+/// How often the file is checked for changes at most.
+const RELOAD_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Hosts to intercept in addition to `HookConfig::hosts`, listed one per line in a file that may
+/// be changed while the process is running.
+struct HostsFile {
+    path: PathBuf,
+    hosts: Vec<HostRule>,
+    modified: Option<SystemTime>,
+    checked_at: Instant,
+}
+
+static HOSTS_FILE: OnceLock<RwLock<HostsFile>> = OnceLock::new();
+
+pub fn init(path: PathBuf) {
+    tracing::info!("Loading hosts from {}", path.display());
+    HOSTS_FILE.get_or_init(|| RwLock::new(HostsFile::new(path)));
+}
+
+/// Whether `destination` is matched by a rule in the hosts file as of the last reload.
+pub fn matches(destination: Destination) -> bool {
+    HOSTS_FILE
+        .get()
+        .and_then(|hosts_file| hosts_file.read().ok())
+        .is_some_and(|hosts_file| {
+            hosts_file
+                .hosts
+                .iter()
+                .any(|rule| rule.matches(destination))
+        })
+}
+
+/// Re-read the hosts file if it hasn't been checked in a while and was modified since. Returns
+/// whether the set of hosts changed.
+pub fn maybe_reload() -> bool {
+    let Some(hosts_file_lock) = HOSTS_FILE.get() else {
+        return false;
+    };
+    match hosts_file_lock.read() {
+        Ok(hosts_file) if hosts_file.checked_at.elapsed() < RELOAD_INTERVAL => return false,
+        Ok(_) => {}
+        _ => {
+            tracing::warn!("Failed to access hosts file state");
+            return false;
+        }
+    }
+
+    let Ok(mut hosts_file) = hosts_file_lock.write() else {
+        tracing::warn!("Failed to access hosts file state");
+        return false;
+    };
+    // Another thread may have reloaded while we were waiting for the lock.
+    if hosts_file.checked_at.elapsed() < RELOAD_INTERVAL {
+        return false;
+    }
+    hosts_file.reload()
+}
+
+impl HostsFile {
+    fn new(path: PathBuf) -> Self {
+        let mut hosts_file = Self {
+            path,
+            hosts: Vec::new(),
+            modified: None,
+            checked_at: Instant::now(),
+        };
+        hosts_file.reload();
+        hosts_file
+    }
+
+    /// Re-read the file if it was modified since the last reload. If it can't be read, the
+    /// previous hosts are kept. Returns whether the set of hosts changed.
+    fn reload(&mut self) -> bool {
+        self.checked_at = Instant::now();
+        let read = std::fs::metadata(&self.path)
+            .and_then(|metadata| metadata.modified())
+            .and_then(|modified| {
+                if self.modified == Some(modified) {
+                    Ok(None)
+                } else {
+                    Ok(Some((modified, std::fs::read_to_string(&self.path)?)))
+                }
+            });
+        let (modified, contents) = match read {
+            Ok(Some(read)) => read,
+            Ok(None) => return false,
+            Err(e) => {
+                tracing::warn!("Failed to read hosts file {}: {e}", self.path.display());
+                return false;
+            }
+        };
+        self.modified = Some(modified);
+
+        // One host per line. Blank lines and lines starting with `#` are ignored.
+        let hosts: Vec<HostRule> = contents
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(HostRule::parse)
+            .collect();
+        if hosts == self.hosts {
+            return false;
+        }
+        tracing::info!("Loaded {} hosts from {}", hosts.len(), self.path.display());
+        self.hosts = hosts;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+    use std::path::Path;
+
+    use super::*;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("preload_latency-{}-{name}", std::process::id()))
+    }
+
+    fn write(path: &Path, contents: &str, modified: SystemTime) {
+        std::fs::write(path, contents).unwrap();
+        File::options()
+            .write(true)
+            .open(path)
+            .unwrap()
+            .set_modified(modified)
+            .unwrap();
+    }
+
+    #[test]
+    fn hosts_are_reloaded_when_the_file_is_modified() {
+        let path = temp_path("hosts-modified");
+        let modified = SystemTime::now();
+        write(&path, "# hosts\nexample.com\n\n10.0.0.0/8\n", modified);
+        let mut hosts_file = HostsFile::new(path.clone());
+        assert_eq!(
+            hosts_file.hosts,
+            [
+                HostRule::Name("example.com".to_owned()),
+                HostRule::parse("10.0.0.0/8")
+            ]
+        );
+
+        // Nothing is read while the modification time stays the same.
+        write(&path, "example.org\n", modified);
+        assert!(!hosts_file.reload());
+        assert_eq!(hosts_file.hosts.len(), 2);
+
+        write(&path, "example.org\n", modified + Duration::from_secs(1));
+        assert!(hosts_file.reload());
+        assert_eq!(hosts_file.hosts, [HostRule::Name("example.org".to_owned())]);
+
+        // Touching the file without changing the hosts isn't a change.
+        write(&path, "example.org\n", modified + Duration::from_secs(2));
+        assert!(!hosts_file.reload());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn hosts_are_kept_when_the_file_cant_be_read() {
+        let path = temp_path("hosts-unreadable");
+        write(&path, "example.com\n", SystemTime::now());
+        let mut hosts_file = HostsFile::new(path.clone());
+        std::fs::remove_file(&path).unwrap();
+        assert!(!hosts_file.reload());
+        assert_eq!(hosts_file.hosts, [HostRule::Name("example.com".to_owned())]);
+    }
+}
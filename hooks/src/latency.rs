@@ -0,0 +1,290 @@
+use std::str::FromStr;
+
+use libc::c_uint;
+
+/// A latency distribution given as points of its cumulative distribution function, e.g. measured
+/// percentiles. Sampled by interpolating linearly between the points. Below the first point the
+/// latency grows linearly from zero, and above the last one it's the last point's latency.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Cdf {
+    /// Cumulative probabilities between 0 and 1 with their latencies in milliseconds, both
+    /// increasing.
+    points: Vec<(f64, f64)>,
+}
+
+impl Cdf {
+    /// `points` must be sorted by probability and their latencies mustn't decrease.
+    pub fn new(points: Vec<(f64, f64)>) -> Result<Self, String> {
+        if points.is_empty() {
+            return Err("expected at least one point".to_owned());
+        }
+        for pair in points.windows(2) {
+            let ((p0, millis0), (p1, millis1)) = (pair[0], pair[1]);
+            if p1 <= p0 {
+                return Err(format!("probability {p1} must be greater than {p0}"));
+            }
+            if millis1 < millis0 {
+                return Err(format!(
+                    "latency {millis1} at {p1} must be at least the {millis0} at {p0}"
+                ));
+            }
+        }
+        Ok(Self { points })
+    }
+
+    /// The latency in microseconds at cumulative probability `u`, which is a sample of the
+    /// distribution if `u` is uniformly random between 0 and 1.
+    pub fn sample_micros(&self, u: f64) -> c_uint {
+        let mut previous = (0.0, 0.0);
+        for &(p, millis) in &self.points {
+            if u <= p {
+                let (p0, millis0) = previous;
+                let millis = if p > p0 {
+                    millis0 + (millis - millis0) * (u - p0) / (p - p0)
+                } else {
+                    millis
+                };
+                return (millis * 1000.0) as c_uint;
+            }
+            previous = (p, millis);
+        }
+        (previous.1 * 1000.0) as c_uint
+    }
+}
+
+/// A range of call sizes in bytes with the latency for calls in it, e.g. `1025-65536=50`. The upper
+/// bound may be left out, as in `65537-=200`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SizeBucket {
+    min: usize,
+    max: Option<usize>,
+    millis: c_uint,
+}
+
+impl SizeBucket {
+    /// Whether a call of `len` bytes falls into this bucket. Both bounds are inclusive.
+    pub fn contains(&self, len: usize) -> bool {
+        len >= self.min && self.max.is_none_or(|max| len <= max)
+    }
+
+    pub fn millis(&self) -> c_uint {
+        self.millis
+    }
+}
+
+impl FromStr for SizeBucket {
+    type Err = String;
+
+    /// Parse `min-max=millis` or `min-=millis`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || "expected `min-max=millis` or `min-=millis`".to_owned();
+        let (range, millis) = s.split_once('=').ok_or_else(invalid)?;
+        let (min, max) = range.split_once('-').ok_or_else(invalid)?;
+        let min = min.parse().map_err(|_| invalid())?;
+        let max = match max {
+            "" => None,
+            max => Some(max.parse().map_err(|_| invalid())?),
+        };
+        if max.is_some_and(|max| max < min) {
+            return Err(format!("range `{range}` is empty"));
+        }
+        let millis = millis.parse().map_err(|_| invalid())?;
+        Ok(Self { min, max, millis })
+    }
+}
+
+/// Latency multipliers for each hour of the local day, e.g. to make peak hours slower. Hours that
+/// aren't covered by any range keep a multiplier of 1.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TodProfile {
+    multipliers: [f64; 24],
+}
+
+impl TodProfile {
+    /// Later ranges override earlier ones where they overlap.
+    pub fn new(ranges: &[HourRange]) -> Self {
+        let mut multipliers = [1.0; 24];
+        for range in ranges {
+            let mut hour = range.first;
+            loop {
+                multipliers[hour] = range.multiplier;
+                if hour == range.last {
+                    break;
+                }
+                hour = (hour + 1) % 24;
+            }
+        }
+        Self { multipliers }
+    }
+
+    /// The multiplier for `hour`, between 0 and 23.
+    pub fn multiplier(&self, hour: usize) -> f64 {
+        self.multipliers.get(hour).copied().unwrap_or(1.0)
+    }
+}
+
+/// A range of hours of the day with a latency multiplier, e.g. `8-18=2`. A single hour like
+/// `12=3` is a range too, and ranges like `22-6` wrap around midnight.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct HourRange {
+    first: usize,
+    last: usize,
+    multiplier: f64,
+}
+
+impl FromStr for HourRange {
+    type Err = String;
+
+    /// Parse `first-last=multiplier` or `hour=multiplier`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || "expected `first-last=multiplier` or `hour=multiplier`".to_owned();
+        let (hours, multiplier) = s.split_once('=').ok_or_else(invalid)?;
+        let (first, last) = hours.split_once('-').unwrap_or((hours, hours));
+        let hour = |hour: &str| match hour.parse() {
+            Ok(hour) if hour < 24 => Ok(hour),
+            _ => Err(format!("hour `{hour}` must be between 0 and 23")),
+        };
+        let (first, last) = (hour(first)?, hour(last)?);
+        let multiplier = match multiplier.parse() {
+            Ok(multiplier) if multiplier >= 0.0 && f64::is_finite(multiplier) => multiplier,
+            _ => {
+                return Err(format!(
+                    "multiplier `{multiplier}` must be a non-negative number"
+                ));
+            }
+        };
+        Ok(Self {
+            first,
+            last,
+            multiplier,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cdf_needs_a_point() {
+        assert!(Cdf::new(Vec::new()).is_err());
+    }
+
+    #[test]
+    fn cdf_points_must_increase() {
+        assert!(Cdf::new(vec![(0.5, 10.0), (0.5, 20.0)]).is_err());
+        assert!(Cdf::new(vec![(0.5, 10.0), (0.4, 20.0)]).is_err());
+        assert!(Cdf::new(vec![(0.5, 20.0), (0.9, 10.0)]).is_err());
+        assert!(Cdf::new(vec![(0.5, 10.0), (0.9, 10.0)]).is_ok());
+    }
+
+    #[test]
+    fn cdf_interpolates_between_points() {
+        let cdf = Cdf::new(vec![(0.5, 10.0), (0.75, 50.0), (1.0, 100.0)]).unwrap();
+        assert_eq!(cdf.sample_micros(0.5), 10_000);
+        assert_eq!(cdf.sample_micros(0.625), 30_000);
+        assert_eq!(cdf.sample_micros(0.75), 50_000);
+        assert_eq!(cdf.sample_micros(0.875), 75_000);
+        assert_eq!(cdf.sample_micros(1.0), 100_000);
+    }
+
+    #[test]
+    fn cdf_grows_from_zero_below_the_first_point() {
+        let cdf = Cdf::new(vec![(0.5, 10.0), (1.0, 20.0)]).unwrap();
+        assert_eq!(cdf.sample_micros(0.0), 0);
+        assert_eq!(cdf.sample_micros(0.25), 5_000);
+    }
+
+    #[test]
+    fn cdf_stays_at_the_last_point_above_it() {
+        let cdf = Cdf::new(vec![(0.5, 10.0), (0.9, 20.0)]).unwrap();
+        assert_eq!(cdf.sample_micros(0.95), 20_000);
+        assert_eq!(cdf.sample_micros(1.0), 20_000);
+    }
+
+    #[test]
+    fn cdf_with_a_single_point_at_zero() {
+        let cdf = Cdf::new(vec![(0.0, 10.0)]).unwrap();
+        assert_eq!(cdf.sample_micros(0.0), 10_000);
+        assert_eq!(cdf.sample_micros(0.5), 10_000);
+    }
+
+    #[test]
+    fn size_bucket_bounds_are_inclusive() {
+        let bucket: SizeBucket = "1025-65536=50".parse().unwrap();
+        assert!(!bucket.contains(1024));
+        assert!(bucket.contains(1025));
+        assert!(bucket.contains(65536));
+        assert!(!bucket.contains(65537));
+        assert_eq!(bucket.millis(), 50);
+    }
+
+    #[test]
+    fn size_bucket_without_upper_bound() {
+        let bucket: SizeBucket = "65537-=200".parse().unwrap();
+        assert!(!bucket.contains(65536));
+        assert!(bucket.contains(65537));
+        assert!(bucket.contains(usize::MAX));
+    }
+
+    #[test]
+    fn size_bucket_of_a_single_size() {
+        let bucket: SizeBucket = "0-0=5".parse().unwrap();
+        assert!(bucket.contains(0));
+        assert!(!bucket.contains(1));
+    }
+
+    #[test]
+    fn size_bucket_rejects_malformed_entries() {
+        for entry in ["", "1-2", "1=5", "-2=5", "a-2=5", "1-b=5", "1-2=x", "3-2=5"] {
+            assert!(entry.parse::<SizeBucket>().is_err(), "{entry}");
+        }
+    }
+
+    fn profile(ranges: &[&str]) -> TodProfile {
+        let ranges: Vec<HourRange> = ranges.iter().map(|range| range.parse().unwrap()).collect();
+        TodProfile::new(&ranges)
+    }
+
+    #[test]
+    fn tod_profile_covers_ranges_inclusively() {
+        let profile = profile(&["8-18=2"]);
+        assert_eq!(profile.multiplier(7), 1.0);
+        assert_eq!(profile.multiplier(8), 2.0);
+        assert_eq!(profile.multiplier(18), 2.0);
+        assert_eq!(profile.multiplier(19), 1.0);
+    }
+
+    #[test]
+    fn tod_profile_ranges_wrap_around_midnight() {
+        let profile = profile(&["22-1=3"]);
+        assert_eq!(profile.multiplier(21), 1.0);
+        assert_eq!(profile.multiplier(22), 3.0);
+        assert_eq!(profile.multiplier(23), 3.0);
+        assert_eq!(profile.multiplier(0), 3.0);
+        assert_eq!(profile.multiplier(1), 3.0);
+        assert_eq!(profile.multiplier(2), 1.0);
+    }
+
+    #[test]
+    fn tod_profile_later_ranges_override_earlier_ones() {
+        let profile = profile(&["0-23=2", "12=0.5"]);
+        assert_eq!(profile.multiplier(11), 2.0);
+        assert_eq!(profile.multiplier(12), 0.5);
+        assert_eq!(profile.multiplier(13), 2.0);
+    }
+
+    #[test]
+    fn tod_profile_hours_out_of_range_keep_the_default() {
+        assert_eq!(profile(&["0-23=2"]).multiplier(24), 1.0);
+    }
+
+    #[test]
+    fn hour_range_rejects_malformed_entries() {
+        for entry in [
+            "", "8-18", "24=2", "8-24=2", "x=2", "8=-1", "8=inf", "8=NaN",
+        ] {
+            assert!(entry.parse::<HourRange>().is_err(), "{entry}");
+        }
+    }
+}
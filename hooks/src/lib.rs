@@ -1,7 +1,36 @@
+// Without every family of hooks, some of the shared machinery goes unused.
+#![cfg_attr(
+    not(all(
+        feature = "hooks-dns",
+        feature = "hooks-data",
+        feature = "hooks-lifecycle"
+    )),
+    allow(dead_code, unused_imports, unused_macros)
+)]
+
+mod asn;
+mod cidr;
 mod config;
+mod dynamic;
+#[cfg(target_os = "linux")]
+mod epoll;
+#[cfg(feature = "json")]
+mod events;
 mod hooks;
+mod host_rule;
+mod hosts_file;
+mod latency;
+mod matcher;
+mod payload;
+mod rate;
+#[cfg(feature = "json")]
+mod snapshot;
+mod stats;
+mod syslog;
+mod tls;
 mod toggle;
 mod util;
+mod vars;
 
 pub use hooks::*;
 
@@ -0,0 +1,85 @@
+use std::ffi::CString;
+use std::net::IpAddr;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::sync::OnceLock;
+
+use libc::c_char;
+
+/// `bool should_delay(const char* host, const char* ip)`, where `host` is null if the address
+/// wasn't resolved through `getaddrinfo`.
+type ShouldDelay = unsafe extern "C" fn(host: *const c_char, ip: *const c_char) -> bool;
+
+/// The matching function loaded from the shared object, if any.
+static SHOULD_DELAY: OnceLock<ShouldDelay> = OnceLock::new();
+
+/// Load `should_delay` from the shared object at `path`. If it can't be loaded, the usual host
+/// matching applies.
+pub fn init(path: &Path) {
+    tracing::info!("Loading matching function from {}", path.display());
+    let Ok(c_path) = CString::new(path.as_os_str().as_bytes()) else {
+        tracing::warn!("Invalid shared object path {}", path.display());
+        return;
+    };
+    unsafe {
+        let handle = libc::dlopen(c_path.as_ptr(), libc::RTLD_NOW | libc::RTLD_LOCAL);
+        if handle.is_null() {
+            tracing::warn!("Failed to load {}: {}", path.display(), dlerror());
+            return;
+        }
+        let symbol = libc::dlsym(handle, c"should_delay".as_ptr());
+        if symbol.is_null() {
+            tracing::warn!("{} doesn't export `should_delay`", path.display());
+            libc::dlclose(handle);
+            return;
+        }
+        // The handle is never closed, so the function stays valid.
+        SHOULD_DELAY.get_or_init(|| std::mem::transmute::<*mut libc::c_void, ShouldDelay>(symbol));
+    }
+}
+
+/// Whether a matching function was loaded.
+pub fn is_loaded() -> bool {
+    SHOULD_DELAY.get().is_some()
+}
+
+/// Whether the loaded function wants sockets connected to `ip`, resolved for `host` if known, to
+/// be intercepted. `None` if no function was loaded.
+pub fn should_delay(host: Option<&str>, ip: IpAddr) -> Option<bool> {
+    let should_delay = SHOULD_DELAY.get()?;
+    let host = host.and_then(|host| CString::new(host).ok());
+    let ip = CString::new(ip.to_string()).ok()?;
+    let host_ptr = host.as_ref().map_or(std::ptr::null(), |host| host.as_ptr());
+    Some(unsafe { should_delay(host_ptr, ip.as_ptr()) })
+}
+
+fn dlerror() -> String {
+    unsafe {
+        let error = libc::dlerror();
+        if error.is_null() {
+            "unknown error".to_owned()
+        } else {
+            std::ffi::CStr::from_ptr(error)
+                .to_string_lossy()
+                .into_owned()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nothing_is_loaded_without_a_matching_function() {
+        init(Path::new("/nonexistent/libmatcher.so"));
+        assert!(!is_loaded());
+        // Already loaded into every process, and doesn't export `should_delay`.
+        init(Path::new("libc.so.6"));
+        assert!(!is_loaded());
+        assert_eq!(
+            should_delay(Some("example.com"), [127, 0, 0, 1].into()),
+            None
+        );
+    }
+}
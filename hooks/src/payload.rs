@@ -0,0 +1,268 @@
+use libc::{c_int, c_void, iovec, msghdr, size_t};
+
+/// Maximum number of buffers in a scatter/gather call. Both Linux and macOS reject calls with more,
+/// so their buffers aren't looked at.
+const IOV_MAX: c_int = 1024;
+
+/// The bytes passed to a send-like call. Nothing is copied; the pointers are only read for the
+/// duration of the call they came from.
+#[derive(Clone, Copy)]
+pub enum Payload {
+    /// A single buffer, as passed to `send`, `sendto` or `write`.
+    Buf { buf: *const c_void, len: size_t },
+    /// An array of buffers, as passed to `writev` or in the `msghdr` passed to `sendmsg`.
+    Iovecs { iov: *const iovec, count: c_int },
+    /// Bytes moved by `splice` from a pipe, which can't be looked at.
+    Pipe { len: size_t },
+}
+
+impl Payload {
+    /// The buffers of `msg`, which mustn't be null.
+    pub unsafe fn from_msghdr(msg: *const msghdr) -> Self {
+        let (iov, iovlen) = unsafe { ((*msg).msg_iov, (*msg).msg_iovlen) };
+        Payload::Iovecs {
+            iov,
+            count: iovlen.try_into().unwrap_or(c_int::MAX),
+        }
+    }
+
+    /// The total number of bytes in the payload, saturating at `usize::MAX`.
+    pub unsafe fn len(&self) -> usize {
+        if let Payload::Pipe { len } = *self {
+            return len;
+        }
+        unsafe { self.segments() }
+            .fold(0usize, |total, segment| total.saturating_add(segment.len()))
+    }
+
+    /// Whether the payload starts with `prefix`. A prefix may span several buffers. Only the first
+    /// `prefix.len()` bytes are ever looked at. Bytes in a pipe never match a non-empty prefix.
+    pub unsafe fn starts_with(&self, prefix: &[u8]) -> bool {
+        segments_start_with(unsafe { self.segments() }, prefix)
+    }
+
+    /// A copy of up to the first `max` bytes of the payload. Bytes in a pipe can't be copied.
+    pub unsafe fn prefix(&self, max: usize) -> Vec<u8> {
+        let mut prefix = Vec::new();
+        for segment in unsafe { self.segments() } {
+            let n = segment.len().min(max - prefix.len());
+            prefix.extend_from_slice(&segment[..n]);
+            if prefix.len() == max {
+                break;
+            }
+        }
+        prefix
+    }
+
+    /// Whether `needle` occurs anywhere in the first `max` bytes of the payload, possibly spanning
+    /// several buffers. The buffers are searched in place. Bytes in a pipe never contain a non-empty
+    /// needle.
+    pub unsafe fn contains(&self, needle: &[u8], max: usize) -> bool {
+        if needle.is_empty() {
+            return true;
+        }
+        let mut segments = unsafe { self.segments() };
+        // Number of bytes in the segments before `segment`.
+        let mut offset = 0;
+        while let Some(segment) = segments.next() {
+            let segment = &segment[..segment.len().min(max.saturating_sub(offset))];
+            if segment.windows(needle.len()).any(|window| window == needle) {
+                return true;
+            }
+            // A match spanning buffers starts in the last `needle.len() - 1` bytes of this one.
+            let spanning = segment.len().saturating_sub(needle.len() - 1);
+            for start in spanning..segment.len() {
+                if offset + start + needle.len() > max {
+                    return false;
+                }
+                let rest = std::iter::once(&segment[start..]).chain(segments.clone());
+                if segments_start_with(rest, needle) {
+                    return true;
+                }
+            }
+            offset += segment.len();
+        }
+        false
+    }
+
+    /// The buffers making up the payload. Null buffers are treated as empty.
+    unsafe fn segments(&self) -> impl Iterator<Item = &[u8]> + Clone {
+        let (buf, iovecs) = match *self {
+            Payload::Buf { buf, len } => (Some(unsafe { slice(buf, len) }), &[][..]),
+            Payload::Iovecs { iov, count } if !iov.is_null() && (1..=IOV_MAX).contains(&count) => {
+                (None, unsafe {
+                    std::slice::from_raw_parts(iov, count as usize)
+                })
+            }
+            Payload::Iovecs { .. } | Payload::Pipe { .. } => (None, &[][..]),
+        };
+        buf.into_iter().chain(
+            iovecs
+                .iter()
+                .map(|iov| unsafe { slice(iov.iov_base, iov.iov_len) }),
+        )
+    }
+}
+
+/// Whether the bytes of `segments`, taken in order, start with `prefix`.
+fn segments_start_with<'a>(segments: impl Iterator<Item = &'a [u8]>, prefix: &[u8]) -> bool {
+    let mut remaining = prefix;
+    for segment in segments {
+        if remaining.is_empty() {
+            break;
+        }
+        let n = segment.len().min(remaining.len());
+        if segment[..n] != remaining[..n] {
+            return false;
+        }
+        remaining = &remaining[n..];
+    }
+    remaining.is_empty()
+}
+
+unsafe fn slice<'a>(buf: *const c_void, len: size_t) -> &'a [u8] {
+    if buf.is_null() || len == 0 {
+        &[]
+    } else {
+        unsafe { std::slice::from_raw_parts(buf as *const u8, len) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn iovecs(buffers: &[&[u8]]) -> Vec<iovec> {
+        buffers
+            .iter()
+            .map(|buffer| iovec {
+                iov_base: buffer.as_ptr() as *mut c_void,
+                iov_len: buffer.len(),
+            })
+            .collect()
+    }
+
+    fn buf(buffer: &[u8]) -> Payload {
+        Payload::Buf {
+            buf: buffer.as_ptr().cast(),
+            len: buffer.len(),
+        }
+    }
+
+    fn spread(iov: &[iovec]) -> Payload {
+        Payload::Iovecs {
+            iov: iov.as_ptr(),
+            count: iov.len() as c_int,
+        }
+    }
+
+    #[test]
+    fn len_adds_up_buffers() {
+        let iov = iovecs(&[b"ab", b"", b"cde"]);
+        unsafe {
+            assert_eq!(buf(b"hello").len(), 5);
+            assert_eq!(spread(&iov).len(), 5);
+            assert_eq!(Payload::Pipe { len: 7 }.len(), 7);
+        }
+    }
+
+    #[test]
+    fn null_and_oversized_iovecs_are_empty() {
+        let iov = iovecs(&[b"ab"]);
+        let null = Payload::Iovecs {
+            iov: std::ptr::null(),
+            count: 1,
+        };
+        let too_many = Payload::Iovecs {
+            iov: iov.as_ptr(),
+            count: IOV_MAX + 1,
+        };
+        let null_buf = Payload::Buf {
+            buf: std::ptr::null(),
+            len: 10,
+        };
+        unsafe {
+            assert_eq!(null.len(), 0);
+            assert_eq!(too_many.len(), 0);
+            assert_eq!(null_buf.len(), 0);
+        }
+    }
+
+    #[test]
+    fn starts_with_a_prefix_spanning_buffers() {
+        let iov = iovecs(&[b"GE", b"", b"T /", b"index"]);
+        unsafe {
+            assert!(spread(&iov).starts_with(b"GET /"));
+            assert!(spread(&iov).starts_with(b"G"));
+            assert!(spread(&iov).starts_with(b""));
+            assert!(!spread(&iov).starts_with(b"GET /x"));
+            assert!(!spread(&iov).starts_with(b"POST"));
+        }
+    }
+
+    #[test]
+    fn shorter_payloads_dont_start_with_longer_prefixes() {
+        unsafe {
+            assert!(!buf(b"GE").starts_with(b"GET"));
+            assert!(!Payload::Pipe { len: 100 }.starts_with(b"GET"));
+            assert!(Payload::Pipe { len: 100 }.starts_with(b""));
+        }
+    }
+
+    #[test]
+    fn prefix_copies_across_buffers() {
+        let iov = iovecs(&[b"ab", b"", b"cde", b"f"]);
+        unsafe {
+            assert_eq!(spread(&iov).prefix(4), b"abcd");
+            assert_eq!(spread(&iov).prefix(100), b"abcdef");
+            assert_eq!(spread(&iov).prefix(0), b"");
+            assert_eq!(Payload::Pipe { len: 10 }.prefix(4), b"");
+        }
+    }
+
+    #[test]
+    fn contains_a_needle_spanning_buffers() {
+        let iov = iovecs(&[b"POST /ap", b"", b"i/v1 HTTP"]);
+        unsafe {
+            assert!(spread(&iov).contains(b"/api/", 100));
+            assert!(spread(&iov).contains(b"HTTP", 100));
+            assert!(!spread(&iov).contains(b"/v2", 100));
+        }
+        // Every split of the haystack finds the same needles.
+        let haystack = b"GET /index.html HTTP/1.1";
+        for i in 0..=haystack.len() {
+            for j in i..=haystack.len() {
+                let iov = iovecs(&[&haystack[..i], &haystack[i..j], &haystack[j..]]);
+                unsafe {
+                    assert!(spread(&iov).contains(b"/index.html", 100));
+                    assert!(spread(&iov).contains(b"GET ", 100));
+                    assert!(spread(&iov).contains(b"1.1", 100));
+                    assert!(!spread(&iov).contains(b"HTTP/2", 100));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn contains_only_looks_at_the_first_bytes() {
+        unsafe {
+            assert!(buf(b"abcdef").contains(b"cd", 4));
+            assert!(!buf(b"abcdef").contains(b"de", 4));
+            assert!(!buf(b"ab").contains(b"abc", 100));
+            let iov = iovecs(&[b"ab", b"cd", b"ef"]);
+            assert!(spread(&iov).contains(b"bcd", 4));
+            assert!(!spread(&iov).contains(b"cde", 4));
+            assert!(!spread(&iov).contains(b"de", 4));
+            assert!(!spread(&iov).contains(b"a", 0));
+        }
+    }
+
+    #[test]
+    fn everything_contains_an_empty_needle() {
+        unsafe {
+            assert!(buf(b"").contains(b"", 100));
+            assert!(Payload::Pipe { len: 10 }.contains(b"", 100));
+            assert!(!Payload::Pipe { len: 10 }.contains(b"x", 100));
+        }
+    }
+}
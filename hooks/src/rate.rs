@@ -0,0 +1,152 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// Tracks the times of recent operations to tell whether more than `limit` happened within the
+/// last `window`.
+pub struct SlidingWindow {
+    timestamps: VecDeque<Instant>,
+    limit: usize,
+    window: Duration,
+}
+
+impl SlidingWindow {
+    pub fn new(limit: usize, window: Duration) -> Self {
+        Self {
+            timestamps: VecDeque::with_capacity(limit + 1),
+            limit,
+            window,
+        }
+    }
+
+    /// Record an operation at `now`. Returns whether it pushed the number of operations in the
+    /// window over the limit.
+    pub fn record(&mut self, now: Instant) -> bool {
+        while self
+            .timestamps
+            .front()
+            .is_some_and(|oldest| now.duration_since(*oldest) >= self.window)
+        {
+            self.timestamps.pop_front();
+        }
+
+        let exceeded = self.timestamps.len() >= self.limit;
+        // We only ever need to know about `limit` operations, so drop the oldest one rather than
+        // growing.
+        if exceeded {
+            self.timestamps.pop_front();
+        }
+        self.timestamps.push_back(now);
+        exceeded
+    }
+}
+
+/// A token bucket refilled at `rate` bytes per second. Taking more tokens than are available
+/// leaves the bucket in debt, so concurrent users queue up behind each other like on a single
+/// link.
+pub struct TokenBucket {
+    rate: f64,
+    capacity: f64,
+    tokens: f64,
+    updated_at: Instant,
+}
+
+impl TokenBucket {
+    /// How long the bucket can be idle before it stops filling up, i.e. how large of a burst goes
+    /// through without waiting.
+    const BURST: Duration = Duration::from_millis(100);
+
+    pub fn new(rate: u64, now: Instant) -> Self {
+        Self {
+            rate: rate as f64,
+            capacity: rate as f64 * Self::BURST.as_secs_f64(),
+            tokens: 0.0,
+            updated_at: now,
+        }
+    }
+
+    /// Take `bytes` tokens at `now`. Returns how long to wait until they would have been
+    /// available.
+    pub fn take(&mut self, bytes: usize, now: Instant) -> Duration {
+        let refilled = now.saturating_duration_since(self.updated_at).as_secs_f64() * self.rate;
+        self.tokens = (self.tokens + refilled).min(self.capacity) - bytes as f64;
+        self.updated_at = now;
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.rate)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sliding_window_allows_up_to_the_limit() {
+        let start = Instant::now();
+        let mut window = SlidingWindow::new(2, Duration::from_secs(1));
+        assert!(!window.record(start));
+        assert!(!window.record(start + Duration::from_millis(100)));
+        assert!(window.record(start + Duration::from_millis(200)));
+    }
+
+    #[test]
+    fn sliding_window_forgets_operations_outside_it() {
+        let start = Instant::now();
+        let mut window = SlidingWindow::new(2, Duration::from_secs(1));
+        window.record(start);
+        window.record(start + Duration::from_millis(500));
+        // The first operation is exactly one window old, so it no longer counts.
+        assert!(!window.record(start + Duration::from_secs(1)));
+        assert!(window.record(start + Duration::from_millis(1100)));
+    }
+
+    #[test]
+    fn sliding_window_keeps_exceeding_while_busy() {
+        let start = Instant::now();
+        let mut window = SlidingWindow::new(1, Duration::from_secs(1));
+        assert!(!window.record(start));
+        for millis in [100, 200, 300] {
+            assert!(window.record(start + Duration::from_millis(millis)));
+        }
+        assert!(!window.record(start + Duration::from_millis(1300)));
+    }
+
+    #[test]
+    fn token_bucket_makes_an_empty_bucket_wait() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(1000, start);
+        assert_eq!(bucket.take(500, start), Duration::from_millis(500));
+    }
+
+    #[test]
+    fn token_bucket_queues_users_behind_each_other() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(1000, start);
+        assert_eq!(bucket.take(100, start), Duration::from_millis(100));
+        assert_eq!(bucket.take(100, start), Duration::from_millis(200));
+        // Paying off the debt takes as long as the first wait.
+        assert_eq!(
+            bucket.take(100, start + Duration::from_millis(200)),
+            Duration::from_millis(100)
+        );
+    }
+
+    #[test]
+    fn token_bucket_refills_up_to_a_burst() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(1000, start);
+        let later = start + Duration::from_secs(10);
+        // Only 100 ms worth of tokens accumulate, however long the bucket was idle.
+        assert_eq!(bucket.take(100, later), Duration::ZERO);
+        assert_eq!(bucket.take(100, later), Duration::from_millis(100));
+    }
+
+    #[test]
+    fn token_bucket_ignores_time_going_backwards() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(1000, start + Duration::from_secs(1));
+        assert_eq!(bucket.take(100, start), Duration::from_millis(100));
+    }
+}
@@ -0,0 +1,90 @@
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+/// How often the snapshot thread checks whether `SIGHUP` arrived.
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+// Set by the signal handler. The handler can't safely do anything else, since it may interrupt a
+// thread holding any of the hooks' locks.
+static REQUESTED: AtomicBool = AtomicBool::new(false);
+
+// The `SIGHUP` handler that was installed before ours, to call it too.
+static PREVIOUS: OnceLock<libc::sigaction> = OnceLock::new();
+
+/// Write the JSON returned by `snapshot` to `path` on a background thread whenever the process
+/// receives `SIGHUP`. A child created with `fork` doesn't inherit the thread, so it doesn't write
+/// snapshots.
+pub fn init(path: PathBuf, snapshot: fn() -> serde_json::Value) {
+    tracing::info!("Writing a snapshot to {} on SIGHUP", path.display());
+    let spawned = std::thread::Builder::new()
+        .name("preload_latency-snapshot".to_owned())
+        .spawn(move || {
+            loop {
+                std::thread::sleep(POLL_INTERVAL);
+                if REQUESTED.swap(false, Ordering::Relaxed) {
+                    write(&path, &snapshot());
+                }
+            }
+        });
+    if let Err(e) = spawned {
+        tracing::warn!("Failed to spawn snapshot thread: {e}");
+        return;
+    }
+    unsafe {
+        let mut action = std::mem::zeroed::<libc::sigaction>();
+        action.sa_sigaction = handle_sighup as *const () as usize;
+        action.sa_flags = libc::SA_SIGINFO | libc::SA_RESTART;
+        libc::sigemptyset(&mut action.sa_mask);
+        let mut previous = std::mem::zeroed::<libc::sigaction>();
+        if libc::sigaction(libc::SIGHUP, &action, &mut previous) != 0 {
+            let e = std::io::Error::last_os_error();
+            tracing::warn!("Failed to install SIGHUP handler: {e}");
+            return;
+        }
+        PREVIOUS.get_or_init(|| previous);
+    }
+}
+
+/// Write `snapshot` to a temporary file next to `path` and move it into place, so readers never
+/// see a partial snapshot.
+fn write(path: &Path, snapshot: &serde_json::Value) {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let written = serde_json::to_vec_pretty(snapshot)
+        .map_err(std::io::Error::from)
+        .and_then(|json| std::fs::write(&tmp, json))
+        .and_then(|()| std::fs::rename(&tmp, path));
+    match written {
+        Ok(()) => tracing::info!("Wrote snapshot to {}", path.display()),
+        Err(e) => tracing::warn!("Failed to write snapshot to {}: {e}", path.display()),
+    }
+}
+
+extern "C" fn handle_sighup(
+    signal: libc::c_int,
+    info: *mut libc::siginfo_t,
+    context: *mut libc::c_void,
+) {
+    REQUESTED.store(true, Ordering::Relaxed);
+    // Programs that handle `SIGHUP` themselves, e.g. to reload, keep doing so. The default action
+    // of terminating the process is replaced.
+    let Some(previous) = PREVIOUS.get() else {
+        return;
+    };
+    let handler = previous.sa_sigaction;
+    if handler == libc::SIG_DFL || handler == libc::SIG_IGN {
+        return;
+    }
+    unsafe {
+        if previous.sa_flags & libc::SA_SIGINFO != 0 {
+            let handler: extern "C" fn(libc::c_int, *mut libc::siginfo_t, *mut libc::c_void) =
+                std::mem::transmute(handler);
+            handler(signal, info, context);
+        } else {
+            let handler: extern "C" fn(libc::c_int) = std::mem::transmute(handler);
+            handler(signal);
+        }
+    }
+}
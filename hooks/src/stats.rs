@@ -0,0 +1,92 @@
+use std::sync::OnceLock;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::thread::Thread;
+use std::time::Duration;
+
+// Aggregate counters over all tracked sockets. Only atomics, so recording never takes a lock that
+// a `fork` could leave held in the child.
+static CALLS: AtomicU64 = AtomicU64::new(0);
+static DELAYED: AtomicU64 = AtomicU64::new(0);
+static SLEPT_MICROS: AtomicU64 = AtomicU64::new(0);
+
+// Set once the process starts exiting, so the report thread stops logging.
+static STOPPED: AtomicBool = AtomicBool::new(false);
+static REPORTER: OnceLock<Thread> = OnceLock::new();
+
+/// Count a call on a tracked socket that slept for `slept_micros` in total.
+pub fn record_call(slept_micros: u64) {
+    CALLS.fetch_add(1, Ordering::Relaxed);
+    if slept_micros > 0 {
+        DELAYED.fetch_add(1, Ordering::Relaxed);
+        record_sleep(slept_micros);
+    }
+}
+
+/// Count a sleep that isn't part of a call on a tracked socket, e.g. before a DNS lookup.
+pub fn record_sleep(slept_micros: u64) {
+    SLEPT_MICROS.fetch_add(slept_micros, Ordering::Relaxed);
+}
+
+/// Total duration in microseconds slept so far.
+pub fn slept_micros() -> u64 {
+    SLEPT_MICROS.load(Ordering::Relaxed)
+}
+
+/// The counters at one point in time.
+pub struct Counters {
+    pub calls: u64,
+    pub delayed: u64,
+    pub slept_micros: u64,
+}
+
+pub fn counters() -> Counters {
+    Counters {
+        calls: CALLS.load(Ordering::Relaxed),
+        delayed: DELAYED.load(Ordering::Relaxed),
+        slept_micros: SLEPT_MICROS.load(Ordering::Relaxed),
+    }
+}
+
+fn report() {
+    let counters = counters();
+    tracing::info!(
+        "Stats: {} calls on tracked sockets, {} delayed, {} ms slept in total",
+        counters.calls,
+        counters.delayed,
+        counters.slept_micros / 1000
+    );
+}
+
+/// Log the counters every `interval` on a background thread until the process exits. A child
+/// created with `fork` doesn't inherit the thread, so it doesn't report.
+pub fn start_reporting(interval: Duration) {
+    tracing::info!(
+        "Initializing stats; reporting every {} seconds",
+        interval.as_secs()
+    );
+    let spawned = std::thread::Builder::new()
+        .name("preload_latency-stats".to_owned())
+        .spawn(move || {
+            while !STOPPED.load(Ordering::Relaxed) {
+                // Woken early by `stop` when the process exits.
+                std::thread::park_timeout(interval);
+                if !STOPPED.load(Ordering::Relaxed) {
+                    report();
+                }
+            }
+        });
+    match spawned {
+        Ok(handle) => {
+            REPORTER.get_or_init(|| handle.thread().clone());
+            unsafe { libc::atexit(stop) };
+        }
+        Err(e) => tracing::warn!("Failed to spawn stats thread: {e}"),
+    }
+}
+
+extern "C" fn stop() {
+    STOPPED.store(true, Ordering::Relaxed);
+    if let Some(reporter) = REPORTER.get() {
+        reporter.unpark();
+    }
+}
@@ -0,0 +1,80 @@
+use std::ffi::CString;
+use std::fmt::{self, Write};
+use std::str::FromStr;
+
+use libc::c_int;
+use tracing::field::{Field, Visit};
+use tracing::{Event, Level, Subscriber};
+use tracing_subscriber::Layer;
+use tracing_subscriber::layer::Context;
+
+/// Whether logs go to syslog, read from the `PRELOAD_LATENCY_SYSLOG` environment variable.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mode {
+    /// Log to syslog in addition to stderr.
+    Also,
+    /// Only log to syslog.
+    Only,
+}
+
+impl FromStr for Mode {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "also" => Ok(Self::Also),
+            "only" => Ok(Self::Only),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Forwards every event to `syslog(3)` with a priority matching its level.
+pub struct SyslogLayer;
+
+impl SyslogLayer {
+    pub fn new() -> Self {
+        unsafe { libc::openlog(c"preload_latency".as_ptr(), libc::LOG_PID, libc::LOG_USER) };
+        Self
+    }
+}
+
+fn priority(level: Level) -> c_int {
+    match level {
+        Level::ERROR => libc::LOG_ERR,
+        Level::WARN => libc::LOG_WARNING,
+        Level::INFO => libc::LOG_INFO,
+        Level::DEBUG | Level::TRACE => libc::LOG_DEBUG,
+    }
+}
+
+/// Formats an event's message followed by its other fields as `name=value`.
+#[derive(Default)]
+struct MessageVisitor(String);
+
+impl Visit for MessageVisitor {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if !self.0.is_empty() {
+            self.0.push(' ');
+        }
+        if field.name() == "message" {
+            let _ = write!(self.0, "{value:?}");
+        } else {
+            let _ = write!(self.0, "{}={value:?}", field.name());
+        }
+    }
+}
+
+impl<S: Subscriber> Layer<S> for SyslogLayer {
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        let mut visitor = MessageVisitor::default();
+        event.record(&mut visitor);
+        // Messages can't contain NUL bytes, so there's nothing sensible to log if one does.
+        let Ok(message) = CString::new(visitor.0) else {
+            return;
+        };
+        let priority = priority(*event.metadata().level());
+        // Never pass the message as the format string, it may contain `%`.
+        unsafe { libc::syslog(priority, c"%s".as_ptr(), message.as_ptr()) };
+    }
+}
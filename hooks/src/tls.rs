@@ -0,0 +1,258 @@
+/// Largest TLS record a ClientHello can be in, including its header.
+pub const MAX_RECORD_LEN: usize = 5 + (1 << 14);
+
+/// The extensions of a TLS ClientHello, found in the first record a client sends. Only
+/// ClientHellos that fit in that record are understood.
+pub struct ClientHello<'a> {
+    extensions: &'a [u8],
+}
+
+const CONTENT_TYPE_HANDSHAKE: u8 = 22;
+const HANDSHAKE_TYPE_CLIENT_HELLO: u8 = 1;
+const EXTENSION_SERVER_NAME: u16 = 0;
+const EXTENSION_ALPN: u16 = 16;
+const NAME_TYPE_HOST_NAME: u8 = 0;
+
+impl<'a> ClientHello<'a> {
+    /// Parse the ClientHello at the start of `bytes`, if it is one.
+    pub fn parse(bytes: &'a [u8]) -> Option<Self> {
+        let mut record = Reader(bytes);
+        if record.u8()? != CONTENT_TYPE_HANDSHAKE {
+            return None;
+        }
+        record.bytes(2)?; // Legacy record version
+        let mut handshake = Reader(record.vec16()?);
+        if handshake.u8()? != HANDSHAKE_TYPE_CLIENT_HELLO {
+            return None;
+        }
+        let len = handshake.u24()?;
+        let mut hello = Reader(handshake.bytes(len)?);
+        hello.bytes(2 + 32)?; // Legacy version and random
+        hello.vec8()?; // Session ID
+        hello.vec16()?; // Cipher suites
+        hello.vec8()?; // Compression methods
+        // Extensions are optional before TLS 1.3.
+        let extensions = if hello.0.is_empty() {
+            &[]
+        } else {
+            hello.vec16()?
+        };
+        Some(Self { extensions })
+    }
+
+    /// The protocols advertised in the ALPN extension, most preferred first. Empty if there's no
+    /// ALPN extension or it's malformed.
+    pub fn alpn_protocols(&self) -> Vec<&'a [u8]> {
+        let Some(alpn) = self.extension(EXTENSION_ALPN) else {
+            return Vec::new();
+        };
+        let Some(list) = Reader(alpn).vec16() else {
+            return Vec::new();
+        };
+        let mut list = Reader(list);
+        let mut protocols = Vec::new();
+        while !list.0.is_empty() {
+            match list.vec8() {
+                Some(protocol) => protocols.push(protocol),
+                None => return Vec::new(),
+            }
+        }
+        protocols
+    }
+
+    /// The host name in the SNI extension. `None` if there's no SNI extension, it's malformed or
+    /// it doesn't name a host.
+    pub fn server_name(&self) -> Option<&'a str> {
+        let sni = self.extension(EXTENSION_SERVER_NAME)?;
+        let mut list = Reader(Reader(sni).vec16()?);
+        while !list.0.is_empty() {
+            let name_type = list.u8()?;
+            let name = list.vec16()?;
+            if name_type == NAME_TYPE_HOST_NAME {
+                return std::str::from_utf8(name).ok();
+            }
+        }
+        None
+    }
+
+    /// The data of the first extension of type `ty`.
+    fn extension(&self, ty: u16) -> Option<&'a [u8]> {
+        let mut extensions = Reader(self.extensions);
+        while !extensions.0.is_empty() {
+            let extension_ty = extensions.u16()?;
+            let data = extensions.vec16()?;
+            if extension_ty == ty {
+                return Some(data);
+            }
+        }
+        None
+    }
+}
+
+/// Reads big-endian integers and length-prefixed vectors off the front of a byte slice.
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.0.len() < len {
+            return None;
+        }
+        let (bytes, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Some(bytes)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        Some(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        let bytes = self.bytes(2)?;
+        Some(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u24(&mut self) -> Option<usize> {
+        let bytes = self.bytes(3)?;
+        Some(usize::from(bytes[0]) << 16 | usize::from(bytes[1]) << 8 | usize::from(bytes[2]))
+    }
+
+    fn vec8(&mut self) -> Option<&'a [u8]> {
+        let len = self.u8()?.into();
+        self.bytes(len)
+    }
+
+    fn vec16(&mut self) -> Option<&'a [u8]> {
+        let len = self.u16()?.into();
+        self.bytes(len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vec8(data: &[u8]) -> Vec<u8> {
+        [&[data.len() as u8][..], data].concat()
+    }
+
+    fn vec16(data: &[u8]) -> Vec<u8> {
+        [&(data.len() as u16).to_be_bytes()[..], data].concat()
+    }
+
+    /// A ClientHello record with `extensions`, or none at all if that's `None`.
+    fn client_hello(extensions: Option<&[(u16, Vec<u8>)]>) -> Vec<u8> {
+        let mut hello = vec![3, 3];
+        hello.extend([0; 32]);
+        hello.extend(vec8(&[1, 2, 3]));
+        hello.extend(vec16(&[0x13, 0x01]));
+        hello.extend(vec8(&[0]));
+        if let Some(extensions) = extensions {
+            let extensions: Vec<u8> = extensions
+                .iter()
+                .flat_map(|(ty, data)| [&ty.to_be_bytes()[..], &vec16(data)].concat())
+                .collect();
+            hello.extend(vec16(&extensions));
+        }
+        let len = (hello.len() as u32).to_be_bytes();
+        let handshake = [&[HANDSHAKE_TYPE_CLIENT_HELLO][..], &len[1..], &hello].concat();
+        [&[CONTENT_TYPE_HANDSHAKE, 3, 1][..], &vec16(&handshake)].concat()
+    }
+
+    fn alpn(protocols: &[&[u8]]) -> (u16, Vec<u8>) {
+        let list: Vec<u8> = protocols
+            .iter()
+            .flat_map(|protocol| vec8(protocol))
+            .collect();
+        (EXTENSION_ALPN, vec16(&list))
+    }
+
+    #[test]
+    fn finds_alpn_protocols_in_order() {
+        let bytes = client_hello(Some(&[(0xff01, vec![0]), alpn(&[b"h2", b"http/1.1"])]));
+        let hello = ClientHello::parse(&bytes).unwrap();
+        assert_eq!(hello.alpn_protocols(), [&b"h2"[..], b"http/1.1"]);
+    }
+
+    #[test]
+    fn no_alpn_without_the_extension() {
+        let bytes = client_hello(Some(&[(0xff01, vec![0])]));
+        assert!(
+            ClientHello::parse(&bytes)
+                .unwrap()
+                .alpn_protocols()
+                .is_empty()
+        );
+        let bytes = client_hello(None);
+        assert!(
+            ClientHello::parse(&bytes)
+                .unwrap()
+                .alpn_protocols()
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn malformed_alpn_lists_are_ignored() {
+        // The second protocol claims to be longer than what's left of the list.
+        let bytes = client_hello(Some(&[(EXTENSION_ALPN, vec16(&[2, b'h', b'2', 9, b'x']))]));
+        assert!(
+            ClientHello::parse(&bytes)
+                .unwrap()
+                .alpn_protocols()
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn rejects_anything_but_a_client_hello() {
+        let mut bytes = client_hello(None);
+        assert!(ClientHello::parse(b"GET / HTTP/1.1\r\n").is_none());
+        assert!(ClientHello::parse(&[]).is_none());
+        bytes[5] = 2; // ServerHello
+        assert!(ClientHello::parse(&bytes).is_none());
+    }
+
+    #[test]
+    fn rejects_truncated_client_hellos() {
+        let bytes = client_hello(Some(&[alpn(&[b"h2"])]));
+        for len in 0..bytes.len() {
+            assert!(ClientHello::parse(&bytes[..len]).is_none(), "{len} bytes");
+        }
+    }
+
+    fn sni(names: &[(u8, &[u8])]) -> (u16, Vec<u8>) {
+        let list: Vec<u8> = names
+            .iter()
+            .flat_map(|(ty, name)| [&[*ty][..], &vec16(name)].concat())
+            .collect();
+        (EXTENSION_SERVER_NAME, vec16(&list))
+    }
+
+    #[test]
+    fn finds_the_server_name() {
+        let bytes = client_hello(Some(&[alpn(&[b"h2"]), sni(&[(0, b"example.com")])]));
+        let hello = ClientHello::parse(&bytes).unwrap();
+        assert_eq!(hello.server_name(), Some("example.com"));
+        assert_eq!(hello.alpn_protocols(), [b"h2"]);
+    }
+
+    #[test]
+    fn skips_names_that_arent_host_names() {
+        let bytes = client_hello(Some(&[sni(&[(7, b"other"), (0, b"example.com")])]));
+        let hello = ClientHello::parse(&bytes).unwrap();
+        assert_eq!(hello.server_name(), Some("example.com"));
+        let bytes = client_hello(Some(&[sni(&[(7, b"other")])]));
+        assert_eq!(ClientHello::parse(&bytes).unwrap().server_name(), None);
+    }
+
+    #[test]
+    fn no_server_name_without_a_valid_extension() {
+        let bytes = client_hello(None);
+        assert_eq!(ClientHello::parse(&bytes).unwrap().server_name(), None);
+        let bytes = client_hello(Some(&[sni(&[(0, b"\xff\xfe")])]));
+        assert_eq!(ClientHello::parse(&bytes).unwrap().server_name(), None);
+        // The name claims to be longer than the extension.
+        let bytes = client_hello(Some(&[(EXTENSION_SERVER_NAME, vec16(&[0, 0, 9, b'a']))]));
+        assert_eq!(ClientHello::parse(&bytes).unwrap().server_name(), None);
+    }
+}
@@ -1,32 +1,294 @@
+use std::ffi::CString;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
 use std::sync::{OnceLock, RwLock};
-use std::time::{Duration, Instant};
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::util;
 
 #[derive(Clone)]
 struct OscillatingToggle {
     enabled: bool,
     updated_at: Instant,
     toggle_window: Duration,
+    /// Fraction at the start of each enabled window during which latency actually applies.
+    duty_cycle: f64,
 }
 
 static TOGGLE_STATE: OnceLock<RwLock<OscillatingToggle>> = OnceLock::new();
 
-pub fn init(toggle_window: Duration) {
+/// Each `interval` contains a single burst of `burst` length starting at a random offset. Latency
+/// is only injected during the burst.
+#[derive(Clone)]
+struct Burst {
+    interval_start: Instant,
+    burst_start: Instant,
+    interval: Duration,
+    burst: Duration,
+}
+
+impl Burst {
+    fn new(interval_start: Instant, interval: Duration, burst: Duration) -> Self {
+        // Pick an offset that keeps the whole burst inside the interval.
+        let slack = interval.saturating_sub(burst).as_millis() as u64;
+        let offset = Duration::from_millis(util::random_u64() % (slack + 1));
+        Self {
+            interval_start,
+            burst_start: interval_start + offset,
+            interval,
+            burst,
+        }
+    }
+}
+
+static BURST_STATE: OnceLock<RwLock<Burst>> = OnceLock::new();
+
+// End of the grace period after startup during which nothing is intercepted.
+static GRACE_PERIOD_END: OnceLock<Instant> = OnceLock::new();
+
+/// A wall-clock window outside of which nothing is intercepted. Once a boundary has been passed
+/// it's remembered, so most checks don't need to look at the clock.
+struct Schedule {
+    start_at: Option<SystemTime>,
+    stop_at: Option<SystemTime>,
+    started: AtomicBool,
+    stopped: AtomicBool,
+}
+
+impl Schedule {
+    fn new(start_at: Option<SystemTime>, stop_at: Option<SystemTime>) -> Self {
+        Self {
+            start_at,
+            stop_at,
+            started: AtomicBool::new(start_at.is_none()),
+            stopped: AtomicBool::new(false),
+        }
+    }
+
+    fn is_active(&self, now: impl FnOnce() -> SystemTime) -> bool {
+        if self.stopped.load(Ordering::Relaxed) {
+            return false;
+        }
+        let started = self.started.load(Ordering::Relaxed);
+        if started && self.stop_at.is_none() {
+            return true;
+        }
+
+        let now = now();
+        if !started {
+            if self.start_at.is_some_and(|start_at| now < start_at) {
+                return false;
+            }
+            tracing::info!("Scheduled start reached, intercepting");
+            self.started.store(true, Ordering::Relaxed);
+        }
+        if self.stop_at.is_some_and(|stop_at| now >= stop_at) {
+            tracing::info!("Scheduled stop reached, no longer intercepting");
+            self.stopped.store(true, Ordering::Relaxed);
+            return false;
+        }
+        true
+    }
+}
+
+static SCHEDULE: OnceLock<Schedule> = OnceLock::new();
+
+// First byte of the shared-memory segment holding the kill switch. Nothing is intercepted while
+// it's non-zero.
+static KILL_SWITCH: OnceLock<&'static AtomicU8> = OnceLock::new();
+
+/// How long whether the flag file exists is remembered, so most checks don't touch the filesystem.
+const FLAG_FILE_CACHE: Duration = Duration::from_millis(100);
+
+/// A file that must exist for anything to be intercepted, with when it was last looked for and
+/// whether it existed then.
+struct FlagFile {
+    path: PathBuf,
+    checked: RwLock<(Instant, bool)>,
+}
+
+impl FlagFile {
+    fn new(path: PathBuf, now: Instant) -> Self {
+        let exists = path.exists();
+        Self {
+            path,
+            checked: RwLock::new((now, exists)),
+        }
+    }
+
+    fn exists(&self, now: Instant) -> bool {
+        let (checked_at, existed) = match self.checked.read() {
+            Ok(checked) => *checked,
+            _ => {
+                tracing::warn!("Failed to access flag file state");
+                return false;
+            }
+        };
+        if now.duration_since(checked_at) < FLAG_FILE_CACHE {
+            return existed;
+        }
+
+        let exists = self.path.exists();
+        if exists != existed {
+            let state = if exists { "appeared" } else { "disappeared" };
+            tracing::info!("Flag file {} {state}", self.path.display());
+        }
+        let Ok(mut checked) = self.checked.write() else {
+            tracing::warn!("Failed to access flag file state");
+            return false;
+        };
+        *checked = (now, exists);
+        exists
+    }
+}
+
+static FLAG_FILE: OnceLock<FlagFile> = OnceLock::new();
+
+/// Number of whole `period`s between `since` and `now`.
+fn periods_elapsed(since: Instant, now: Instant, period: Duration) -> u64 {
+    now.duration_since(since)
+        .as_secs()
+        .checked_div(period.as_secs())
+        .unwrap_or(0)
+}
+
+/// Whether `now` falls in the first `duty_cycle` fraction of the window of length `window`
+/// starting at `window_start`.
+fn in_duty_cycle(window_start: Instant, now: Instant, window: Duration, duty_cycle: f64) -> bool {
+    now.duration_since(window_start) < window.mul_f64(duty_cycle)
+}
+
+pub fn init(toggle_window: Duration, duty_cycle: f64) {
     let enabled = false;
     let updated_at = Instant::now();
     tracing::info!(
         "Initializing oscillating toggle; starts disabled but flips every {} seconds",
         toggle_window.as_secs()
     );
+    if duty_cycle < 1.0 {
+        tracing::info!(
+            "Only intercepting for the first {:.3} seconds of each enabled window",
+            toggle_window.mul_f64(duty_cycle).as_secs_f64()
+        );
+    }
     TOGGLE_STATE.get_or_init(|| {
         RwLock::new(OscillatingToggle {
             enabled,
             updated_at,
             toggle_window,
+            duty_cycle,
         })
     });
 }
 
+pub fn init_burst(burst: Duration, interval: Duration) {
+    tracing::info!(
+        "Initializing bursts; one burst of {} seconds in every {} second interval",
+        burst.as_secs(),
+        interval.as_secs()
+    );
+    BURST_STATE.get_or_init(|| RwLock::new(Burst::new(Instant::now(), interval, burst)));
+}
+
+pub fn init_grace_period(grace_period: Duration) {
+    tracing::info!(
+        "Initializing grace period; nothing is intercepted for the first {} seconds",
+        grace_period.as_secs()
+    );
+    GRACE_PERIOD_END.get_or_init(|| Instant::now() + grace_period);
+}
+
+pub fn init_schedule(start_at: Option<SystemTime>, stop_at: Option<SystemTime>) {
+    let describe = |time: Option<SystemTime>| {
+        time.map(|time| chrono::DateTime::<chrono::Utc>::from(time).to_rfc3339())
+    };
+    tracing::info!(
+        "Initializing schedule; intercepting from {} until {}",
+        describe(start_at).unwrap_or_else(|| "now".to_owned()),
+        describe(stop_at).unwrap_or_else(|| "forever".to_owned())
+    );
+    SCHEDULE.get_or_init(|| Schedule::new(start_at, stop_at));
+}
+
+/// Map the first byte of the POSIX shared-memory segment `name` as a kill switch another process
+/// can set. If the segment doesn't exist yet, there's no kill switch.
+pub fn init_kill_switch(name: &str) {
+    tracing::info!("Initializing kill switch; nothing is intercepted while {name} is set");
+    let Ok(c_name) = CString::new(name) else {
+        tracing::warn!("Invalid shared-memory name {name}");
+        return;
+    };
+    unsafe {
+        let fd = libc::shm_open(c_name.as_ptr(), libc::O_RDONLY, 0);
+        if fd < 0 {
+            let e = std::io::Error::last_os_error();
+            tracing::warn!("Failed to open shared memory {name}, ignoring the kill switch: {e}");
+            return;
+        }
+        let mut stat = std::mem::MaybeUninit::<libc::stat>::zeroed();
+        let mapped = if libc::fstat(fd, stat.as_mut_ptr()) == 0 && stat.assume_init().st_size > 0 {
+            libc::mmap(
+                std::ptr::null_mut(),
+                1,
+                libc::PROT_READ,
+                libc::MAP_SHARED,
+                fd,
+                0,
+            )
+        } else {
+            libc::MAP_FAILED
+        };
+        libc::close(fd);
+        if mapped == libc::MAP_FAILED {
+            tracing::warn!("Failed to map shared memory {name}, ignoring the kill switch");
+            return;
+        }
+        KILL_SWITCH.get_or_init(|| &*mapped.cast::<AtomicU8>());
+    }
+}
+
+pub fn init_flag_file(path: PathBuf) {
+    tracing::info!(
+        "Initializing flag file; nothing is intercepted unless {} exists",
+        path.display()
+    );
+    FLAG_FILE.get_or_init(|| FlagFile::new(path, Instant::now()));
+}
+
 pub fn is_active() -> bool {
+    let now = Instant::now();
+    !killed()
+        && FLAG_FILE
+            .get()
+            .is_none_or(|flag_file| flag_file.exists(now))
+        && GRACE_PERIOD_END
+            .get()
+            .is_none_or(|grace_period_end| grace_period_elapsed(*grace_period_end, now))
+        && schedule_is_active()
+        && toggle_is_active()
+        // Always bursting if no bursts were configured
+        && BURST_STATE
+            .get()
+            .is_none_or(|burst_state_lock| burst_is_active(burst_state_lock, now))
+}
+
+fn killed() -> bool {
+    KILL_SWITCH
+        .get()
+        .is_some_and(|kill_switch| kill_switch.load(Ordering::Relaxed) != 0)
+}
+
+fn schedule_is_active() -> bool {
+    SCHEDULE
+        .get()
+        .is_none_or(|schedule| schedule.is_active(SystemTime::now))
+}
+
+fn grace_period_elapsed(grace_period_end: Instant, now: Instant) -> bool {
+    now >= grace_period_end
+}
+
+fn toggle_is_active() -> bool {
     let Some(toggle_state_lock) = TOGGLE_STATE.get() else {
         // Enabled if no toggle window was configured
         return true;
@@ -38,6 +300,7 @@ pub fn is_active() -> bool {
         mut enabled,
         mut updated_at,
         toggle_window,
+        duty_cycle,
     } = match toggle_state_lock.read() {
         Ok(current_state) => current_state.clone(),
         // Disable if we can't access the toggle state.
@@ -49,7 +312,7 @@ pub fn is_active() -> bool {
 
     // Check how many periods of `toggle_window` seconds have passed since the last update. If >0
     // periods have passed, we must update the toggle state.
-    let periods_elapsed = now.duration_since(updated_at).as_secs() / toggle_window.as_secs();
+    let periods_elapsed = periods_elapsed(updated_at, now, toggle_window);
     if periods_elapsed > 0 {
         tracing::info!("Toggle period elapsed {periods_elapsed} times");
 
@@ -74,5 +337,188 @@ pub fn is_active() -> bool {
         toggle_state.updated_at = updated_at;
     }
 
-    enabled
+    // `updated_at` is the start of the current window.
+    enabled && in_duty_cycle(updated_at, now, toggle_window, duty_cycle)
+}
+
+fn burst_is_active(burst_state_lock: &RwLock<Burst>, now: Instant) -> bool {
+    let mut burst = match burst_state_lock.read() {
+        Ok(current_state) => current_state.clone(),
+        _ => {
+            tracing::warn!("Failed to access burst state");
+            return false;
+        }
+    };
+
+    // Once the current interval is over, schedule the burst for the interval we're now in.
+    let periods_elapsed = periods_elapsed(burst.interval_start, now, burst.interval);
+    if periods_elapsed > 0 {
+        let interval_start =
+            burst.interval_start + burst.interval * periods_elapsed.try_into().unwrap_or(0);
+        burst = Burst::new(interval_start, burst.interval, burst.burst);
+        tracing::debug!(
+            "Next burst starts {} seconds into the interval",
+            (burst.burst_start - interval_start).as_secs()
+        );
+
+        let Ok(mut burst_state) = burst_state_lock.write() else {
+            tracing::warn!("Failed to access burst state");
+            return false;
+        };
+        // Another thread may have already rescheduled this interval.
+        if burst_state.interval_start < interval_start {
+            *burst_state = burst.clone();
+        } else {
+            burst = burst_state.clone();
+        }
+    }
+
+    now >= burst.burst_start && now < burst.burst_start + burst.burst
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bursts_stay_inside_their_interval() {
+        let start = Instant::now();
+        let interval = Duration::from_secs(60);
+        let length = Duration::from_secs(10);
+        for _ in 0..1000 {
+            let burst = Burst::new(start, interval, length);
+            assert!(burst.burst_start >= start);
+            assert!(burst.burst_start + length <= start + interval);
+        }
+    }
+
+    #[test]
+    fn bursts_filling_the_interval_start_with_it() {
+        let start = Instant::now();
+        let interval = Duration::from_secs(10);
+        assert_eq!(Burst::new(start, interval, interval).burst_start, start);
+        let longer = Duration::from_secs(20);
+        assert_eq!(Burst::new(start, interval, longer).burst_start, start);
+    }
+
+    #[test]
+    fn bursts_are_active_once_per_interval() {
+        let start = Instant::now();
+        let interval = Duration::from_secs(10);
+        let length = Duration::from_secs(3);
+        let burst_state = RwLock::new(Burst::new(start, interval, length));
+        for i in 0..5 {
+            let interval_start = start + interval * i;
+            let interval_end = interval_start + interval;
+            // The first check in each interval schedules its burst.
+            let active = burst_is_active(&burst_state, interval_start);
+            let burst_start = burst_state.read().unwrap().burst_start;
+            assert!(burst_start >= interval_start);
+            assert!(burst_start + length <= interval_end);
+            assert_eq!(active, burst_start == interval_start);
+            if burst_start > interval_start {
+                let before = burst_start - Duration::from_millis(1);
+                assert!(!burst_is_active(&burst_state, before));
+            }
+            assert!(burst_is_active(&burst_state, burst_start));
+            let last = burst_start + length - Duration::from_millis(1);
+            assert!(burst_is_active(&burst_state, last));
+            if burst_start + length < interval_end {
+                assert!(!burst_is_active(&burst_state, burst_start + length));
+            }
+        }
+    }
+
+    #[test]
+    fn flag_file_is_only_looked_for_once_the_cache_expires() {
+        let path =
+            std::env::temp_dir().join(format!("preload_latency-{}-flag", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let start = Instant::now();
+        let flag_file = FlagFile::new(path.clone(), start);
+        assert!(!flag_file.exists(start));
+
+        std::fs::write(&path, "").unwrap();
+        let checked_at = start + FLAG_FILE_CACHE - Duration::from_millis(1);
+        assert!(!flag_file.exists(checked_at));
+        let checked_at = start + FLAG_FILE_CACHE;
+        assert!(flag_file.exists(checked_at));
+
+        std::fs::remove_file(&path).unwrap();
+        assert!(flag_file.exists(checked_at + FLAG_FILE_CACHE - Duration::from_millis(1)));
+        assert!(!flag_file.exists(checked_at + FLAG_FILE_CACHE));
+    }
+
+    #[test]
+    fn schedule_intercepts_from_its_start_until_its_stop() {
+        let start_at = SystemTime::now() + Duration::from_secs(60);
+        let stop_at = start_at + Duration::from_secs(60);
+        let schedule = Schedule::new(Some(start_at), Some(stop_at));
+        let at = |time: SystemTime| move || time;
+        assert!(!schedule.is_active(at(start_at - Duration::from_millis(1))));
+        assert!(schedule.is_active(at(start_at)));
+        assert!(schedule.is_active(at(stop_at - Duration::from_millis(1))));
+        assert!(!schedule.is_active(at(stop_at)));
+        // Once stopped, it stays stopped even if the clock goes back.
+        assert!(!schedule.is_active(at(start_at)));
+    }
+
+    #[test]
+    fn schedules_may_leave_out_either_end() {
+        let start_at = SystemTime::now() + Duration::from_secs(60);
+        let schedule = Schedule::new(Some(start_at), None);
+        assert!(!schedule.is_active(|| start_at - Duration::from_millis(1)));
+        assert!(schedule.is_active(|| start_at));
+        assert!(schedule.is_active(|| unreachable!()));
+
+        let schedule = Schedule::new(None, None);
+        assert!(schedule.is_active(|| unreachable!()));
+        let stop_at = SystemTime::now() + Duration::from_secs(60);
+        let schedule = Schedule::new(None, Some(stop_at));
+        assert!(schedule.is_active(|| stop_at - Duration::from_millis(1)));
+        assert!(!schedule.is_active(|| stop_at));
+    }
+
+    #[test]
+    fn grace_period_ends_at_its_end() {
+        let end = Instant::now() + Duration::from_secs(5);
+        assert!(!grace_period_elapsed(end, end - Duration::from_secs(5)));
+        assert!(!grace_period_elapsed(end, end - Duration::from_millis(1)));
+        assert!(grace_period_elapsed(end, end));
+        assert!(grace_period_elapsed(end, end + Duration::from_secs(5)));
+    }
+
+    #[test]
+    fn duty_cycle_limits_the_start_of_each_window() {
+        let start = Instant::now();
+        let window = Duration::from_secs(10);
+        let at = |millis| start + Duration::from_millis(millis);
+        for millis in [0, 4_999, 5_000, 9_999] {
+            assert!(!in_duty_cycle(start, at(millis), window, 0.0));
+            assert!(in_duty_cycle(start, at(millis), window, 1.0));
+        }
+        assert!(in_duty_cycle(start, at(0), window, 0.5));
+        assert!(in_duty_cycle(start, at(4_999), window, 0.5));
+        assert!(!in_duty_cycle(start, at(5_000), window, 0.5));
+        assert!(!in_duty_cycle(start, at(9_999), window, 0.5));
+    }
+
+    #[test]
+    fn periods_elapsed_counts_whole_periods() {
+        let start = Instant::now();
+        let period = Duration::from_secs(10);
+        assert_eq!(periods_elapsed(start, start, period), 0);
+        assert_eq!(
+            periods_elapsed(start, start + Duration::from_secs(9), period),
+            0
+        );
+        assert_eq!(
+            periods_elapsed(start, start + Duration::from_secs(10), period),
+            1
+        );
+        assert_eq!(
+            periods_elapsed(start, start + Duration::from_secs(35), period),
+            3
+        );
+    }
 }
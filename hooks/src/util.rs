@@ -1,36 +1,507 @@
+use std::mem::MaybeUninit;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::atomic::{AtomicU8, AtomicU64, Ordering};
+
 use libc::{c_char, c_int, c_void, sockaddr, socklen_t};
 
-unsafe extern "C" {
-    /// The `libc` crate doesn't export this but we can extern it ourselves.
-    fn inet_ntop(af: c_int, src: *const c_void, dst: *mut c_void, size: socklen_t)
-    -> *const c_char;
-}
+/// `SOCK_NONBLOCK` flag for the `type` of `socket`/`socketpair`. macOS doesn't have it.
+#[cfg(target_os = "linux")]
+pub const SOCK_NONBLOCK: c_int = libc::SOCK_NONBLOCK;
+#[cfg(not(target_os = "linux"))]
+pub const SOCK_NONBLOCK: c_int = 0;
 
 /// Create a UTF8 Rust `&str` from a `*const c_char` (`libc` C string).
 pub unsafe fn utf8_from_ptr<'a>(ptr: *const c_char) -> Result<&'a str, std::str::Utf8Error> {
     unsafe { std::str::from_utf8(std::ffi::CStr::from_ptr(ptr).to_bytes()) }
 }
 
-/// Create a human-readable IP address `String` from a `*const sockaddr`. Returns
-/// an empty string if the `sockaddr` is not IPv4 or IPv6.
-pub unsafe fn get_in_addr(addr: *const sockaddr) -> String {
+/// Whether there's a definition of `symbol` after this library's, which `real!` would call. If
+/// there isn't, e.g. in a static binary, `real!` would panic. The answer is cached in `state`,
+/// which starts out as 0. Logging that it's missing may call the same hook again, so nothing is
+/// locked.
+pub fn resolves_next(symbol: &std::ffi::CStr, state: &AtomicU8) -> bool {
+    const RESOLVED: u8 = 1;
+    const MISSING: u8 = 2;
+    match state.load(Ordering::Relaxed) {
+        RESOLVED => return true,
+        MISSING => return false,
+        _ => {}
+    }
+    let resolved = !unsafe { libc::dlsym(libc::RTLD_NEXT, symbol.as_ptr()) }.is_null();
+    let new_state = if resolved { RESOLVED } else { MISSING };
+    if state.swap(new_state, Ordering::Relaxed) != new_state && !resolved {
+        tracing::error!(
+            "Failed to find the real {}, failing calls to it with ENOSYS",
+            symbol.to_string_lossy()
+        );
+    }
+    resolved
+}
+
+/// Set the calling thread's `errno`.
+pub fn set_errno(errno: c_int) {
+    #[cfg(target_os = "linux")]
+    unsafe {
+        *libc::__errno_location() = errno;
+    }
+    #[cfg(target_os = "macos")]
     unsafe {
-        let mut buf = [0; 45];
-        let family = (*addr).sa_family.into();
-        let addr_ptr: *const c_void = match family {
+        *libc::__error() = errno;
+    }
+}
+
+/// Create a Rust `SocketAddr` from a `*const sockaddr`. Returns `None` if the `sockaddr` is null
+/// or not IPv4 or IPv6.
+pub unsafe fn get_socket_addr(addr: *const sockaddr) -> Option<SocketAddr> {
+    if addr.is_null() {
+        return None;
+    }
+    unsafe {
+        match (*addr).sa_family.into() {
             libc::AF_INET => {
                 let sa_in = addr.cast::<libc::sockaddr_in>();
-                std::ptr::addr_of!((*sa_in).sin_addr).cast()
+                let octets = (*sa_in).sin_addr.s_addr.to_ne_bytes();
+                let port = u16::from_be((*sa_in).sin_port);
+                Some(SocketAddr::from((Ipv4Addr::from(octets), port)))
             }
             libc::AF_INET6 => {
                 let sa_in6 = addr.cast::<libc::sockaddr_in6>();
-                std::ptr::addr_of!((*sa_in6).sin6_addr).cast()
+                let ip = Ipv6Addr::from((*sa_in6).sin6_addr.s6_addr);
+                let port = u16::from_be((*sa_in6).sin6_port);
+                Some(SocketAddr::from((ip, port)))
             }
-            _ => std::ptr::null(),
-        };
-        if !addr_ptr.is_null() {
-            inet_ntop(family, addr_ptr, buf.as_mut_ptr().cast(), 45);
+            family => {
+                log_unsupported_family(family);
+                None
+            }
+        }
+    }
+}
+
+// Address families below 64 that `get_socket_addr` already logged as unsupported, one bit each.
+static UNSUPPORTED_FAMILIES: AtomicU64 = AtomicU64::new(0);
+
+/// Log that a `sockaddr` of `family` can't be matched, once per family so it doesn't flood the
+/// logs.
+fn log_unsupported_family(family: c_int) {
+    let bit = u32::try_from(family)
+        .ok()
+        .and_then(|family| 1u64.checked_shl(family));
+    if bit.is_some_and(|bit| UNSUPPORTED_FAMILIES.fetch_or(bit, Ordering::Relaxed) & bit != 0) {
+        return;
+    }
+    tracing::debug!(
+        "Not matching {} address by IP, since it isn't IPv4 or IPv6",
+        family_name(family)
+    );
+}
+
+/// The address of a vsock socket, used for communication between VMs and their host.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct VsockAddr {
+    pub cid: u32,
+    pub port: u32,
+}
+
+impl std::fmt::Display for VsockAddr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "vsock:{}:{}", self.cid, self.port)
+    }
+}
+
+/// Create a [`VsockAddr`] from a `*const sockaddr`. Returns `None` if the `sockaddr` is null or
+/// not `AF_VSOCK`.
+#[cfg(target_os = "linux")]
+pub unsafe fn get_vsock_addr(addr: *const sockaddr) -> Option<VsockAddr> {
+    if addr.is_null() {
+        return None;
+    }
+    unsafe {
+        if c_int::from((*addr).sa_family) != libc::AF_VSOCK {
+            return None;
+        }
+        let sa_vm = addr.cast::<libc::sockaddr_vm>();
+        Some(VsockAddr {
+            cid: (*sa_vm).svm_cid,
+            port: (*sa_vm).svm_port,
+        })
+    }
+}
+
+/// The path of an `AF_UNIX` `*const sockaddr` of `len` bytes. Abstract names are prefixed with `@`
+/// like `ss` prints them. Returns `None` if the `sockaddr` is null, not `AF_UNIX` or unnamed.
+pub unsafe fn get_unix_path(addr: *const sockaddr, len: socklen_t) -> Option<String> {
+    if addr.is_null() {
+        return None;
+    }
+    let offset = std::mem::offset_of!(libc::sockaddr_un, sun_path);
+    let len = (len as usize)
+        .checked_sub(offset)?
+        .min(std::mem::size_of::<libc::sockaddr_un>() - offset);
+    unsafe {
+        if c_int::from((*addr).sa_family) != libc::AF_UNIX || len == 0 {
+            return None;
+        }
+        let path = addr.cast::<u8>().add(offset);
+        let path = std::slice::from_raw_parts(path, len);
+        match path.split_first() {
+            Some((0, name)) => Some(format!("@{}", String::from_utf8_lossy(name))),
+            _ => {
+                let end = path.iter().position(|byte| *byte == 0).unwrap_or(len);
+                Some(String::from_utf8_lossy(&path[..end]).into_owned())
+            }
+        }
+    }
+}
+
+/// Create a Rust `IpAddr` from a `*const sockaddr`. Returns `None` if the `sockaddr` is null or
+/// not IPv4 or IPv6.
+pub unsafe fn get_ip_addr(addr: *const sockaddr) -> Option<IpAddr> {
+    unsafe { get_socket_addr(addr) }.map(|addr| addr.ip())
+}
+
+/// The IP address `socket` is connected to, if it's an IPv4 or IPv6 socket.
+pub fn get_peer_ip(socket: c_int) -> Option<IpAddr> {
+    get_ip_with(socket, libc::getpeername)
+}
+
+/// The local IP address `socket` is bound to, if it's an IPv4 or IPv6 socket.
+pub fn get_local_ip(socket: c_int) -> Option<IpAddr> {
+    get_ip_with(socket, libc::getsockname)
+}
+
+fn get_ip_with(
+    socket: c_int,
+    get_name: unsafe extern "C" fn(c_int, *mut sockaddr, *mut libc::socklen_t) -> c_int,
+) -> Option<IpAddr> {
+    let mut storage = MaybeUninit::<libc::sockaddr_storage>::zeroed();
+    let mut len = std::mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+    let addr = storage.as_mut_ptr().cast::<sockaddr>();
+    unsafe {
+        if get_name(socket, addr, &mut len) != 0 {
+            return None;
+        }
+        get_ip_addr(addr)
+    }
+}
+
+/// Classes an IP address can belong to, from [`classify_ip`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct IpClass {
+    /// `127.0.0.0/8` or `::1`.
+    pub loopback: bool,
+    /// `169.254.0.0/16` or `fe80::/10`.
+    pub link_local: bool,
+    /// RFC 1918 (`10.0.0.0/8`, `172.16.0.0/12`, `192.168.0.0/16`) or ULA (`fc00::/7`).
+    pub private: bool,
+}
+
+impl IpClass {
+    /// Whether the address is on the public internet rather than the host or a local network.
+    pub fn public(&self) -> bool {
+        !self.loopback && !self.link_local && !self.private
+    }
+}
+
+/// Classify `ip`. IPv4-mapped IPv6 addresses are classified as IPv4.
+pub fn classify_ip(ip: IpAddr) -> IpClass {
+    match ip.to_canonical() {
+        IpAddr::V4(ip) => IpClass {
+            loopback: ip.is_loopback(),
+            link_local: ip.is_link_local(),
+            private: ip.is_private(),
+        },
+        IpAddr::V6(ip) => IpClass {
+            loopback: ip.is_loopback(),
+            link_local: ip.is_unicast_link_local(),
+            private: ip.is_unique_local(),
+        },
+    }
+}
+
+/// Human-readable name of an address family, e.g. `AF_INET`.
+pub fn family_name(family: c_int) -> String {
+    match family {
+        libc::AF_UNSPEC => "AF_UNSPEC".to_owned(),
+        libc::AF_INET => "AF_INET".to_owned(),
+        libc::AF_INET6 => "AF_INET6".to_owned(),
+        libc::AF_UNIX => "AF_UNIX".to_owned(),
+        #[cfg(target_os = "linux")]
+        libc::AF_VSOCK => "AF_VSOCK".to_owned(),
+        other => format!("family {other}"),
+    }
+}
+
+// State for the splitmix64 generator behind [`random_u64`].
+static RNG_STATE: AtomicU64 = AtomicU64::new(0);
+
+/// Seed the generator behind [`random_u64`].
+pub fn seed_rng(seed: u64) {
+    RNG_STATE.store(seed, Ordering::Relaxed);
+}
+
+/// Return a pseudo-random `u64`. Not suitable for anything security-related, but cheap and
+/// lock-free which is what we need in the hooks.
+pub fn random_u64() -> u64 {
+    mix(RNG_STATE
+        .fetch_add(0x9E3779B97F4A7C15, Ordering::Relaxed)
+        .wrapping_add(0x9E3779B97F4A7C15))
+}
+
+/// The ID of the calling thread.
+#[cfg(target_os = "linux")]
+pub fn current_tid() -> u64 {
+    unsafe { libc::gettid() as u64 }
+}
+
+/// The ID of the calling thread.
+#[cfg(not(target_os = "linux"))]
+pub fn current_tid() -> u64 {
+    let mut tid = 0;
+    unsafe { libc::pthread_threadid_np(0, &mut tid) };
+    tid
+}
+
+/// The current `CLOCK_MONOTONIC` time in nanoseconds, as used by e.g. `perf` and `bpftrace`.
+pub fn monotonic_nanos() -> u64 {
+    let mut now = MaybeUninit::<libc::timespec>::uninit();
+    unsafe {
+        libc::clock_gettime(libc::CLOCK_MONOTONIC, now.as_mut_ptr());
+        let now = now.assume_init();
+        now.tv_sec as u64 * 1_000_000_000 + now.tv_nsec as u64
+    }
+}
+
+/// The current hour of the local day and the number of seconds until the next one starts, or
+/// `None` if the local time can't be determined.
+pub fn local_hour() -> Option<(usize, u64)> {
+    let mut local = MaybeUninit::<libc::tm>::uninit();
+    unsafe {
+        let now = libc::time(std::ptr::null_mut());
+        if libc::localtime_r(&now, local.as_mut_ptr()).is_null() {
+            return None;
+        }
+        let local = local.assume_init();
+        let into_hour = local.tm_min * 60 + local.tm_sec;
+        Some((local.tm_hour as usize, (3600 - into_hour).max(1) as u64))
+    }
+}
+
+/// Return a pseudo-random number between 0 (inclusive) and 1 (exclusive).
+pub fn random_f64() -> f64 {
+    (random_u64() >> 11) as f64 / (1u64 << 53) as f64
+}
+
+/// Return `true` with probability `p`.
+pub fn random_chance(p: f64) -> bool {
+    random_f64() < p
+}
+
+/// A hash of `bytes` that's the same across runs and platforms for the same `seed`, unlike
+/// `std`'s randomly keyed hashers.
+pub fn stable_hash(bytes: &[u8], seed: u64) -> u64 {
+    // FNV-1a, finished with the splitmix64 mixer so similar inputs end up far apart.
+    let hash = bytes.iter().fold(0xCBF29CE484222325 ^ seed, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x100000001B3)
+    });
+    mix(hash)
+}
+
+/// The splitmix64 output function.
+fn mix(mut z: u64) -> u64 {
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+    z ^ (z >> 31)
+}
+
+/// Whether `fd` is an IPv4 or IPv6 socket rather than e.g. a unix or netlink socket, a regular
+/// file or a pipe.
+pub fn is_ip_socket(fd: c_int) -> bool {
+    matches!(socket_family(fd), Some(libc::AF_INET | libc::AF_INET6))
+}
+
+/// Whether `fd` is a unix socket, which can pass fds.
+pub fn is_unix_socket(fd: c_int) -> bool {
+    socket_family(fd) == Some(libc::AF_UNIX)
+}
+
+/// The address family `fd` is bound in, if it's a socket.
+fn socket_family(fd: c_int) -> Option<c_int> {
+    let mut storage = MaybeUninit::<libc::sockaddr_storage>::zeroed();
+    let mut len = std::mem::size_of::<libc::sockaddr_storage>() as socklen_t;
+    unsafe {
+        (libc::getsockname(fd, storage.as_mut_ptr().cast(), &mut len) == 0)
+            .then(|| c_int::from(storage.assume_init().ss_family))
+    }
+}
+
+/// Whether the ancillary data of `msg` passes fds with `SCM_RIGHTS`.
+pub unsafe fn passes_fds(msg: *const libc::msghdr) -> bool {
+    if msg.is_null() {
+        return false;
+    }
+    unsafe {
+        let mut cmsg = libc::CMSG_FIRSTHDR(msg);
+        while !cmsg.is_null() {
+            if (*cmsg).cmsg_level == libc::SOL_SOCKET && (*cmsg).cmsg_type == libc::SCM_RIGHTS {
+                return true;
+            }
+            cmsg = libc::CMSG_NXTHDR(msg, cmsg);
         }
-        utf8_from_ptr(buf.as_ptr()).unwrap_or("").to_owned()
+    }
+    false
+}
+
+/// Whether `fd` is a `SOCK_STREAM` socket, whose data may be split across calls unlike datagrams.
+pub fn is_stream_socket(fd: c_int) -> bool {
+    let mut sock_type: c_int = 0;
+    let mut len = std::mem::size_of::<c_int>() as socklen_t;
+    let result = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_TYPE,
+            &mut sock_type as *mut c_int as *mut c_void,
+            &mut len,
+        )
+    };
+    result == 0 && sock_type == libc::SOCK_STREAM
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn class(ip: &str) -> IpClass {
+        classify_ip(ip.parse().unwrap())
+    }
+
+    #[test]
+    fn loopback_addresses() {
+        for ip in ["127.0.0.1", "127.255.0.1", "::1", "::ffff:127.0.0.1"] {
+            let class = class(ip);
+            assert!(class.loopback, "{ip}");
+            assert!(
+                !class.link_local && !class.private && !class.public(),
+                "{ip}"
+            );
+        }
+    }
+
+    #[test]
+    fn link_local_addresses() {
+        for ip in ["169.254.1.1", "fe80::1", "febf::1"] {
+            let class = class(ip);
+            assert!(class.link_local, "{ip}");
+            assert!(!class.loopback && !class.private && !class.public(), "{ip}");
+        }
+    }
+
+    #[test]
+    fn private_addresses() {
+        for ip in [
+            "10.0.0.1",
+            "172.16.0.1",
+            "172.31.255.255",
+            "192.168.1.1",
+            "fd00::1",
+            "fc00::1",
+            "::ffff:10.0.0.1",
+        ] {
+            let class = class(ip);
+            assert!(class.private, "{ip}");
+            assert!(
+                !class.loopback && !class.link_local && !class.public(),
+                "{ip}"
+            );
+        }
+    }
+
+    #[test]
+    fn public_addresses() {
+        for ip in [
+            "8.8.8.8",
+            "172.32.0.1",
+            "192.169.0.1",
+            "2001:4860:4860::8888",
+            "::ffff:8.8.8.8",
+        ] {
+            assert_eq!(class(ip), IpClass::default(), "{ip}");
+            assert!(class(ip).public(), "{ip}");
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn vsock_addresses_are_extracted() {
+        let mut addr: libc::sockaddr_vm = unsafe { std::mem::zeroed() };
+        addr.svm_family = libc::AF_VSOCK as libc::sa_family_t;
+        addr.svm_cid = 3;
+        addr.svm_port = 5000;
+        let vsock = unsafe { get_vsock_addr(&addr as *const _ as *const sockaddr) }.unwrap();
+        assert_eq!(vsock, VsockAddr { cid: 3, port: 5000 });
+        assert_eq!(vsock.to_string(), "vsock:3:5000");
+        let rule = crate::host_rule::HostRule::parse("vsock:3:5000");
+        assert!(rule.matches(crate::host_rule::Destination::Vsock(vsock)));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn only_vsock_addresses_are_extracted() {
+        let mut addr: libc::sockaddr_in = unsafe { std::mem::zeroed() };
+        addr.sin_family = libc::AF_INET as libc::sa_family_t;
+        assert_eq!(
+            unsafe { get_vsock_addr(&addr as *const _ as *const sockaddr) },
+            None
+        );
+        assert_eq!(unsafe { get_vsock_addr(std::ptr::null()) }, None);
+    }
+
+    #[test]
+    fn stable_hash_is_the_same_across_runs() {
+        // Per-host offsets depend on these, so changing them moves every host's latency.
+        assert_eq!(stable_hash(b"", 0), 17665956581633026203);
+        assert_eq!(stable_hash(b"example.com", 0), 5550653309705616055);
+        assert_eq!(stable_hash(b"example.com", 1), 12296690280150614600);
+    }
+
+    /// A `msghdr` with one control message of `level` and `ty` holding an fd, in `control`.
+    fn msg_with_cmsg(control: &mut [u64; 8], level: c_int, ty: c_int) -> libc::msghdr {
+        let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+        msg.msg_control = control.as_mut_ptr().cast();
+        msg.msg_controllen = unsafe { libc::CMSG_SPACE(std::mem::size_of::<c_int>() as u32) } as _;
+        unsafe {
+            let cmsg = libc::CMSG_FIRSTHDR(&msg);
+            (*cmsg).cmsg_level = level;
+            (*cmsg).cmsg_type = ty;
+            (*cmsg).cmsg_len = libc::CMSG_LEN(std::mem::size_of::<c_int>() as u32) as _;
+            libc::CMSG_DATA(cmsg).cast::<c_int>().write_unaligned(0);
+        }
+        msg
+    }
+
+    #[test]
+    fn scm_rights_passes_fds() {
+        let mut control = [0; 8];
+        let msg = msg_with_cmsg(&mut control, libc::SOL_SOCKET, libc::SCM_RIGHTS);
+        assert!(unsafe { passes_fds(&msg) });
+    }
+
+    #[test]
+    fn plain_data_and_other_control_messages_dont_pass_fds() {
+        let mut data = *b"hello";
+        let mut iov = libc::iovec {
+            iov_base: data.as_mut_ptr().cast(),
+            iov_len: data.len(),
+        };
+        let mut plain: libc::msghdr = unsafe { std::mem::zeroed() };
+        plain.msg_iov = &mut iov;
+        plain.msg_iovlen = 1;
+        assert!(!unsafe { passes_fds(&plain) });
+        assert!(!unsafe { passes_fds(std::ptr::null()) });
+
+        let mut control = [0; 8];
+        let timestamps = msg_with_cmsg(&mut control, libc::SOL_SOCKET, libc::SCM_TIMESTAMP);
+        assert!(!unsafe { passes_fds(&timestamps) });
     }
 }
@@ -0,0 +1,136 @@
+use std::cell::RefCell;
+use std::collections::BTreeSet;
+
+use crate::config::ConfigError;
+
+/// Environment variable holding a JSON object with settings to use for variables that aren't set.
+const JSON_VAR: &str = "PRELOAD_LATENCY_JSON";
+
+/// Where configuration values come from: the environment, falling back to the settings in
+/// `PRELOAD_LATENCY_JSON`.
+///
+/// Each key in the JSON object is the name of a variable without the `PRELOAD_LATENCY_` prefix, in
+/// lowercase. Strings and numbers are used as if they were the variable's value, `true` sets a
+/// flag and `false` or `null` leave it unset. Arrays are joined with the variable's list separator
+/// and objects become a list of `key=value` entries, e.g. `{"10.0.0.0/8": 100}` for `cidrs`.
+pub(crate) struct Vars {
+    #[cfg(feature = "json")]
+    json: serde_json::Map<String, serde_json::Value>,
+    /// Names of the variables that were looked up, to find unknown keys in the JSON.
+    looked_up: RefCell<BTreeSet<&'static str>>,
+}
+
+impl Vars {
+    pub(crate) fn load(errors: &mut Vec<ConfigError>) -> Self {
+        let json = std::env::var(JSON_VAR).ok().filter(|json| !json.is_empty());
+        #[cfg(feature = "json")]
+        let json = match json.as_deref().map(serde_json::from_str) {
+            Some(Ok(serde_json::Value::Object(json))) => json,
+            Some(Ok(_)) => {
+                errors.push(ConfigError::Unparseable {
+                    var: JSON_VAR,
+                    reason: "expected an object".to_owned(),
+                });
+                Default::default()
+            }
+            Some(Err(e)) => {
+                errors.push(ConfigError::Unparseable {
+                    var: JSON_VAR,
+                    reason: e.to_string(),
+                });
+                Default::default()
+            }
+            None => Default::default(),
+        };
+        #[cfg(not(feature = "json"))]
+        if json.is_some() {
+            errors.push(ConfigError::Unparseable {
+                var: JSON_VAR,
+                reason: "the hooks were built without the `json` feature".to_owned(),
+            });
+        }
+        Self {
+            #[cfg(feature = "json")]
+            json,
+            looked_up: RefCell::new(BTreeSet::new()),
+        }
+    }
+
+    /// The value of the variable `name`, or the matching JSON setting if it isn't set.
+    pub(crate) fn get(&self, name: &'static str) -> Option<String> {
+        self.looked_up.borrow_mut().insert(name);
+        std::env::var(name).ok().or_else(|| self.get_json(name))
+    }
+
+    #[cfg(feature = "json")]
+    fn get_json(&self, name: &'static str) -> Option<String> {
+        use serde_json::Value;
+
+        let separator = if name == "PRELOAD_LATENCY_HOSTS" {
+            ":"
+        } else {
+            ","
+        };
+        let scalar = |value: &Value| match value {
+            Value::String(value) => Some(value.clone()),
+            Value::Number(value) => Some(value.to_string()),
+            Value::Bool(true) => Some("1".to_owned()),
+            _ => None,
+        };
+        match self.json.get(&json_key(name))? {
+            Value::Array(values) => Some(
+                values
+                    .iter()
+                    .filter_map(scalar)
+                    .collect::<Vec<_>>()
+                    .join(separator),
+            ),
+            Value::Object(entries) => Some(
+                entries
+                    .iter()
+                    .map(|(key, value)| match scalar(value) {
+                        Some(value) => format!("{key}={value}"),
+                        None => key.clone(),
+                    })
+                    .collect::<Vec<_>>()
+                    .join(separator),
+            ),
+            value => scalar(value),
+        }
+    }
+
+    #[cfg(not(feature = "json"))]
+    fn get_json(&self, _name: &'static str) -> Option<String> {
+        None
+    }
+
+    /// Report keys in the JSON that don't correspond to any variable that was looked up.
+    pub(crate) fn check_unknown(&self, errors: &mut Vec<ConfigError>) {
+        #[cfg(feature = "json")]
+        {
+            let known: BTreeSet<String> = self
+                .looked_up
+                .borrow()
+                .iter()
+                .map(|name| json_key(name))
+                .collect();
+            for key in self.json.keys().filter(|key| !known.contains(*key)) {
+                errors.push(ConfigError::InvalidEntry {
+                    var: JSON_VAR,
+                    entry: key.clone(),
+                    reason: "unknown setting".to_owned(),
+                });
+            }
+        }
+        #[cfg(not(feature = "json"))]
+        let _ = errors;
+    }
+}
+
+/// The key for the variable `name` in `PRELOAD_LATENCY_JSON`.
+#[cfg(feature = "json")]
+fn json_key(name: &str) -> String {
+    name.strip_prefix("PRELOAD_LATENCY_")
+        .unwrap_or(name)
+        .to_lowercase()
+}
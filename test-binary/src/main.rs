@@ -3,10 +3,16 @@ use bigtable_rs::google::bigtable::v2::row_range::{EndKey, StartKey};
 use bigtable_rs::google::bigtable::v2::{ReadRowsRequest, RowRange, RowSet};
 use std::time::Duration;
 
+mod self_test;
+
 #[tokio::main]
 pub async fn main() -> Result<(), Box<dyn std::error::Error>> {
     tracing_subscriber::fmt::init();
 
+    if std::env::args().any(|arg| arg == "--self-test") {
+        return self_test::run();
+    }
+
     // Uncomment to test toggle window
     tokio::time::sleep(Duration::from_secs(3)).await;
 
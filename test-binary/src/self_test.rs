@@ -0,0 +1,143 @@
+//! `--self-test`: call each kind of hooked function against local echo servers and check that
+//! each call took about as long as the hooks should have made it take. Expects the hooks to be
+//! loaded with `PRELOAD_LATENCY_HOSTS=localhost` and optionally `PRELOAD_LATENCY_MILLIS`, but
+//! nothing else that changes sleep durations.
+
+use std::io::{IoSlice, IoSliceMut, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs, UdpSocket};
+use std::time::{Duration, Instant};
+
+/// How much longer than expected a call may take, for scheduling noise.
+const SLACK: Duration = Duration::from_millis(100);
+
+/// How long a call is expected to take.
+#[derive(Clone, Copy)]
+enum Expect {
+    /// About as long as `PRELOAD_LATENCY_MILLIS`.
+    Delayed,
+    /// Not delayed at all.
+    Fast,
+}
+
+struct SelfTest {
+    millis: Duration,
+    failures: usize,
+}
+
+impl SelfTest {
+    /// Time `call`, print how long it took and record whether that was within bounds.
+    fn check<T>(&mut self, name: &str, expect: Expect, call: impl FnOnce() -> T) -> T {
+        let started = Instant::now();
+        let result = call();
+        let elapsed = started.elapsed();
+        let (min, max) = match expect {
+            Expect::Delayed => (self.millis.mul_f64(0.9), self.millis + SLACK),
+            Expect::Fast => (Duration::ZERO, SLACK),
+        };
+        let ok = elapsed >= min && elapsed <= max;
+        if !ok {
+            self.failures += 1;
+        }
+        println!(
+            "{} {name:<24} {:>8.1} ms (expected {:.0}-{:.0} ms)",
+            if ok { "ok  " } else { "FAIL" },
+            elapsed.as_secs_f64() * 1000.0,
+            min.as_secs_f64() * 1000.0,
+            max.as_secs_f64() * 1000.0,
+        );
+        result
+    }
+
+    /// Wait until a reply must have arrived, so timing a receive only measures the hooks' sleep.
+    fn await_reply(&self) {
+        std::thread::sleep(self.millis * 2 + SLACK);
+    }
+}
+
+pub fn run() -> Result<(), Box<dyn std::error::Error>> {
+    let millis = match std::env::var("PRELOAD_LATENCY_MILLIS") {
+        Ok(millis) => millis.parse()?,
+        Err(_) => 200,
+    };
+    let mut test = SelfTest {
+        millis: Duration::from_millis(millis),
+        failures: 0,
+    };
+    // Bound by address, so resolving `localhost` below is the only lookup.
+    let tcp_addr = spawn_tcp_echo()?;
+    let udp_addr = spawn_udp_echo()?;
+
+    let resolved = test.check("getaddrinfo", Expect::Fast, || {
+        ("localhost", tcp_addr.port()).to_socket_addrs()
+    })?;
+    if !resolved.into_iter().any(|addr| addr == tcp_addr) {
+        return Err("localhost doesn't resolve to 127.0.0.1".into());
+    }
+
+    let mut stream = test.check("connect", Expect::Fast, || TcpStream::connect(tcp_addr))?;
+    let mut buf = [0; 64];
+    test.check("write", Expect::Delayed, || stream.write_all(b"ping"))?;
+    test.await_reply();
+    test.check("recv (peek)", Expect::Delayed, || stream.peek(&mut buf))?;
+    test.check("read", Expect::Delayed, || stream.read(&mut buf))?;
+    test.check("writev", Expect::Delayed, || {
+        stream.write_vectored(&[IoSlice::new(b"pi"), IoSlice::new(b"ng")])
+    })?;
+    test.await_reply();
+    test.check("readv", Expect::Delayed, || {
+        let (head, tail) = buf.split_at_mut(2);
+        stream.read_vectored(&mut [IoSliceMut::new(head), IoSliceMut::new(tail)])
+    })?;
+
+    let socket = UdpSocket::bind("127.0.0.1:0")?;
+    test.check("sendto", Expect::Delayed, || {
+        socket.send_to(b"ping", udp_addr)
+    })?;
+    test.await_reply();
+    test.check("recvfrom", Expect::Delayed, || socket.recv_from(&mut buf))?;
+    test.check("connect (udp)", Expect::Fast, || socket.connect(udp_addr))?;
+    test.check("send (udp)", Expect::Delayed, || socket.send(b"ping"))?;
+    test.await_reply();
+    test.check("recv (udp)", Expect::Delayed, || socket.recv(&mut buf))?;
+
+    if test.failures > 0 {
+        return Err(format!("{} calls took an unexpected amount of time", test.failures).into());
+    }
+    println!("All calls took as long as expected");
+    Ok(())
+}
+
+/// Echo everything sent to the returned address over TCP on a background thread.
+fn spawn_tcp_echo() -> std::io::Result<SocketAddr> {
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let Ok(mut stream) = stream else {
+                continue;
+            };
+            std::thread::spawn(move || {
+                let mut buf = [0; 64];
+                while let Ok(n @ 1..) = stream.read(&mut buf) {
+                    if stream.write_all(&buf[..n]).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+    });
+    Ok(addr)
+}
+
+/// Echo every datagram sent to the returned address back to its sender on a background thread.
+fn spawn_udp_echo() -> std::io::Result<SocketAddr> {
+    let socket = UdpSocket::bind("127.0.0.1:0")?;
+    let addr = socket.local_addr()?;
+    std::thread::spawn(move || {
+        let mut buf = [0; 64];
+        while let Ok((n, peer)) = socket.recv_from(&mut buf) {
+            let _ = socket.send_to(&buf[..n], peer);
+        }
+    });
+    Ok(addr)
+}